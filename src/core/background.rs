@@ -0,0 +1,312 @@
+use super::defs::{Real, PI};
+use super::distribution::Distribution2D;
+use super::primitives::*;
+use rand::{Rng, RngCore};
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum DesBackground {
+    Color {
+        color: Vec3R,
+    },
+    Gradient {
+        bottom: Vec3R,
+        top: Vec3R,
+        #[serde(default = "DesBackground::default_up")]
+        up: Vec3R,
+    },
+    /// equirectangular Radiance HDR image, `path` is relative to the scene file
+    EnvironmentMap {
+        path: PathBuf,
+        /// degrees around the y axis
+        #[serde(default)]
+        rotation: Real,
+        #[serde(default = "DesBackground::default_intensity")]
+        intensity: Real,
+    },
+}
+
+impl DesBackground {
+    fn default_up() -> Vec3R {
+        *Unit3R::UP.vec()
+    }
+
+    fn default_intensity() -> Real {
+        1.0
+    }
+
+    pub fn load(self, base_dir: &Path) -> Result<Background, String> {
+        Ok(match self {
+            DesBackground::Color { color } => Background::Color(color),
+            DesBackground::Gradient { bottom, top, up } => {
+                if up.length_squared() == 0.0 {
+                    return Err("gradient up axis can't be zero".to_string());
+                }
+                Background::Gradient {
+                    bottom,
+                    top,
+                    up: up.unit(),
+                }
+            }
+            DesBackground::EnvironmentMap {
+                path,
+                rotation,
+                intensity,
+            } => Background::EnvironmentMap(EnvironmentMap::from_hdr(
+                &base_dir.join(path),
+                rotation.to_radians(),
+                intensity,
+            )?),
+        })
+    }
+}
+
+/// Light coming from infinitely far away, seen by the rays that escape the scene.
+pub enum Background {
+    Color(Vec3R),
+    Gradient {
+        bottom: Vec3R,
+        top: Vec3R,
+        up: Unit3R,
+    },
+    EnvironmentMap(EnvironmentMap),
+}
+
+impl Default for Background {
+    fn default() -> Self {
+        Background::Gradient {
+            bottom: Vec3R::new(1.0, 1.0, 1.0),
+            top: Vec3R::new(0.3, 0.5, 1.0),
+            up: Unit3R::UP,
+        }
+    }
+}
+
+impl Background {
+    /// Radiance coming from `direction`.
+    pub fn color(&self, direction: &Unit3R) -> Vec3R {
+        match self {
+            Background::Color(color) => *color,
+            Background::Gradient { bottom, top, up } => {
+                let k = (direction.vec().dot(up.vec()) + 1.0) * 0.5;
+                bottom.lerp(top, k)
+            }
+            Background::EnvironmentMap(map) => map.color(direction),
+        }
+    }
+
+    /// Whether the background is sampled as a light, only environment maps are.
+    pub fn is_light(&self) -> bool {
+        matches!(self, Background::EnvironmentMap(_))
+    }
+
+    /// Random direction, more likely where the background is brighter, and its pdf.
+    pub fn sample(&self, rng: &mut dyn RngCore) -> Option<(Unit3R, Real)> {
+        match self {
+            Background::EnvironmentMap(map) => map.sample(rng),
+            _ => None,
+        }
+    }
+
+    /// Probability density (solid angle) of `sample` returning `direction`.
+    pub fn pdf(&self, direction: &Unit3R) -> Real {
+        match self {
+            Background::EnvironmentMap(map) => map.pdf(direction),
+            _ => 0.0,
+        }
+    }
+}
+
+/// Equirectangular image, the center of the image is seen looking towards -z
+/// and its top row is straight up (+y).
+pub struct EnvironmentMap {
+    pixels: Vec<Vec3R>,
+    width: usize,
+    height: usize,
+    rotation: Real,
+    intensity: Real,
+    /// distribution of the pixels luminance, over the image coordinates
+    distribution: Distribution2D,
+}
+
+impl EnvironmentMap {
+    pub fn new(
+        pixels: Vec<Vec3R>,
+        width: usize,
+        height: usize,
+        rotation: Real,
+        intensity: Real,
+    ) -> EnvironmentMap {
+        debug_assert!(pixels.len() == width * height);
+        // rows near the poles cover a smaller solid angle, so they're less likely
+        let luminance: Vec<Real> = pixels
+            .iter()
+            .enumerate()
+            .map(|(index, color)| {
+                let theta = PI * ((index / width) as Real + 0.5) / height as Real;
+                color.luminance().max(0.0) * theta.sin()
+            })
+            .collect();
+        EnvironmentMap {
+            distribution: Distribution2D::new(&luminance, width, height),
+            pixels,
+            width,
+            height,
+            rotation,
+            intensity,
+        }
+    }
+
+    pub fn from_hdr(
+        path: &Path,
+        rotation: Real,
+        intensity: Real,
+    ) -> Result<EnvironmentMap, String> {
+        let error =
+            |err: &dyn std::fmt::Display| format!("cannot read '{}': {}", path.display(), err);
+        let file = std::fs::File::open(path).map_err(|err| error(&err))?;
+        let decoder = image::codecs::hdr::HdrDecoder::new(std::io::BufReader::new(file))
+            .map_err(|err| error(&err))?;
+        let metadata = decoder.metadata();
+        let pixels = decoder
+            .read_image_hdr()
+            .map_err(|err| error(&err))?
+            .iter()
+            .map(|rgb| Vec3R::new(rgb[0] as Real, rgb[1] as Real, rgb[2] as Real))
+            .collect();
+        Ok(EnvironmentMap::new(
+            pixels,
+            metadata.width as usize,
+            metadata.height as usize,
+            rotation,
+            intensity,
+        ))
+    }
+
+    /// Image coordinates in [0, 1) of `direction`.
+    fn uv(&self, direction: &Unit3R) -> Vec2R {
+        let d = direction.vec();
+        let phi = d.x.atan2(-d.z) - self.rotation;
+        let theta = d.y.clamp(-1.0, 1.0).acos();
+        let u = 0.5 + phi / (2.0 * PI);
+        Vec2R::new(u - u.floor(), theta / PI)
+    }
+
+    fn direction(&self, uv: &Vec2R) -> Unit3R {
+        let phi = (uv.x - 0.5) * 2.0 * PI + self.rotation;
+        let theta = uv.y * PI;
+        let sin_theta = theta.sin();
+        Unit3R::normalized(Vec3R::new(
+            sin_theta * phi.sin(),
+            theta.cos(),
+            -sin_theta * phi.cos(),
+        ))
+    }
+
+    /*
+        the image coordinates map to the sphere with
+        dA = 2PI du * PI dv * sin(theta)
+        so the pdf over solid angle is pdf(u, v) / (2 PI^2 sin(theta))
+    */
+    pub fn sample(&self, rng: &mut dyn RngCore) -> Option<(Unit3R, Real)> {
+        let (uv, pdf) = self
+            .distribution
+            .sample(Vec2R::new(rng.gen::<Real>(), rng.gen::<Real>()));
+        let sin_theta = (uv.y * PI).sin();
+        if pdf > 0.0 && sin_theta > 0.0 {
+            Some((self.direction(&uv), pdf / (2.0 * PI * PI * sin_theta)))
+        } else {
+            None
+        }
+    }
+
+    pub fn pdf(&self, direction: &Unit3R) -> Real {
+        let uv = self.uv(direction);
+        let sin_theta = (uv.y * PI).sin();
+        if sin_theta > 0.0 {
+            self.distribution.pdf(uv) / (2.0 * PI * PI * sin_theta)
+        } else {
+            0.0
+        }
+    }
+
+    pub fn color(&self, direction: &Unit3R) -> Vec3R {
+        let uv = self.uv(direction);
+        let x = ((uv.x * self.width as Real) as usize).min(self.width - 1);
+        let y = ((uv.y * self.height as Real) as usize).min(self.height - 1);
+        self.pixels[y * self.width + x] * self.intensity
+    }
+}
+
+#[test]
+fn test_background() {
+    let gradient = Background::Gradient {
+        bottom: Vec3R::new(1.0, 1.0, 1.0),
+        top: Vec3R::new(0.0, 0.0, 0.0),
+        up: Vec3R::new(0.0, 0.0, 1.0).unit(),
+    };
+    assert_eq!(
+        gradient.color(&Vec3R::new(0.0, 0.0, 1.0).unit()),
+        Vec3R::new(0.0, 0.0, 0.0)
+    );
+    assert_eq!(
+        gradient.color(&Vec3R::new(0.0, 1.0, 0.0).unit()),
+        Vec3R::new(0.5, 0.5, 0.5)
+    );
+
+    // 4x2 map, each pixel has its own color
+    let pixels = (0..8).map(|i| Vec3R::new(i as Real, 0.0, 0.0)).collect();
+    let map = EnvironmentMap::new(pixels, 4, 2, 0.0, 2.0);
+    let color_at = |x: Real, y: Real, z: Real| map.color(&Vec3R::new(x, y, z).unit()).x;
+    // looking forward, slightly up, is at the center of the top row
+    assert_eq!(color_at(0.0, 0.1, -1.0), 2.0 * 2.0);
+    // right, slightly down, is at 3/4 of the bottom row
+    assert_eq!(color_at(1.0, -0.1, 0.0), 2.0 * 7.0);
+    // backward is at the seam
+    assert_eq!(color_at(-0.01, 0.1, 1.0), 2.0 * 0.0);
+    assert_eq!(color_at(0.01, 0.1, 1.0), 2.0 * 3.0);
+
+    // rotating the map by 90 degrees brings its left side in front
+    let pixels = (0..8).map(|i| Vec3R::new(i as Real, 0.0, 0.0)).collect();
+    let map = EnvironmentMap::new(pixels, 4, 2, (90.0 as Real).to_radians(), 1.0);
+    assert_eq!(map.color(&Vec3R::new(0.0, -0.1, -1.0).unit()).x, 5.0);
+}
+
+#[test]
+fn test_environment_map_sampling() {
+    // dark map with a bright spot
+    let mut pixels = vec![Vec3R::new(0.1, 0.1, 0.1); 16 * 8];
+    pixels[3 * 16 + 5] = Vec3R::new(100.0, 100.0, 100.0);
+    let map = EnvironmentMap::new(pixels, 16, 8, 0.3, 1.0);
+    let mut bright = 0;
+    let mut rng = super::random::SampleRng::new(0, 0, 0, 0);
+    for _ in 0..1000 {
+        let (direction, pdf) = map.sample(&mut rng).unwrap();
+        assert!((direction.vec().length() - 1.0).abs() < 1e-9);
+        assert!((map.pdf(&direction) - pdf).abs() < 1e-6 * pdf);
+        if map.color(&direction).x > 1.0 {
+            bright += 1;
+        }
+    }
+    assert!(bright > 900);
+
+    // the pdf integrates to 1 over the sphere
+    let n = 200;
+    let mut integral = 0.0;
+    for i in 0..n {
+        for j in 0..2 * n {
+            let theta = PI * (i as Real + 0.5) / n as Real;
+            let phi = PI * (j as Real + 0.5) / n as Real;
+            let direction = Vec3R::new(
+                theta.sin() * phi.cos(),
+                theta.cos(),
+                theta.sin() * phi.sin(),
+            );
+            let d_omega = theta.sin() * (PI / n as Real) * (PI / n as Real);
+            integral += map.pdf(&direction.unit()) * d_omega;
+        }
+    }
+    assert!((integral - 1.0).abs() < 0.02);
+}
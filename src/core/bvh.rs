@@ -0,0 +1,333 @@
+use super::defs::Real;
+use super::primitives::*;
+
+/// Bounding volume hierarchy over a list of primitives, identified by their index.
+/// The tree is stored depth first: the first child of an interior node is the next
+/// node, the second one is at `offset`.
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    indices: Vec<usize>,
+}
+
+struct BvhNode {
+    bounds: Aabb,
+    /// leaf: first primitive in `indices`, interior: index of the second child
+    offset: usize,
+    /// number of primitives, 0 for interior nodes
+    count: usize,
+}
+
+struct BuildPrimitive {
+    index: usize,
+    bounds: Aabb,
+    centroid: Point3R,
+}
+
+#[derive(Copy, Clone)]
+struct Bin {
+    bounds: Aabb,
+    count: usize,
+}
+
+impl Bvh {
+    const BINS: usize = 12;
+    const MAX_LEAF_SIZE: usize = 8;
+    /// cost of visiting a node, two box tests, relative to the cost of intersecting a primitive
+    const TRAVERSAL_COST: Real = 2.0;
+    /// deeper nodes are always leaves, so that the traversal stack has a fixed size
+    const MAX_DEPTH: usize = 63;
+
+    /// Builds the tree using the surface area heuristic evaluated on a fixed number of bins.
+    pub fn new(bounding_boxes: &[Aabb]) -> Bvh {
+        let mut primitives: Vec<BuildPrimitive> = bounding_boxes
+            .iter()
+            .enumerate()
+            .map(|(index, bounds)| BuildPrimitive {
+                index,
+                bounds: *bounds,
+                centroid: bounds.centroid(),
+            })
+            .collect();
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(2 * primitives.len()),
+            indices: Vec::with_capacity(primitives.len()),
+        };
+        if !primitives.is_empty() {
+            bvh.build(&mut primitives, 0);
+        }
+        bvh
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes
+            .first()
+            .map(|node| node.bounds)
+            .unwrap_or_else(Aabb::empty)
+    }
+
+    fn build(&mut self, primitives: &mut [BuildPrimitive], depth: usize) {
+        let bounds = primitives
+            .iter()
+            .fold(Aabb::empty(), |acc, p| acc.union(&p.bounds));
+        let node_index = self.nodes.len();
+        self.nodes.push(BvhNode {
+            bounds,
+            offset: 0,
+            count: 0,
+        });
+
+        let split = if depth < Bvh::MAX_DEPTH {
+            Bvh::split(primitives, &bounds)
+        } else {
+            None
+        };
+        match split {
+            Some(mid) => {
+                let (left, right) = primitives.split_at_mut(mid);
+                self.build(left, depth + 1);
+                self.nodes[node_index].offset = self.nodes.len();
+                self.build(right, depth + 1);
+            }
+            None => {
+                let node = &mut self.nodes[node_index];
+                node.offset = self.indices.len();
+                node.count = primitives.len();
+                self.indices.extend(primitives.iter().map(|p| p.index));
+            }
+        }
+    }
+
+    /// Partitions the primitives and returns where the second half starts,
+    /// None when it's cheaper to make a leaf.
+    fn split(primitives: &mut [BuildPrimitive], bounds: &Aabb) -> Option<usize> {
+        if primitives.len() <= 1 {
+            return None;
+        }
+        let centroid_bounds = primitives
+            .iter()
+            .fold(Aabb::empty(), |acc, p| acc.grow(&p.centroid));
+        let axis = centroid_bounds.largest_axis();
+        let axis_min = centroid_bounds.min[axis];
+        let axis_extent = centroid_bounds.max[axis] - axis_min;
+        if axis_extent.is_nan() || axis_extent <= 0.0 {
+            // all the centroids are in the same place, nothing to split
+            return None;
+        }
+
+        let bin_of = |p: &BuildPrimitive| {
+            let k = (p.centroid[axis] - axis_min) / axis_extent;
+            ((k * Bvh::BINS as Real) as usize).min(Bvh::BINS - 1)
+        };
+        let mut bins = [Bin {
+            bounds: Aabb::empty(),
+            count: 0,
+        }; Bvh::BINS];
+        for p in primitives.iter() {
+            let bin = &mut bins[bin_of(p)];
+            bin.bounds = bin.bounds.union(&p.bounds);
+            bin.count += 1;
+        }
+
+        // sweep from the right to know the cost of every right side, then from the left
+        let mut right_areas = [0.0; Bvh::BINS];
+        let mut right_counts = [0usize; Bvh::BINS];
+        let mut right = Bin {
+            bounds: Aabb::empty(),
+            count: 0,
+        };
+        for i in (1..Bvh::BINS).rev() {
+            right.bounds = right.bounds.union(&bins[i].bounds);
+            right.count += bins[i].count;
+            right_areas[i] = right.bounds.surface_area();
+            right_counts[i] = right.count;
+        }
+        let mut best_cost = Real::INFINITY;
+        let mut best_split = 0;
+        let mut left = Bin {
+            bounds: Aabb::empty(),
+            count: 0,
+        };
+        for i in 1..Bvh::BINS {
+            left.bounds = left.bounds.union(&bins[i - 1].bounds);
+            left.count += bins[i - 1].count;
+            if left.count == 0 || right_counts[i] == 0 {
+                continue;
+            }
+            let cost = left.count as Real * left.bounds.surface_area()
+                + right_counts[i] as Real * right_areas[i];
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = i;
+            }
+        }
+
+        let area = bounds.surface_area();
+        let leaf_cost = primitives.len() as Real;
+        let split_cost = Bvh::TRAVERSAL_COST + best_cost / area;
+        if best_split == 0
+            || (primitives.len() <= Bvh::MAX_LEAF_SIZE
+                && (split_cost.is_nan() || split_cost >= leaf_cost))
+        {
+            return None;
+        }
+
+        // in place partition
+        let mut mid = 0;
+        for i in 0..primitives.len() {
+            if bin_of(&primitives[i]) < best_split {
+                primitives.swap(i, mid);
+                mid += 1;
+            }
+        }
+        Some(mid)
+    }
+
+    /// Finds the closest primitive hit by the ray in (t_min, t_max).
+    /// `intersect` is called with the primitive index and the current maximum time
+    /// and returns the time of the hit, or infinity.
+    pub fn closest_hit<F: FnMut(usize, Real) -> Real>(
+        &self,
+        ray: &Ray,
+        t_min: Real,
+        t_max: Real,
+        mut intersect: F,
+    ) -> Option<(usize, Real)> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let direction = ray.direction.vec();
+        let inv_direction = Vec3R {
+            x: 1.0 / direction.x,
+            y: 1.0 / direction.y,
+            z: 1.0 / direction.z,
+        };
+        let mut max_time = t_max;
+        let mut closest = None;
+        // every level pushes at most one node more than it pops
+        let mut stack = [0usize; Bvh::MAX_DEPTH + 2];
+        let mut stack_size = 0;
+        if self.nodes[0]
+            .bounds
+            .intersect(&ray.origin, &inv_direction, t_min, max_time)
+            < Real::INFINITY
+        {
+            stack[0] = 0;
+            stack_size = 1;
+        }
+
+        while stack_size > 0 {
+            stack_size -= 1;
+            let node_index = stack[stack_size];
+            let node = &self.nodes[node_index];
+            if node.count > 0 {
+                for &index in &self.indices[node.offset..node.offset + node.count] {
+                    let time = intersect(index, max_time);
+                    if time < max_time {
+                        max_time = time;
+                        closest = Some((index, time));
+                    }
+                }
+            } else {
+                let first = node_index + 1;
+                let second = node.offset;
+                let t_first = self.nodes[first].bounds.intersect(
+                    &ray.origin,
+                    &inv_direction,
+                    t_min,
+                    max_time,
+                );
+                let t_second = self.nodes[second].bounds.intersect(
+                    &ray.origin,
+                    &inv_direction,
+                    t_min,
+                    max_time,
+                );
+                // the nearest child is pushed last so that it's visited first
+                let (near, t_near, far, t_far) = if t_first <= t_second {
+                    (first, t_first, second, t_second)
+                } else {
+                    (second, t_second, first, t_first)
+                };
+                if t_far < max_time {
+                    stack[stack_size] = far;
+                    stack_size += 1;
+                }
+                if t_near < max_time {
+                    stack[stack_size] = near;
+                    stack_size += 1;
+                }
+            }
+        }
+        closest
+    }
+}
+
+#[test]
+fn test_bvh_closest_hit() {
+    use super::geometry::{Geometry, Sphere};
+
+    // a grid of small spheres along x, y and z
+    let mut spheres = Vec::new();
+    for x in 0..8 {
+        for y in 0..8 {
+            for z in 0..8 {
+                let center = Point3R::new(x as Real, y as Real, z as Real) * 2.0;
+                spheres.push(Sphere::new(center, 0.5));
+            }
+        }
+    }
+    let boxes: Vec<Aabb> = spheres.iter().map(|s| s.bounding_box()).collect();
+    let bvh = Bvh::new(&boxes);
+    assert_eq!(
+        bvh.bounds(),
+        Aabb::new(
+            Point3R::new(-0.5, -0.5, -0.5),
+            Point3R::new(14.5, 14.5, 14.5)
+        )
+    );
+
+    let rays = [
+        Ray::new(
+            Point3R::new(-3.0, 4.0, 6.0),
+            Vec3R::new(1.0, 0.0, 0.0).unit(),
+        ),
+        Ray::new(
+            Point3R::new(-3.0, -3.0, -3.0),
+            Vec3R::new(1.0, 1.0, 1.0).unit(),
+        ),
+        Ray::new(
+            Point3R::new(7.0, 20.0, 7.0),
+            Vec3R::new(0.1, -1.0, 0.05).unit(),
+        ),
+        Ray::new(
+            Point3R::new(7.0, 7.0, 7.0),
+            Vec3R::new(-0.3, 0.2, 1.0).unit(),
+        ),
+        Ray::new(
+            Point3R::new(1.0, 1.0, -3.0),
+            Vec3R::new(0.0, 0.0, 1.0).unit(),
+        ),
+    ];
+    for ray in rays.iter() {
+        let mut expected = None;
+        let mut max_time = Real::INFINITY;
+        for (index, sphere) in spheres.iter().enumerate() {
+            let time = sphere.intersect(ray, 0.0, max_time);
+            if time < max_time {
+                max_time = time;
+                expected = Some((index, time));
+            }
+        }
+        let found = bvh.closest_hit(ray, 0.0, Real::INFINITY, |index, max_time| {
+            spheres[index].intersect(ray, 0.0, max_time)
+        });
+        assert_eq!(found, expected);
+    }
+
+    let empty = Bvh::new(&[]);
+    assert!(empty.bounds().is_empty());
+    assert_eq!(
+        empty.closest_hit(&rays[0], 0.0, Real::INFINITY, |_, _| 0.0),
+        None
+    );
+}
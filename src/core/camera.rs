@@ -1,60 +1,137 @@
 use super::defs::*;
 use super::primitives::*;
-//use rand::Rng;
+use rand::{Rng, RngCore};
+use serde::Deserialize;
+use std::convert::TryFrom;
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum DesProjection {
+    /// uses the camera fov
+    #[default]
+    Perspective,
+    Orthographic {
+        /// height of the view in world units
+        height: Real,
+    },
+    /// equidistant fisheye, `fov` in degrees up to 360
+    Fisheye { fov: Real },
+    /// 360 degrees panorama
+    Equirectangular,
+}
+
+impl TryFrom<DesProjection> for Projection {
+    type Error = String;
+
+    fn try_from(des_projection: DesProjection) -> Result<Self, Self::Error> {
+        Ok(match des_projection {
+            DesProjection::Perspective => Projection::Perspective,
+            DesProjection::Orthographic { height } => {
+                if height.is_nan() || height <= 0.0 {
+                    return Err("orthographic view height must be positive".to_string());
+                }
+                Projection::Orthographic { height }
+            }
+            DesProjection::Fisheye { fov } => {
+                if !(fov > 0.0 && fov <= 360.0) {
+                    return Err("fisheye fov must be in (0, 360] degrees".to_string());
+                }
+                Projection::Fisheye {
+                    fov_radians: fov.to_radians(),
+                }
+            }
+            DesProjection::Equirectangular => Projection::Equirectangular,
+        })
+    }
+}
+
+/// How the points of the image are mapped to rays.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Projection {
+    /// pinhole or thin lens camera
+    Perspective,
+    /// parallel rays from a view `height` tall
+    Orthographic { height: Real },
+    /// the angle from the view direction is proportional to the distance
+    /// from the center of the image, the image circle touches its shorter side
+    Fisheye { fov_radians: Real },
+    /// longitude along x and latitude along y, the center of the image is straight ahead
+    Equirectangular,
+}
 
 #[derive(Clone)]
 pub struct Camera {
     pub origin: Point3R,
-    lower_left_corner: Point3R,
+    /// relative to the origin, on the focus plane
+    lower_left_corner: Vec3R,
     horizontal: Vec3R,
     vertical: Vec3R,
     direction: Unit3R,
     aspect_ratio: Real,
     pub fov_radians: Real,
     rotation: Vec2R,
-    //pub lens_radius: Real,
+    /// radius of the thin lens, 0 for a pinhole camera
+    pub lens_radius: Real,
+    /// distance of the plane in focus
+    focus_distance: Real,
+    /// unit vectors of the view, right and up
+    right: Vec3R,
+    up: Vec3R,
+    pub projection: Projection,
 }
 
 impl Camera {
     pub fn new(aspect_ratio: Real, fov_radians: Real) -> Camera {
-        let focal_length = 1.0;
-
-        let viewport_height = 2.0;
-        let viewport_width = aspect_ratio * viewport_height;
-        // camera
-
-        let origin = Point3R::default(); // 0,0,0
-        let horizontal = Point3R::new(viewport_width, 0.0, 0.0);
-        let vertical = Point3R::new(0.0, viewport_height, 0.0);
-        let lower_left_corner =
-            origin - 0.5 * horizontal - 0.5 * vertical - Vec3R::new(0.0, 0.0, focal_length);
-        Camera {
-            origin,
-            lower_left_corner,
-            horizontal,
-            vertical,
-            direction: Unit3R::UP,
+        let mut camera = Camera {
+            origin: Point3R::default(), // 0,0,0
+            lower_left_corner: Vec3R::default(),
+            horizontal: Vec3R::default(),
+            vertical: Vec3R::default(),
+            direction: Unit3R::normalized(Vec3R::new(0.0, 0.0, -1.0)),
             fov_radians,
             rotation: Vec2R::default(),
             aspect_ratio,
-            //lens_radius: 0.0,
-        }
+            lens_radius: 0.0,
+            focus_distance: 1.0,
+            right: Vec3R::default(),
+            up: Vec3R::default(),
+            projection: Projection::Perspective,
+        };
+        camera.update_viewport();
+        camera
     }
 
     pub fn set_aspect_ratio(&mut self, width: usize, height: usize) {
         self.aspect_ratio = width as Real / height as Real;
+        self.update_viewport();
+    }
+
+    pub fn direction(&self) -> &Unit3R {
+        &self.direction
+    }
+
+    #[cfg(test)]
+    pub fn focus_distance(&self) -> Real {
+        self.focus_distance
+    }
+
+    pub fn set_focus_distance(&mut self, focus_distance: Real) {
+        debug_assert!(focus_distance > 0.0);
+        self.focus_distance = focus_distance;
+        self.update_viewport();
     }
 
     pub fn look_at(&mut self, target: Vec3R) {
         self.direction = target.unit();
-        self.rotation.x = self.direction.vec().y.asin();
-        self.rotation.y = (self.direction.vec().x / self.rotation.x.cos()).asin();
+        let d = self.direction.vec();
+        self.rotation.x = d.y.asin();
+        self.rotation.y = Camera::clamp_angle(d.x.atan2(-d.z));
         self.update_viewport();
     }
 
     fn clamp_angle(angle: Real) -> Real {
         if angle < 0.0 {
-            2.0 * PI - angle
+            2.0 * PI + angle
         } else if angle >= 2.0 * PI {
             angle - 2.0 * PI
         } else {
@@ -63,7 +140,9 @@ impl Camera {
     }
 
     pub fn rotate(&mut self, rotation_rads: Vec2R) {
-        self.rotation.x = Camera::clamp_angle(self.rotation.x + rotation_rads.x);
+        // the pitch stops short of straight up or down, past them the view would flip
+        let max_pitch = 0.5 * PI - 1e-6;
+        self.rotation.x = (self.rotation.x + rotation_rads.x).clamp(-max_pitch, max_pitch);
         self.rotation.y = Camera::clamp_angle(self.rotation.y + rotation_rads.y);
         let b = self.rotation.x.cos();
         self.direction = Unit3R::normalized(Vec3R::new(
@@ -71,31 +150,33 @@ impl Camera {
             self.rotation.x.sin(),
             -self.rotation.y.cos() * b,
         ));
+        self.update_viewport();
     }
 
     pub fn update_viewport(&mut self) {
         let h = (self.fov_radians * 0.5).tan();
-        let viewport_height = 2.0 * h;
+        let viewport_height = 2.0 * h * self.focus_distance;
         let viewport_width = self.aspect_ratio * viewport_height;
 
-        let vup = Unit3R::UP;
-        let u = vup.vec().cross(self.direction.vec()).unit();
-        let v = self.direction.vec().cross(u.vec());
+        // the right axis only depends on the yaw, so it's defined even looking straight up
+        let u = Vec3R::new(self.rotation.y.cos(), 0.0, self.rotation.y.sin());
+        let v = u.cross(self.direction.vec());
 
-        self.horizontal = viewport_width * u.vec();
+        self.horizontal = viewport_width * u;
         self.vertical = viewport_height * v;
-        self.lower_left_corner =
-            self.origin - self.horizontal * 0.5 - self.vertical * 0.5 - self.direction.vec();
+        self.right = u;
+        self.up = v;
+        self.lower_left_corner = self.direction.vec() * self.focus_distance
+            - self.horizontal * 0.5
+            - self.vertical * 0.5;
     }
 
     pub fn move_forward(&mut self, distance: Real) {
-        self.move_backward(-distance);
+        self.origin += self.direction.vec() * distance;
     }
 
     pub fn move_right(&mut self, distance: Real) {
-        let movement = self.horizontal.normalize() * distance;
-        self.origin += movement;
-        self.lower_left_corner += movement;
+        self.origin += self.right * distance;
     }
 
     pub fn move_left(&mut self, distance: Real) {
@@ -103,9 +184,7 @@ impl Camera {
     }
 
     pub fn move_backward(&mut self, distance: Real) {
-        let movement = self.direction.vec() * distance;
-        self.origin += movement;
-        self.lower_left_corner += movement;
+        self.move_forward(-distance);
     }
 
     pub fn move_down(&mut self, distance: Real) {
@@ -114,34 +193,226 @@ impl Camera {
 
     pub fn move_up(&mut self, distance: Real) {
         let vup = Unit3R::UP;
-        let movement = vup.vec() * distance;
-        self.origin += movement;
-        self.lower_left_corner += movement;
+        self.origin += vup.vec() * distance;
     }
 
-    /*fn random_in_unit_disk() -> Vec3R {
+    fn random_in_unit_disk(rng: &mut dyn RngCore) -> Vec2R {
         loop {
-            let mut rng = rand::thread_rng();
-            let p = Vec3R::new(rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0), 0.0);
+            let p = Vec2R::new(rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0));
             if p.length_squared() < 1.0 {
                 break p;
             }
         }
-    }*/
+    }
 
-    pub fn ray_at(&self, x: Real, y: Real) -> Ray {
+    /// Ray through the point (x, y) of the image, (0, 0) is the lower left corner.
+    /// None when the point isn't covered by the projection, like the corners of a fisheye.
+    /// `rng` picks the point of the lens.
+    pub fn ray_at(&self, x: Real, y: Real, rng: &mut dyn RngCore) -> Option<Ray> {
         debug_assert!(x >= 0.0 && x <= 1.0);
         debug_assert!(y >= 0.0 && y <= 1.0);
-        /*let mut h = self.horizontal;
-        let mut v = self.vertical;
-        if self.lens_radius != 0.0 {
-            let distorsion = self.lens_radius * Camera::random_in_unit_disk();
-            h *= distorsion;
-            v *= distorsion;
-        }*/
-        let h = self.horizontal;
-        let v = self.vertical;
-        let ray_origin = self.lower_left_corner + h * x + v * y;
-        Ray::new(self.origin, (ray_origin - self.origin).unit())
+        let forward = *self.direction.vec();
+        match self.projection {
+            Projection::Perspective => Some(self.perspective_ray(x, y, rng)),
+            Projection::Orthographic { height } => {
+                let width = height * self.aspect_ratio;
+                let origin =
+                    self.origin + self.right * ((x - 0.5) * width) + self.up * ((y - 0.5) * height);
+                Some(Ray::new(origin, self.direction))
+            }
+            Projection::Fisheye { fov_radians } => {
+                // coordinates in the image circle
+                let px = (x - 0.5) * 2.0 * self.aspect_ratio.max(1.0);
+                let py = (y - 0.5) * 2.0 * (1.0 / self.aspect_ratio).max(1.0);
+                let r = (px * px + py * py).sqrt();
+                if r > 1.0 {
+                    return None;
+                }
+                let theta = r * fov_radians * 0.5;
+                let (cos_phi, sin_phi) = if r > 0.0 {
+                    (px / r, py / r)
+                } else {
+                    (1.0, 0.0)
+                };
+                let side = self.right * cos_phi + self.up * sin_phi;
+                Some(Ray::new(
+                    self.origin,
+                    (forward * theta.cos() + side * theta.sin()).unit(),
+                ))
+            }
+            Projection::Equirectangular => {
+                let phi = (x - 0.5) * 2.0 * PI;
+                let theta = (y - 0.5) * PI;
+                let horizontal = forward * phi.cos() + self.right * phi.sin();
+                Some(Ray::new(
+                    self.origin,
+                    (horizontal * theta.cos() + self.up * theta.sin()).unit(),
+                ))
+            }
+        }
+    }
+
+    /// With a lens the ray starts from a random point of the lens and passes through
+    /// the point in focus.
+    fn perspective_ray(&self, x: Real, y: Real, rng: &mut dyn RngCore) -> Ray {
+        let target = self.lower_left_corner + self.horizontal * x + self.vertical * y;
+        if self.lens_radius > 0.0 {
+            let disk = Camera::random_in_unit_disk(rng) * self.lens_radius;
+            let offset = self.right * disk.x + self.up * disk.y;
+            Ray::new(self.origin + offset, (target - offset).unit())
+        } else {
+            Ray::new(self.origin, target.unit())
+        }
+    }
+}
+
+#[test]
+fn test_camera_focus() {
+    let mut rng = rand::thread_rng();
+    let mut camera = Camera::new(2.0, (90.0 as Real).to_radians());
+    camera.origin = Point3R::new(1.0, 2.0, 3.0);
+    camera.rotate(Vec2R::new(0.0, PI * 0.5));
+    assert!(
+        camera
+            .direction()
+            .vec()
+            .distance(&Vec3R::new(1.0, 0.0, 0.0))
+            < 1e-9
+    );
+
+    // without a lens the center ray goes straight ahead and the corners are at the fov
+    let center = camera.ray_at(0.5, 0.5, &mut rng).unwrap();
+    assert_eq!(center.origin, camera.origin);
+    assert!(center.direction.vec().distance(camera.direction().vec()) < 1e-9);
+    let corner = camera.ray_at(0.0, 1.0, &mut rng).unwrap().direction;
+    assert!(
+        corner
+            .vec()
+            .distance(&Vec3R::new(1.0, 1.0, -2.0).normalize())
+            < 1e-9
+    );
+
+    // with a lens the rays of a pixel start from different points but meet on the focus plane
+    camera.lens_radius = 0.2;
+    camera.set_focus_distance(5.0);
+    let pinhole = {
+        let mut pinhole = camera.clone();
+        pinhole.lens_radius = 0.0;
+        pinhole.ray_at(0.25, 0.75, &mut rng).unwrap()
+    };
+    let expected = pinhole.origin + pinhole.direction.vec() * (5.0 / pinhole.direction.vec().x);
+    for _ in 0..10 {
+        let ray = camera.ray_at(0.25, 0.75, &mut rng).unwrap();
+        assert!(ray.origin.distance(&camera.origin) <= 0.2 + 1e-9);
+        assert!((ray.origin.x - camera.origin.x).abs() < 1e-9);
+        let t = (expected.x - ray.origin.x) / ray.direction.vec().x;
+        let point = ray.origin + ray.direction.vec() * t;
+        assert!(point.distance(&expected) < 1e-9);
     }
 }
+
+#[test]
+fn test_camera_pitch() {
+    let mut camera = Camera::new(1.0, (90.0 as Real).to_radians());
+    camera.rotate(Vec2R::new(-0.3, 0.0));
+    assert!(camera.direction().vec().y < 0.0);
+
+    // looking up past the vertical stops there, right stays right
+    camera.rotate(Vec2R::new(3.0, 0.0));
+    assert!(camera.direction().vec().y > 0.999);
+    assert!(camera.direction().vec().z < 0.0);
+    let right = camera
+        .ray_at(1.0, 0.5, &mut rand::thread_rng())
+        .unwrap()
+        .direction;
+    assert!(right.vec().x > 0.0);
+    camera.rotate(Vec2R::new(-0.5 * PI, 0.0));
+    assert!(
+        camera
+            .direction()
+            .vec()
+            .distance(&Vec3R::new(0.0, 0.0, -1.0))
+            < 1e-5
+    );
+}
+
+#[test]
+fn test_camera_movement() {
+    let mut camera = Camera::new(1.0, (90.0 as Real).to_radians());
+    camera.rotate(Vec2R::new(0.0, 0.5 * PI));
+    camera.move_forward(2.0);
+    camera.move_right(1.0);
+    camera.move_up(0.5);
+    assert!(camera.origin.distance(&Vec3R::new(2.0, 0.5, 1.0)) < 1e-9);
+    camera.move_backward(2.0);
+    camera.move_left(1.0);
+    camera.move_down(0.5);
+    assert!(camera.origin.length() < 1e-9);
+}
+
+#[test]
+fn test_camera_projections() {
+    let mut rng = rand::thread_rng();
+    // 4x2 image looking towards -z from (0, 1, 0)
+    let mut camera = Camera::new(2.0, (90.0 as Real).to_radians());
+    camera.origin = Point3R::new(0.0, 1.0, 0.0);
+    camera.update_viewport();
+    // centers of the lower left and upper right pixels
+    let lower_left = (0.125, 0.25);
+    let upper_right = (0.875, 0.75);
+    let assert_direction = |ray: Ray, expected: Vec3R| {
+        assert!(ray.direction.vec().distance(&expected.normalize()) < 1e-9);
+    };
+
+    let ray = camera.ray_at(lower_left.0, lower_left.1, &mut rng).unwrap();
+    assert_eq!(ray.origin, camera.origin);
+    assert_direction(ray, Vec3R::new(-1.5, -0.5, -1.0));
+
+    camera.projection = Projection::Orthographic { height: 4.0 };
+    let ray = camera
+        .ray_at(upper_right.0, upper_right.1, &mut rng)
+        .unwrap();
+    assert!(ray.origin.distance(&Point3R::new(3.0, 2.0, 0.0)) < 1e-9);
+    assert_direction(ray, Vec3R::new(0.0, 0.0, -1.0));
+
+    // the corners are outside the image circle, the sides are at half the fov
+    camera.projection = Projection::Fisheye {
+        fov_radians: (180.0 as Real).to_radians(),
+    };
+    assert!(camera
+        .ray_at(lower_left.0, lower_left.1, &mut rng)
+        .is_none());
+    assert!(camera
+        .ray_at(upper_right.0, upper_right.1, &mut rng)
+        .is_none());
+    assert_direction(
+        camera.ray_at(0.5, 1.0, &mut rng).unwrap(),
+        Vec3R::new(0.0, 1.0, 0.0),
+    );
+    assert_direction(
+        camera.ray_at(0.375, 0.5, &mut rng).unwrap(),
+        Vec3R::new(-1.0, 0.0, -1.0),
+    );
+    // with a 360 degrees fov the edge of the circle looks backward
+    camera.projection = Projection::Fisheye {
+        fov_radians: (360.0 as Real).to_radians(),
+    };
+    assert_direction(
+        camera.ray_at(0.5, 0.0, &mut rng).unwrap(),
+        Vec3R::new(0.0, 0.0, 1.0),
+    );
+
+    camera.projection = Projection::Equirectangular;
+    let latitude = (-45.0 as Real).to_radians();
+    let ray = camera.ray_at(lower_left.0, lower_left.1, &mut rng).unwrap();
+    // 135 degrees to the left, 45 degrees down
+    assert_direction(ray, Vec3R::new(-latitude.cos(), -1.0, latitude.cos()));
+    let ray = camera
+        .ray_at(upper_right.0, upper_right.1, &mut rng)
+        .unwrap();
+    assert_direction(ray, Vec3R::new(latitude.cos(), 1.0, latitude.cos()));
+    assert_direction(
+        camera.ray_at(0.5, 0.5, &mut rng).unwrap(),
+        Vec3R::new(0.0, 0.0, -1.0),
+    );
+}
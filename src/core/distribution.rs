@@ -0,0 +1,134 @@
+use super::defs::Real;
+use super::primitives::*;
+
+/// Piecewise constant distribution over [0, 1), with one step for each value of `func`.
+/// When all the values are zero it's uniform.
+pub struct Distribution1D {
+    func: Vec<Real>,
+    /// cdf[i] is the probability of sampling before step i, cdf has len + 1 entries
+    cdf: Vec<Real>,
+    integral: Real,
+}
+
+impl Distribution1D {
+    pub fn new(func: Vec<Real>) -> Distribution1D {
+        debug_assert!(!func.is_empty());
+        let n = func.len() as Real;
+        let mut cdf = Vec::with_capacity(func.len() + 1);
+        cdf.push(0.0);
+        for (i, value) in func.iter().enumerate() {
+            debug_assert!(*value >= 0.0);
+            cdf.push(cdf[i] + value / n);
+        }
+        let integral = cdf[func.len()];
+        for (i, c) in cdf.iter_mut().enumerate() {
+            *c = if integral > 0.0 {
+                *c / integral
+            } else {
+                i as Real / n
+            };
+        }
+        Distribution1D {
+            func,
+            cdf,
+            integral,
+        }
+    }
+
+    pub fn steps(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> Real {
+        self.integral
+    }
+
+    /// Maps the uniform `u` in [0, 1) to the distribution.
+    /// Returns the sampled value, its pdf and the index of its step.
+    pub fn sample(&self, u: Real) -> (Real, Real, usize) {
+        // last step whose cdf is <= u, skipping the empty ones
+        let offset = match self.cdf.binary_search_by(|c| {
+            if *c <= u {
+                std::cmp::Ordering::Less
+            } else {
+                std::cmp::Ordering::Greater
+            }
+        }) {
+            Ok(index) | Err(index) => index.max(1) - 1,
+        }
+        .min(self.steps() - 1);
+        let step = self.cdf[offset + 1] - self.cdf[offset];
+        let du = if step > 0.0 {
+            (u - self.cdf[offset]) / step
+        } else {
+            0.0
+        };
+        let x = (offset as Real + du) / self.steps() as Real;
+        (x.min(1.0 - Real::EPSILON), self.pdf(offset), offset)
+    }
+
+    /// Density of the step at `offset`.
+    pub fn pdf(&self, offset: usize) -> Real {
+        if self.integral > 0.0 {
+            self.func[offset] / self.integral
+        } else {
+            1.0
+        }
+    }
+}
+
+/// Piecewise constant distribution over [0, 1)^2, made of `height` rows of `width` steps,
+/// sampled choosing the row first and then the step inside the row.
+pub struct Distribution2D {
+    rows: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(func: &[Real], width: usize, height: usize) -> Distribution2D {
+        debug_assert!(func.len() == width * height);
+        let rows: Vec<Distribution1D> = func
+            .chunks(width)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect();
+        let marginal = Distribution1D::new(rows.iter().map(|row| row.integral()).collect());
+        Distribution2D { rows, marginal }
+    }
+
+    /// Maps two uniform numbers to the distribution, returns the point and its pdf.
+    pub fn sample(&self, u: Vec2R) -> (Vec2R, Real) {
+        let (y, pdf_y, row) = self.marginal.sample(u.y);
+        let (x, pdf_x, _) = self.rows[row].sample(u.x);
+        (Vec2R::new(x, y), pdf_x * pdf_y)
+    }
+
+    pub fn pdf(&self, point: Vec2R) -> Real {
+        let height = self.rows.len();
+        let row = ((point.y * height as Real) as usize).min(height - 1);
+        let width = self.rows[row].steps();
+        let column = ((point.x * width as Real) as usize).min(width - 1);
+        self.rows[row].pdf(column) * self.marginal.pdf(row)
+    }
+}
+
+#[test]
+fn test_distribution() {
+    let distribution = Distribution1D::new(vec![1.0, 3.0, 0.0, 4.0]);
+    assert_eq!(distribution.integral(), 2.0);
+    assert_eq!(distribution.sample(0.0), (0.0, 0.5, 0));
+    assert_eq!(distribution.sample(0.3125), (0.375, 1.5, 1));
+    // the empty step is never sampled
+    assert_eq!(distribution.sample(0.5), (0.75, 2.0, 3));
+    assert_eq!(distribution.sample(0.75), (0.875, 2.0, 3));
+
+    let uniform = Distribution1D::new(vec![0.0, 0.0]);
+    assert_eq!(uniform.sample(0.75), (0.75, 1.0, 1));
+
+    let distribution = Distribution2D::new(&[1.0, 0.0, 1.0, 2.0], 2, 2);
+    let (point, pdf) = distribution.sample(Vec2R::new(0.5, 0.4375));
+    assert!(point.distance(&Vec2R::new(0.625, 0.625)) < 1e-9);
+    assert!((pdf - 2.0).abs() < 1e-9);
+    assert_eq!(pdf, distribution.pdf(point));
+    assert_eq!(distribution.pdf(Vec2R::new(0.75, 0.25)), 0.0);
+    assert_eq!(distribution.pdf(Vec2R::new(0.25, 0.25)), 1.0);
+}
@@ -1,19 +1,21 @@
-use super::defs::Real;
+use super::defs::{Real, PI};
+use super::mesh::*;
 use super::primitives::*;
+use rand::{Rng, RngCore};
 use serde::Deserialize;
+use std::path::{Path, PathBuf};
 
 pub trait Geometry {
     fn intersect(&self, ray: &Ray, t_min: Real, t_max: Real) -> Real;
     fn hit(&self, ray: &Ray, time: Real) -> Hit;
-}
-
-impl<'a> From<GeometryType> for Box<dyn Geometry + Send + Sync> {
-    fn from(src: GeometryType) -> Box<dyn Geometry + Send + Sync> {
-        match src {
-            GeometryType::Sphere(geo) => Box::new(geo),
-            GeometryType::Line(geo) => Box::new(geo),
-            GeometryType::Cube(geo) => Box::new(geo),
-        }
+    fn bounding_box(&self) -> Aabb;
+    /// Surface area, 0 when the geometry can't be sampled as a light.
+    fn area(&self) -> Real {
+        0.0
+    }
+    /// Random point uniformly distributed on the surface.
+    fn sample_point(&self, _rng: &mut dyn RngCore) -> Option<Point3R> {
+        None
     }
 }
 
@@ -23,6 +25,37 @@ pub enum GeometryType {
     Sphere(Sphere),
     Line(Line),
     Cube(Cube),
+    Triangle(Triangle),
+    Mesh(DesMesh),
+    ObjMesh(ObjMesh),
+}
+
+/// Mesh loaded from a Wavefront OBJ file, `path` is relative to the scene file.
+#[derive(Deserialize, Debug)]
+pub struct ObjMesh {
+    pub path: PathBuf,
+}
+
+impl GeometryType {
+    /// Builds the geometry, loading any external file relative to `base_dir`.
+    pub fn load(self, base_dir: &Path) -> Result<Box<dyn Geometry + Send + Sync>, String> {
+        Ok(match self {
+            GeometryType::Sphere(geo) => Box::new(geo),
+            GeometryType::Line(geo) => {
+                if geo.width.is_nan() || geo.width <= 0.0 {
+                    return Err("line width must be positive".to_string());
+                }
+                Box::new(geo)
+            }
+            // the corners can be given in any order
+            GeometryType::Cube(geo) => Box::new(Cube::new(geo.min, geo.max)),
+            GeometryType::Triangle(geo) => Box::new(geo),
+            GeometryType::Mesh(geo) => {
+                Box::new(Mesh::new(geo.positions, geo.normals, geo.uvs, geo.indices)?)
+            }
+            GeometryType::ObjMesh(obj) => Box::new(Mesh::from_obj(&base_dir.join(obj.path))?),
+        })
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -35,6 +68,14 @@ impl Sphere {
     pub fn new(center: Point3R, radius: Real) -> Sphere {
         Sphere { center, radius }
     }
+
+    /// Longitude and latitude of a point on the unit sphere, u goes around the y axis
+    /// starting from -x and v goes from the bottom to the top.
+    fn uv(&self, p: &Vec3R) -> Vec2R {
+        let phi = (-p.z).atan2(p.x) + PI;
+        let theta = (-p.y).clamp(-1.0, 1.0).acos();
+        Vec2R::new(phi / (2.0 * PI), theta / PI)
+    }
 }
 
 /*
@@ -77,6 +118,7 @@ impl Geometry for Sphere {
         let hit = ray.at(time);
         let outward_normal = (hit - self.center) / self.radius;
         let is_front_face = ray.direction.vec().dot(&outward_normal) <= 0.0;
+        let uv = self.uv(&((hit - self.center) / self.radius.abs()));
 
         let normal = Unit3R::normalized(if is_front_face {
             outward_normal
@@ -86,10 +128,29 @@ impl Geometry for Sphere {
 
         Hit {
             point: hit,
+            local_point: hit,
             normal,
+            geometric_normal: normal,
             is_front_face,
+            barycentric: Vec2R::default(),
+            uv,
         }
     }
+
+    fn bounding_box(&self) -> Aabb {
+        // negative radiuses are used for hollow spheres
+        let radius = self.radius.abs();
+        let extent = Vec3R::new(radius, radius, radius);
+        Aabb::new(self.center - extent, self.center + extent)
+    }
+
+    fn area(&self) -> Real {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    fn sample_point(&self, rng: &mut dyn RngCore) -> Option<Point3R> {
+        Some(self.center + Unit3R::random_with(rng).vec() * self.radius.abs())
+    }
 }
 
 #[test]
@@ -111,30 +172,220 @@ fn test_intersection() {
 
 // ---- CUBE ------
 
+/// Axis aligned box spanning from the `min` to the `max` corner.
 #[derive(Deserialize, Debug)]
 pub struct Cube {
-    pub origin: Point3R,
-    pub corner: Point2R,
+    pub min: Point3R,
+    pub max: Point3R,
 }
 
 impl Cube {
-    pub fn new(origin: Point3R, corner: Point2R) -> Cube {
-        Cube { origin, corner }
+    pub fn new(corner_a: Point3R, corner_b: Point3R) -> Cube {
+        Cube {
+            min: corner_a.min(&corner_b),
+            max: corner_a.max(&corner_b),
+        }
     }
 }
 
+/*
+    slab method: for each axis the ray is inside the slab between the two planes
+    for t in [(min - origin) / direction, (max - origin) / direction].
+    The ray enters the box when it has entered all the slabs (max of the near times)
+    and exits when it leaves the first one (min of the far times).
+    When the direction has a 0 component the division gives +-inf, which
+    correctly makes that slab always (or never) crossed.
+*/
 impl Geometry for Cube {
-    fn intersect(&self, _ray: &Ray, _t_min: Real, _t_max: Real) -> Real {
+    fn intersect(&self, ray: &Ray, t_min: Real, t_max: Real) -> Real {
+        let mut t_enter = Real::NEG_INFINITY;
+        let mut t_exit = Real::INFINITY;
+        for axis in 0..3 {
+            let inv_direction = 1.0 / ray.direction.vec()[axis];
+            let t0 = (self.min[axis] - ray.origin[axis]) * inv_direction;
+            let t1 = (self.max[axis] - ray.origin[axis]) * inv_direction;
+            // f64 min and max ignore NaN, which happens when the origin lies on a plane
+            t_enter = t_enter.max(t0.min(t1));
+            t_exit = t_exit.min(t0.max(t1));
+        }
+        if t_enter > t_exit {
+            return Real::INFINITY;
+        }
+        if t_enter < t_max && t_enter > t_min {
+            return t_enter;
+        }
+        if t_exit < t_max && t_exit > t_min {
+            return t_exit;
+        }
         Real::INFINITY
     }
 
-    fn hit(&self, ray: &Ray, _time: Real) -> Hit {
+    fn hit(&self, ray: &Ray, time: Real) -> Hit {
+        let hit = ray.at(time);
+        // the face hit is the closest to the point, without dividing by the
+        // size of the box so that flat boxes work too
+        let to_min = (hit - self.min).abs();
+        let to_max = (self.max - hit).abs();
+        let distance = to_min.min(&to_max);
+        let axis = if distance.x <= distance.y && distance.x <= distance.z {
+            Vec3R::X
+        } else if distance.y <= distance.z {
+            Vec3R::Y
+        } else {
+            Vec3R::Z
+        };
+        let sign = if to_max[axis] < to_min[axis] {
+            1.0
+        } else if to_min[axis] < to_max[axis] {
+            -1.0
+        } else {
+            // both faces of a flat box are there, the one facing the ray is hit
+            -ray.direction.vec()[axis].signum()
+        };
+        let outward_normal = match axis {
+            Vec3R::X => Vec3R::new(sign, 0.0, 0.0),
+            Vec3R::Y => Vec3R::new(0.0, sign, 0.0),
+            _ => Vec3R::new(0.0, 0.0, sign),
+        };
+        let is_front_face = ray.direction.vec().dot(&outward_normal) <= 0.0;
+
+        let normal = Unit3R::normalized(if is_front_face {
+            outward_normal
+        } else {
+            -outward_normal
+        });
+
+        // the face is mapped to the whole texture, using the other two axes
+        let size = self.max - self.min;
+        let relative = |axis: usize| {
+            if size[axis] > 0.0 {
+                (hit[axis] - self.min[axis]) / size[axis]
+            } else {
+                0.0
+            }
+        };
+        let k = Vec3R::new(relative(0), relative(1), relative(2));
+        let uv = match axis {
+            Vec3R::X => Vec2R::new(k.z, k.y),
+            Vec3R::Y => Vec2R::new(k.x, k.z),
+            _ => Vec2R::new(k.x, k.y),
+        };
+
         Hit {
-            point: ray.origin,
-            normal: ray.direction,
-            is_front_face: true,
+            point: hit,
+            local_point: hit,
+            normal,
+            geometric_normal: normal,
+            is_front_face,
+            barycentric: Vec2R::default(),
+            uv,
         }
     }
+
+    fn bounding_box(&self) -> Aabb {
+        Aabb::new(self.min, self.max)
+    }
+
+    fn area(&self) -> Real {
+        Aabb::new(self.min, self.max).surface_area()
+    }
+
+    fn sample_point(&self, rng: &mut dyn RngCore) -> Option<Point3R> {
+        let size = (self.max - self.min).abs();
+        let min = self.min.min(&self.max);
+        // pick a couple of opposite faces proportionally to their area, then one of the two
+        let faces_area = [size.y * size.z, size.z * size.x, size.x * size.y];
+        let r = rng.gen::<Real>() * (faces_area[0] + faces_area[1] + faces_area[2]);
+        let side = if rng.gen::<bool>() { 1.0 } else { 0.0 };
+        let (u, v) = (rng.gen::<Real>(), rng.gen::<Real>());
+        let local = if r < faces_area[0] {
+            Vec3R::new(side, u, v)
+        } else if r < faces_area[0] + faces_area[1] {
+            Vec3R::new(u, side, v)
+        } else {
+            Vec3R::new(u, v, side)
+        };
+        Some(min + size * local)
+    }
+}
+
+#[test]
+fn test_cube_intersection() {
+    let cube = Cube::new(Point3R::new(1.0, 1.0, 3.0), Point3R::new(-1.0, -1.0, 1.0));
+    assert_eq!(cube.min, Point3R::new(-1.0, -1.0, 1.0));
+    assert_eq!(cube.max, Point3R::new(1.0, 1.0, 3.0));
+
+    // frontal hit on the near face
+    let ray = Ray::new(
+        Point3R::new(0.0, 0.0, -1.0),
+        Vec3R::new(0.0, 0.0, 1.0).unit(),
+    );
+    let t = cube.intersect(&ray, 0.0, Real::INFINITY);
+    assert_eq!(t, 2.0);
+    let hit = cube.hit(&ray, t);
+    assert!(hit.is_front_face);
+    assert_eq!(*hit.normal.vec(), Vec3R::new(0.0, 0.0, -1.0));
+    // the far face is found when the near one is excluded
+    assert_eq!(cube.intersect(&ray, 2.5, Real::INFINITY), 4.0);
+    assert_eq!(cube.intersect(&ray, 0.0, 1.5), Real::INFINITY);
+
+    // ray starting inside hits the back face from within
+    let ray = Ray::new(
+        Point3R::new(0.0, 0.0, 2.0),
+        Vec3R::new(1.0, 0.0, 0.0).unit(),
+    );
+    let t = cube.intersect(&ray, 0.0, Real::INFINITY);
+    assert_eq!(t, 1.0);
+    let hit = cube.hit(&ray, t);
+    assert!(!hit.is_front_face);
+    assert_eq!(*hit.normal.vec(), Vec3R::new(-1.0, 0.0, 0.0));
+
+    // top face hit diagonally
+    let ray = Ray::new(
+        Point3R::new(0.0, 3.0, 0.0),
+        Vec3R::new(0.0, -1.0, 1.0).unit(),
+    );
+    let t = cube.intersect(&ray, 0.0, Real::INFINITY);
+    assert!((t - 2.0 * (2.0 as Real).sqrt()).abs() < 1e-9);
+    let hit = cube.hit(&ray, t);
+    assert!(hit.is_front_face);
+    assert_eq!(*hit.normal.vec(), Vec3R::new(0.0, 1.0, 0.0));
+
+    // misses: parallel to a face outside the slab, and pointing away
+    let ray = Ray::new(
+        Point3R::new(0.0, 2.0, -1.0),
+        Vec3R::new(0.0, 0.0, 1.0).unit(),
+    );
+    assert_eq!(cube.intersect(&ray, 0.0, Real::INFINITY), Real::INFINITY);
+    let ray = Ray::new(
+        Point3R::new(0.0, 0.0, -1.0),
+        Vec3R::new(0.0, 0.0, -1.0).unit(),
+    );
+    assert_eq!(cube.intersect(&ray, 0.0, Real::INFINITY), Real::INFINITY);
+
+    // a flat box is a rectangle, its front face is the one facing the ray
+    let flat = Cube::new(Point3R::new(-1.0, 1.0, -1.0), Point3R::new(1.0, 1.0, 1.0));
+    for &direction in &[-1.0, 1.0] {
+        let ray = Ray::new(
+            Point3R::new(0.5, 1.0 - 2.0 * direction, 0.0),
+            Vec3R::new(0.0, direction, 0.0).unit(),
+        );
+        let t = flat.intersect(&ray, 0.0, Real::INFINITY);
+        assert_eq!(t, 2.0);
+        let hit = flat.hit(&ray, t);
+        assert!(hit.is_front_face);
+        assert_eq!(*hit.normal.vec(), Vec3R::new(0.0, -direction, 0.0));
+        assert_eq!(hit.uv, Vec2R::new(0.75, 0.5));
+    }
+
+    // the corners of a loaded cube can be in any order
+    let geometry: GeometryType = serde_json::from_str(
+        r#"{"type": "cube", "min": {"x": 1, "y": 1, "z": 3}, "max": {"x": -1, "y": -1, "z": 1}}"#,
+    )
+    .unwrap();
+    let bounds = geometry.load(Path::new("")).unwrap().bounding_box();
+    assert_eq!(bounds.min, Point3R::new(-1.0, -1.0, 1.0));
+    assert_eq!(bounds.max, Point3R::new(1.0, 1.0, 3.0));
 }
 
 // ---- LINE ------
@@ -150,18 +401,276 @@ impl Line {
     pub fn new(start: Point3R, end: Point3R, width: Real) -> Line {
         Line { start, end, width }
     }
+
+    pub fn radius(&self) -> Real {
+        self.width * 0.5
+    }
+
+    /// u goes around the axis, v along it from the start to the end.
+    fn uv(&self, point: &Point3R) -> Vec2R {
+        let axis = self.end - self.start;
+        let length_squared = axis.length_squared();
+        if length_squared <= 0.0 {
+            return Vec2R::default();
+        }
+        let (tangent, bitangent) = axis.unit().basis();
+        let diff = point - self.start;
+        let phi = diff.dot(&bitangent).atan2(diff.dot(&tangent));
+        let v = (diff.dot(&axis) / length_squared).clamp(0.0, 1.0);
+        Vec2R::new(0.5 + phi / (2.0 * PI), v)
+    }
+
+    /// Point of the segment closest to `point`.
+    fn closest_on_segment(&self, point: &Point3R) -> Point3R {
+        let axis = self.end - self.start;
+        let axis_length_squared = axis.length_squared();
+        if axis_length_squared > 0.0 {
+            let k = ((point - self.start).dot(&axis) / axis_length_squared).clamp(0.0, 1.0);
+            self.start + axis * k
+        } else {
+            self.start
+        }
+    }
 }
 
+/*
+    The line is a capsule: a cylinder of radius width/2 around the segment
+    closed by two half spheres at the ends.
+
+    Axis = End - Start, Delta = Origin - Start
+    The distance of a point from the infinite axis is r when
+    |Delta + t*Direction|^2 - ((Delta + t*Direction).dot.Axis)^2 / Axis.dot.Axis = r^2
+    multiplying by Axis.dot.Axis (aa) and using the reduced form like the sphere:
+    a = aa - (Axis.dot.Direction)^2
+    h = aa*(Delta.dot.Direction) - (Axis.dot.Delta)*(Axis.dot.Direction)
+    c = aa*(Delta.dot.Delta) - (Axis.dot.Delta)^2 - r^2*aa
+    A root belongs to the body only if its projection on the axis falls inside the segment,
+    otherwise the surface there is given by the sphere of the nearest end.
+*/
 impl Geometry for Line {
-    fn intersect(&self, _ray: &Ray, _t_min: Real, _t_max: Real) -> Real {
-        Real::INFINITY
+    fn intersect(&self, ray: &Ray, t_min: Real, t_max: Real) -> Real {
+        let radius = self.radius();
+        let direction = ray.direction.vec();
+        let axis = self.end - self.start;
+        let diff = ray.origin - self.start;
+        let axis_axis = axis.dot(&axis);
+        let axis_direction = axis.dot(direction);
+        let axis_diff = axis.dot(&diff);
+
+        let mut closest = Real::INFINITY;
+        let mut consider = |t: Real| {
+            if t > t_min && t < t_max && t < closest {
+                closest = t;
+            }
+        };
+
+        // body, skipped when the ray is parallel to the axis
+        let a = axis_axis - axis_direction * axis_direction;
+        if a > 100.0 * Real::EPSILON * axis_axis {
+            let h = axis_axis * diff.dot(direction) - axis_diff * axis_direction;
+            let c =
+                axis_axis * diff.dot(&diff) - axis_diff * axis_diff - radius * radius * axis_axis;
+            let discriminant = h * h - a * c;
+            if discriminant >= 0.0 {
+                let root = discriminant.sqrt();
+                for &t in &[(-h - root) / a, (-h + root) / a] {
+                    let projection = axis_diff + t * axis_direction;
+                    if projection >= 0.0 && projection <= axis_axis {
+                        consider(t);
+                    }
+                }
+            }
+        }
+
+        // caps, same as the sphere
+        for &(center, is_start) in &[(self.start, true), (self.end, false)] {
+            let cap_diff = ray.origin - center;
+            let h = cap_diff.dot(direction);
+            let c = cap_diff.dot(&cap_diff) - radius * radius;
+            let discriminant = h * h - c;
+            if discriminant >= 0.0 {
+                let root = discriminant.sqrt();
+                for &t in &[-h - root, -h + root] {
+                    let projection = axis_diff + t * axis_direction;
+                    if (is_start && projection <= 0.0) || (!is_start && projection >= axis_axis) {
+                        consider(t);
+                    }
+                }
+            }
+        }
+
+        closest
     }
 
-    fn hit(&self, ray: &Ray, _time: Real) -> Hit {
+    fn hit(&self, ray: &Ray, time: Real) -> Hit {
+        let hit = ray.at(time);
+        let outward_normal = (hit - self.closest_on_segment(&hit)) / self.radius();
+        let is_front_face = ray.direction.vec().dot(&outward_normal) <= 0.0;
+        let uv = self.uv(&hit);
+
+        let normal = Unit3R::normalized(if is_front_face {
+            outward_normal
+        } else {
+            -outward_normal
+        });
+
         Hit {
-            point: ray.origin,
-            normal: ray.direction,
-            is_front_face: true,
+            point: hit,
+            local_point: hit,
+            normal,
+            geometric_normal: normal,
+            is_front_face,
+            barycentric: Vec2R::default(),
+            uv,
+        }
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let radius = self.radius().abs();
+        let extent = Vec3R::new(radius, radius, radius);
+        Aabb::new(self.start, self.end)
+            .grow(&(self.start.min(&self.end) - extent))
+            .grow(&(self.start.max(&self.end) + extent))
+    }
+
+    fn area(&self) -> Real {
+        let radius = self.radius();
+        2.0 * PI * radius * self.start.distance(&self.end) + 4.0 * PI * radius * radius
+    }
+
+    fn sample_point(&self, rng: &mut dyn RngCore) -> Option<Point3R> {
+        let radius = self.radius().abs();
+        let axis = self.end - self.start;
+        let length = axis.length();
+        let body_area = 2.0 * PI * radius * length;
+        if rng.gen::<Real>() * self.area() < body_area {
+            let (tangent, bitangent) = axis.unit().basis();
+            let angle = rng.gen_range(0.0, 2.0 * PI);
+            let offset = tangent * angle.cos() + bitangent * angle.sin();
+            Some(self.start + axis * rng.gen::<Real>() + offset * radius)
+        } else {
+            // each half of the sphere goes to the cap on its side
+            let offset = Unit3R::random_with(rng).vec() * radius;
+            if offset.dot(&axis) >= 0.0 {
+                Some(self.end + offset)
+            } else {
+                Some(self.start + offset)
+            }
         }
     }
 }
+
+#[test]
+fn test_line_intersection() {
+    let line = Line::new(
+        Point3R::new(-1.0, 0.0, 2.0),
+        Point3R::new(1.0, 0.0, 2.0),
+        1.0,
+    );
+    let eps = 1e-9;
+
+    // body hit, normal points back at the ray
+    let ray = Ray::new(
+        Point3R::new(0.0, 0.0, -1.0),
+        Vec3R::new(0.0, 0.0, 1.0).unit(),
+    );
+    let t = line.intersect(&ray, 0.0, Real::INFINITY);
+    assert!((t - 2.5).abs() < eps);
+    let hit = line.hit(&ray, t);
+    assert!(hit.is_front_face);
+    assert!((hit.normal.vec() - Vec3R::new(0.0, 0.0, -1.0)).length() < eps);
+    // the far side of the body, seen from the inside
+    let t = line.intersect(&ray, 3.0, Real::INFINITY);
+    assert!((t - 3.5).abs() < eps);
+    let hit = line.hit(&ray, t);
+    assert!(!hit.is_front_face);
+    assert!((hit.normal.vec() - Vec3R::new(0.0, 0.0, -1.0)).length() < eps);
+
+    // grazing rays just inside and just outside the radius
+    let ray = Ray::new(
+        Point3R::new(0.0, 0.5 - 1e-6, -1.0),
+        Vec3R::new(0.0, 0.0, 1.0).unit(),
+    );
+    let t = line.intersect(&ray, 0.0, Real::INFINITY);
+    assert!((t - 3.0).abs() < 1e-2);
+    let hit = line.hit(&ray, t);
+    assert!(hit.normal.vec().y > 0.99);
+    let ray = Ray::new(
+        Point3R::new(0.0, 0.5 + 1e-6, -1.0),
+        Vec3R::new(0.0, 0.0, 1.0).unit(),
+    );
+    assert_eq!(line.intersect(&ray, 0.0, Real::INFINITY), Real::INFINITY);
+
+    // along the axis the rounded end cap is hit first
+    let ray = Ray::new(
+        Point3R::new(-4.0, 0.0, 2.0),
+        Vec3R::new(1.0, 0.0, 0.0).unit(),
+    );
+    let t = line.intersect(&ray, 0.0, Real::INFINITY);
+    assert!((t - 2.5).abs() < eps);
+    let hit = line.hit(&ray, t);
+    assert!(hit.is_front_face);
+    assert!((hit.normal.vec() - Vec3R::new(-1.0, 0.0, 0.0)).length() < eps);
+    // and it leaves from the other cap
+    let t = line.intersect(&ray, 2.6, Real::INFINITY);
+    assert!((t - 5.5).abs() < eps);
+    let hit = line.hit(&ray, t);
+    assert!(!hit.is_front_face);
+    assert!((hit.normal.vec() - Vec3R::new(-1.0, 0.0, 0.0)).length() < eps);
+
+    // end cap hit off axis, beyond the end of the segment
+    let ray = Ray::new(
+        Point3R::new(1.3, 0.0, -1.0),
+        Vec3R::new(0.0, 0.0, 1.0).unit(),
+    );
+    let t = line.intersect(&ray, 0.0, Real::INFINITY);
+    assert!((t - 2.6).abs() < eps);
+    let hit = line.hit(&ray, t);
+    assert!((hit.normal.vec() - Vec3R::new(0.6, 0.0, -0.8)).length() < eps);
+
+    // past the caps
+    let ray = Ray::new(
+        Point3R::new(1.6, 0.0, -1.0),
+        Vec3R::new(0.0, 0.0, 1.0).unit(),
+    );
+    assert_eq!(line.intersect(&ray, 0.0, Real::INFINITY), Real::INFINITY);
+
+    // lines without a width are refused when loaded
+    for width in &["0", "-1"] {
+        let geometry: GeometryType = serde_json::from_str(&format!(
+            r#"{{"type": "line", "start": {{"x": 0, "y": 0, "z": 0}}, "end": {{"x": 1, "y": 0, "z": 0}}, "width": {}}}"#,
+            width
+        ))
+        .unwrap();
+        assert!(geometry.load(Path::new("")).is_err());
+    }
+}
+
+#[test]
+fn test_sample_point() {
+    let sphere = Sphere::new(Point3R::new(1.0, 2.0, 3.0), -2.0);
+    let cube = Cube::new(Point3R::new(-1.0, 0.0, 2.0), Point3R::new(1.0, 3.0, 3.0));
+    let line = Line::new(
+        Point3R::new(0.0, 0.0, 0.0),
+        Point3R::new(0.0, 2.0, 0.0),
+        1.0,
+    );
+    let eps = 1e-9;
+    let mut rng = rand::thread_rng();
+    assert!((sphere.area() - 16.0 * PI).abs() < eps);
+    assert!((cube.area() - 2.0 * (6.0 + 3.0 + 2.0)).abs() < eps);
+    assert!((line.area() - (2.0 * PI + PI)).abs() < eps);
+    for _ in 0..100 {
+        let point = sphere.sample_point(&mut rng).unwrap();
+        assert!((point.distance(&sphere.center) - 2.0).abs() < eps);
+
+        let point = cube.sample_point(&mut rng).unwrap();
+        let to_min = (point - cube.min).abs();
+        let to_max = (point - cube.max).abs();
+        assert!(to_min.min(&to_max).min_component() < eps);
+        assert_eq!(point.max(&cube.min).min(&cube.max), point);
+
+        let point = line.sample_point(&mut rng).unwrap();
+        assert!((point.distance(&line.closest_on_segment(&point)) - 0.5).abs() < eps);
+    }
+}
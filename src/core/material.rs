@@ -1,50 +1,149 @@
+use super::defs::PI;
+use super::microfacet::*;
 use super::primitives::*;
-use rand::thread_rng;
-use rand::Rng;
+use super::spectrum::Dispersion;
+use super::texture::{Albedo, Texture};
+use rand::{Rng, RngCore};
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
 
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type", rename_all = "kebab-case")]
 pub enum DesMaterial {
     Diffuse(Diffuse),
     Metal(Metal),
+    Conductor(Conductor),
     Dieletric(Dieletric),
+    Emissive(Emissive),
+    Principled(Principled),
+    Interface(Interface),
 }
 
-impl<'a> From<DesMaterial> for Box<dyn Material + Send + Sync> {
-    fn from(src: DesMaterial) -> Box<dyn Material + Send + Sync> {
-        match src {
-            DesMaterial::Diffuse(mat) => Box::new(mat),
-            DesMaterial::Metal(mat) => Box::new(mat),
-            DesMaterial::Dieletric(mat) => Box::new(mat),
-        }
+impl DesMaterial {
+    /// Builds the material, binding the texture names to `textures`.
+    pub fn load(
+        self,
+        textures: &HashMap<String, Arc<dyn Texture + Send + Sync>>,
+    ) -> Result<Box<dyn Material + Send + Sync>, String> {
+        Ok(match self {
+            DesMaterial::Diffuse(mut mat) => {
+                mat.albedo.resolve(textures)?;
+                Box::new(mat)
+            }
+            DesMaterial::Metal(mut mat) => {
+                mat.albedo.resolve(textures)?;
+                Box::new(mat)
+            }
+            DesMaterial::Conductor(mat) => {
+                mat.validate()?;
+                Box::new(mat)
+            }
+            DesMaterial::Dieletric(mut mat) => {
+                mat.validate()?;
+                mat.albedo.resolve(textures)?;
+                Box::new(mat)
+            }
+            DesMaterial::Emissive(mat) => Box::new(mat),
+            DesMaterial::Principled(mut mat) => {
+                mat.validate()?;
+                mat.base_color.resolve(textures)?;
+                Box::new(mat)
+            }
+            DesMaterial::Interface(mat) => Box::new(mat),
+        })
+    }
+}
+
+/// Direction sampled by a material.
+pub struct Bounce {
+    /// the bounced ray, its color is the incoming color times bsdf * cosine / pdf
+    pub ray: Ray,
+    /// probability density (solid angle) of the sampled direction,
+    /// None for specular bounces that can't be evaluated in other directions
+    pub pdf: Option<Real>,
+}
+
+impl Bounce {
+    pub fn specular(ray: Ray) -> Bounce {
+        Bounce { ray, pdf: None }
     }
 }
 
 pub trait Material {
-    fn bounce(&self, ray: &Ray, hit: &Hit) -> Ray;
+    fn bounce(&self, ray: &Ray, hit: &Hit, rng: &mut dyn RngCore) -> Bounce;
+    /// Bsdf times the cosine with the normal for light coming from `direction` and leaving
+    /// towards the ray origin, together with the pdf of `bounce` sampling `direction`.
+    /// Specular materials return zero.
+    fn eval(&self, _ray: &Ray, _hit: &Hit, _direction: &Unit3R) -> (Vec3R, Real) {
+        (Vec3R::default(), 0.0)
+    }
+    /// Radiance emitted from the hit point towards the ray origin.
+    fn emitted(&self, _ray: &Ray, _hit: &Hit) -> Vec3R {
+        Vec3R::default()
+    }
+    /// Whether the objects with this material are sampled as lights.
+    fn is_light(&self) -> bool {
+        false
+    }
+    /// Whether the surface is invisible and only bounds a medium, rays go through it.
+    fn is_interface(&self) -> bool {
+        false
+    }
+    /// Color of the surface at the hit point, the fraction of light it reflects
+    /// or transmits at normal incidence, for the output variables.
+    fn albedo(&self, _ray: &Ray, _hit: &Hit) -> Vec3R {
+        Vec3R::default()
+    }
 }
 
 // ------- DIFFUSE -------
 
 #[derive(Deserialize, Debug)]
 pub struct Diffuse {
-    pub albedo: Vec3R,
+    pub albedo: Albedo,
 }
 
 impl Diffuse {
     pub fn new(albedo: Vec3R) -> Diffuse {
-        Diffuse { albedo }
+        Diffuse {
+            albedo: albedo.into(),
+        }
     }
 }
 
+/*
+    normal + random unit vector is cosine distributed around the normal,
+    pdf = cos / PI, so the bsdf (albedo / PI) * cos / pdf is just the albedo.
+*/
 impl Material for Diffuse {
-    fn bounce(&self, ray: &Ray, hit: &Hit) -> Ray {
-        Ray::with_color(
-            hit.point,
-            (hit.normal.vec() + Unit3R::random().vec()).unit(),
-            ray.color * self.albedo,
-        )
+    fn bounce(&self, ray: &Ray, hit: &Hit, rng: &mut dyn RngCore) -> Bounce {
+        let direction = (hit.normal.vec() + Unit3R::random_with(rng).vec()).unit();
+        let cosine = direction.vec().dot(hit.normal.vec()).max(0.0);
+        Bounce {
+            ray: Ray::with_color(
+                hit.point,
+                direction,
+                ray.color * ray.color_of(&self.albedo.value(hit)),
+            ),
+            pdf: Some(cosine / PI),
+        }
+    }
+
+    fn eval(&self, ray: &Ray, hit: &Hit, direction: &Unit3R) -> (Vec3R, Real) {
+        let cosine = direction.vec().dot(hit.normal.vec());
+        if cosine > 0.0 {
+            (
+                ray.color_of(&self.albedo.value(hit)) * (cosine / PI),
+                cosine / PI,
+            )
+        } else {
+            (Vec3R::default(), 0.0)
+        }
+    }
+
+    fn albedo(&self, _ray: &Ray, hit: &Hit) -> Vec3R {
+        self.albedo.value(hit)
     }
 }
 
@@ -52,7 +151,7 @@ impl Material for Diffuse {
 
 #[derive(Deserialize, Debug)]
 pub struct Metal {
-    pub albedo: Vec3R,
+    pub albedo: Albedo,
     #[serde(default)]
     pub fuzz: Real,
 }
@@ -60,7 +159,7 @@ pub struct Metal {
 impl Metal {
     pub fn new(albedo: Vec3R, fuzz: Real) -> Metal {
         Metal {
-            albedo,
+            albedo: albedo.into(),
             fuzz: fuzz.min(1.0),
         }
     }
@@ -70,31 +169,343 @@ impl Metal {
     }
 }
 impl Material for Metal {
-    fn bounce(&self, ray: &Ray, hit: &Hit) -> Ray {
+    fn bounce(&self, ray: &Ray, hit: &Hit, rng: &mut dyn RngCore) -> Bounce {
         let mut reflected = Metal::reflect(&ray.direction, &hit.normal);
         if self.fuzz > 0.0 {
-            reflected += Unit3R::random().vec() * self.fuzz;
+            reflected += Unit3R::random_with(rng).vec() * self.fuzz;
         }
         let reflected = reflected.unit();
         let color = if reflected.vec().dot(hit.normal.vec()) > 0.0 {
-            ray.color * self.albedo
+            ray.color * ray.color_of(&self.albedo.value(hit))
         } else {
             Vec3R::new(0.0, 0.0, 0.0)
         };
-        Ray::with_color(hit.point, reflected, color)
+        Bounce::specular(Ray::with_color(hit.point, reflected, color))
+    }
+
+    fn albedo(&self, _ray: &Ray, hit: &Hit) -> Vec3R {
+        self.albedo.value(hit)
+    }
+}
+
+// ------- CONDUCTOR -------
+
+/// Rough metal with the GGX microfacet distribution and the Fresnel reflectance
+/// of its complex index of refraction, a roughness of 0 makes a perfect mirror.
+#[derive(Deserialize, Debug)]
+pub struct Conductor {
+    #[serde(flatten)]
+    pub ior: ConductorIor,
+    #[serde(default)]
+    pub roughness: Real,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(untagged)]
+pub enum ConductorIor {
+    Preset {
+        metal: MetalPreset,
+    },
+    /// red, green and blue components of the index of refraction `eta + i k`
+    Measured {
+        eta: Vec3R,
+        k: Vec3R,
+    },
+}
+
+/// Measured metals, indices of refraction at 650, 550 and 450 nm.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MetalPreset {
+    Gold,
+    Silver,
+    Copper,
+    Aluminium,
+    Iron,
+    Chromium,
+}
+
+impl ConductorIor {
+    /// `(eta, k)` of the metal.
+    pub fn eta_k(&self) -> (Vec3R, Vec3R) {
+        match self {
+            ConductorIor::Measured { eta, k } => (*eta, *k),
+            ConductorIor::Preset { metal } => match metal {
+                MetalPreset::Gold => (
+                    Vec3R::new(0.143, 0.374, 1.442),
+                    Vec3R::new(3.983, 2.385, 1.603),
+                ),
+                MetalPreset::Silver => (
+                    Vec3R::new(0.155, 0.117, 0.138),
+                    Vec3R::new(4.828, 3.122, 2.147),
+                ),
+                MetalPreset::Copper => (
+                    Vec3R::new(0.200, 0.924, 1.102),
+                    Vec3R::new(3.912, 2.452, 2.142),
+                ),
+                MetalPreset::Aluminium => (
+                    Vec3R::new(1.657, 0.880, 0.521),
+                    Vec3R::new(9.224, 6.270, 4.837),
+                ),
+                MetalPreset::Iron => (
+                    Vec3R::new(2.911, 2.950, 2.585),
+                    Vec3R::new(3.089, 2.932, 2.767),
+                ),
+                MetalPreset::Chromium => (
+                    Vec3R::new(3.107, 3.181, 2.323),
+                    Vec3R::new(3.331, 3.329, 3.135),
+                ),
+            },
+        }
+    }
+}
+
+impl Conductor {
+    #[cfg(test)]
+    pub fn new(ior: ConductorIor, roughness: Real) -> Conductor {
+        Conductor { ior, roughness }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let (eta, k) = self.ior.eta_k();
+        if !(self.roughness >= 0.0 && self.roughness <= 1.0) {
+            Err("conductor roughness must be between 0 and 1".to_string())
+        } else if !(eta.min_component() > 0.0 && k.min_component() >= 0.0) {
+            Err("conductor eta must be positive and k not negative".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn fresnel(&self, ray: &Ray, cosine: Real) -> Vec3R {
+        let (eta, k) = self.ior.eta_k();
+        fresnel_conductor(cosine, &ray.color_of(&eta), &ray.color_of(&k))
+    }
+}
+
+/*
+    sampling the visible normals the weight of a bounce,
+    F * D * G / (4 cos_o cos_i) * cos_i / pdf, simplifies to F * G / G1(wo)
+*/
+impl Material for Conductor {
+    fn bounce(&self, ray: &Ray, hit: &Hit, rng: &mut dyn RngCore) -> Bounce {
+        let absorbed = Bounce::specular(Ray::with_color(hit.point, hit.normal, Vec3R::default()));
+        let ggx = Ggx::from_roughness(self.roughness);
+        if ggx.is_smooth() {
+            let cosine = (-ray.direction.vec()).dot(hit.normal.vec());
+            if cosine <= 0.0 {
+                return absorbed;
+            }
+            let reflected = Metal::reflect(&ray.direction, &hit.normal).unit();
+            return Bounce::specular(Ray::with_color(
+                hit.point,
+                reflected,
+                ray.color * self.fresnel(ray, cosine),
+            ));
+        }
+        let frame = Frame::new(&hit.normal);
+        let wo = frame.to_local(&-ray.direction.vec());
+        if wo.z <= 0.0 {
+            return absorbed;
+        }
+        let h = ggx.sample_visible(&wo, Vec2R::new(rng.gen(), rng.gen()));
+        let wi = reflect(&wo, &h);
+        let cos_d = wo.dot(&h);
+        if wi.z <= 0.0 || cos_d <= 0.0 {
+            return absorbed;
+        }
+        Bounce {
+            ray: Ray::with_color(
+                hit.point,
+                frame.to_world(&wi).unit(),
+                ray.color * self.fresnel(ray, cos_d) * (ggx.g(&wo, &wi) / ggx.g1(&wo)),
+            ),
+            pdf: Some(ggx.pdf_visible(&wo, &h) / (4.0 * cos_d)),
+        }
+    }
+
+    fn eval(&self, ray: &Ray, hit: &Hit, direction: &Unit3R) -> (Vec3R, Real) {
+        let ggx = Ggx::from_roughness(self.roughness);
+        let frame = Frame::new(&hit.normal);
+        let wo = frame.to_local(&-ray.direction.vec());
+        let wi = frame.to_local(direction.vec());
+        if ggx.is_smooth() || wo.z <= 0.0 || wi.z <= 0.0 {
+            return (Vec3R::default(), 0.0);
+        }
+        let h = (wo + wi).normalize();
+        let cos_d = wo.dot(&h);
+        let bsdf = self.fresnel(ray, cos_d) * (ggx.d(&h) * ggx.g(&wo, &wi) / (4.0 * wo.z));
+        (bsdf, ggx.pdf_visible(&wo, &h) / (4.0 * cos_d))
+    }
+
+    fn albedo(&self, ray: &Ray, _hit: &Hit) -> Vec3R {
+        self.fresnel(ray, 1.0)
     }
 }
 
 // ------- DIELETRIC -------
+
+/// Glass-like material. `albedo` tints the light at every interface, while
+/// `absorption_color` is the color that white light takes after traveling
+/// `absorption_distance` inside, so thicker glass is darker.
+/// With a roughness above 0 the surface is frosted, using GGX microfacets
+/// (Walter et al. 2007, "Microfacet Models for Refraction through Rough Surfaces").
 #[derive(Deserialize, Debug)]
 pub struct Dieletric {
-    pub albedo: Vec3R,
+    #[serde(default = "Dieletric::default_albedo")]
+    pub albedo: Albedo,
     pub refraction: Real,
+    #[serde(default)]
+    pub roughness: Real,
+    #[serde(default)]
+    pub absorption_color: Option<Vec3R>,
+    #[serde(default = "Dieletric::default_absorption_distance")]
+    pub absorption_distance: Real,
+    /// index of refraction for each wavelength in spectral mode, replaces `refraction`
+    #[serde(default)]
+    pub dispersion: Option<Dispersion>,
 }
 
 impl Dieletric {
     pub fn new(albedo: Vec3R, refraction: Real) -> Dieletric {
-        Dieletric { albedo, refraction }
+        Dieletric {
+            albedo: albedo.into(),
+            refraction,
+            roughness: 0.0,
+            absorption_color: None,
+            absorption_distance: 1.0,
+            dispersion: None,
+        }
+    }
+
+    fn default_albedo() -> Albedo {
+        Vec3R::new(1.0, 1.0, 1.0).into()
+    }
+
+    fn default_absorption_distance() -> Real {
+        1.0
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.refraction.is_nan() || self.refraction <= 0.0 {
+            Err("dieletric refraction must be positive".to_string())
+        } else if !(self.roughness >= 0.0 && self.roughness <= 1.0) {
+            Err("dieletric roughness must be between 0 and 1".to_string())
+        } else if self.absorption_distance.is_nan() || self.absorption_distance <= 0.0 {
+            Err("dieletric absorption distance must be positive".to_string())
+        } else if let Some(color) = self.absorption_color {
+            if color.min_component() > 0.0 && color.max_component() <= 1.0 {
+                Ok(())
+            } else {
+                Err("dieletric absorption color must be between 0 (excluded) and 1".to_string())
+            }
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether the wavelengths of the ray would bend each in its own direction.
+    fn is_dispersive(&self, ray: &Ray) -> bool {
+        self.dispersion.is_some() && ray.wavelengths.is_some_and(|w| !w.is_single())
+    }
+
+    /// Index of refraction at the hero wavelength of the ray.
+    fn ior(&self, ray: &Ray) -> Real {
+        match (&self.dispersion, &ray.wavelengths) {
+            (Some(dispersion), Some(wavelengths)) => dispersion.ior(wavelengths.hero()),
+            _ => self.refraction,
+        }
+    }
+
+    /// Surface tint times the light left after traveling inside, from the previous surface
+    /// crossed by the ray up to the hit (Beer-Lambert law).
+    fn tint(&self, ray: &Ray, hit: &Hit) -> Vec3R {
+        let albedo = ray.color_of(&self.albedo.value(hit));
+        match self.absorption_color {
+            Some(color) if !hit.is_front_face => {
+                let color = ray.color_of(&color);
+                let k = hit.point.distance(&ray.origin) / self.absorption_distance;
+                albedo * Vec3R::new(color.x.powf(k), color.y.powf(k), color.z.powf(k))
+            }
+            _ => albedo,
+        }
+    }
+
+    /// Index of refraction of the other side over the one of the ray side.
+    fn eta(&self, ray: &Ray, hit: &Hit) -> Real {
+        if hit.is_front_face {
+            self.ior(ray)
+        } else {
+            1.0 / self.ior(ray)
+        }
+    }
+
+    /// Rough bounce in local space, reflection or refraction around a visible microfacet
+    /// chosen by the Fresnel reflectance, the weight simplifies to G / G1(wo).
+    fn bounce_rough(
+        &self,
+        ggx: &Ggx,
+        wo: &Vec3R,
+        eta: Real,
+        rng: &mut dyn RngCore,
+    ) -> Option<(Vec3R, Real, Real)> {
+        let h = ggx.sample_visible(wo, Vec2R::new(rng.gen(), rng.gen()));
+        let cos_o = wo.dot(&h);
+        if cos_o <= 0.0 {
+            return None;
+        }
+        let fresnel = fresnel_dielectric(cos_o, eta);
+        let wi = if rng.gen::<Real>() < fresnel {
+            let wi = reflect(wo, &h);
+            if wi.z <= 0.0 {
+                return None;
+            }
+            wi
+        } else {
+            let cos_t = (1.0 - (1.0 - cos_o * cos_o) / (eta * eta)).max(0.0).sqrt();
+            let wi = -wo / eta + (cos_o / eta - cos_t) * h;
+            if wi.z >= 0.0 {
+                return None;
+            }
+            wi.normalize()
+        };
+        let (_, pdf) = self.eval_rough(ggx, wo, &wi, eta);
+        Some((wi, ggx.g(wo, &wi) / ggx.g1(wo), pdf))
+    }
+
+    /// Bsdf times the cosine and pdf in local space, without the tint.
+    fn eval_rough(&self, ggx: &Ggx, wo: &Vec3R, wi: &Vec3R, eta: Real) -> (Real, Real) {
+        if wo.z <= 0.0 || wi.z == 0.0 {
+            return (0.0, 0.0);
+        }
+        if wi.z > 0.0 {
+            let h = (wo + wi).normalize();
+            let cos_o = wo.dot(&h);
+            let fresnel = fresnel_dielectric(cos_o, eta);
+            (
+                fresnel * ggx.d(&h) * ggx.g(wo, wi) / (4.0 * wo.z),
+                fresnel * ggx.pdf_visible(wo, &h) / (4.0 * cos_o),
+            )
+        } else {
+            // generalized half vector of the refraction, towards the ray side
+            let mut h = (wo + eta * wi).normalize();
+            if h.z < 0.0 {
+                h = -h;
+            }
+            let cos_o = wo.dot(&h);
+            let cos_i = wi.dot(&h);
+            if cos_o <= 0.0 || cos_i >= 0.0 {
+                return (0.0, 0.0);
+            }
+            let fresnel = fresnel_dielectric(cos_o, eta);
+            let denominator = cos_o + eta * cos_i;
+            // change of variables from the microfacet normal to the refracted direction
+            let jacobian = eta * eta * -cos_i / (denominator * denominator);
+            (
+                (1.0 - fresnel) * ggx.d(&h) * ggx.g(wo, wi) * cos_o * jacobian / wo.z,
+                (1.0 - fresnel) * ggx.pdf_visible(wo, &h) * jacobian,
+            )
+        }
     }
     fn refract(incoming: &Unit3R, normal: &Unit3R, etai_over_etat: Real, cos_theta: Real) -> Vec3R {
         let incoming = incoming.vec();
@@ -112,27 +523,631 @@ impl Dieletric {
 }
 
 impl Material for Dieletric {
-    fn bounce(&self, ray: &Ray, hit: &Hit) -> Ray {
-        let etai_over_etat = if hit.is_front_face {
-            1.0 / self.refraction
+    fn bounce(&self, ray: &Ray, hit: &Hit, rng: &mut dyn RngCore) -> Bounce {
+        let mut bounce = self.scatter(ray, hit, rng);
+        if self.is_dispersive(ray) {
+            // the direction is right only for the hero wavelength, which takes the place of all
+            let hero = bounce.ray.color.x;
+            bounce.ray.color = Vec3R::new(hero, hero, hero);
+            bounce.ray.wavelengths = ray.wavelengths.map(|w| w.hero_only());
+        }
+        bounce
+    }
+
+    fn eval(&self, ray: &Ray, hit: &Hit, direction: &Unit3R) -> (Vec3R, Real) {
+        let ggx = Ggx::from_roughness(self.roughness);
+        if ggx.is_smooth() {
+            return (Vec3R::default(), 0.0);
+        }
+        let frame = Frame::new(&hit.normal);
+        let wo = frame.to_local(&-ray.direction.vec());
+        let wi = frame.to_local(direction.vec());
+        let (bsdf, pdf) = self.eval_rough(&ggx, &wo, &wi, self.eta(ray, hit));
+        let bsdf = self.tint(ray, hit) * bsdf;
+        if self.is_dispersive(ray) {
+            // as if the bounce kept only the hero wavelength
+            (Vec3R::new(3.0 * bsdf.x, 0.0, 0.0), pdf)
         } else {
-            self.refraction
-        };
+            (bsdf, pdf)
+        }
+    }
+
+    fn albedo(&self, _ray: &Ray, hit: &Hit) -> Vec3R {
+        self.albedo.value(hit)
+    }
+}
+
+impl Dieletric {
+    /// Bounce at the hero wavelength, without dropping the others.
+    fn scatter(&self, ray: &Ray, hit: &Hit, rng: &mut dyn RngCore) -> Bounce {
+        let tint = self.tint(ray, hit);
+        let ggx = Ggx::from_roughness(self.roughness);
+        if !ggx.is_smooth() {
+            let frame = Frame::new(&hit.normal);
+            let wo = frame.to_local(&-ray.direction.vec());
+            return match self.bounce_rough(&ggx, &wo, self.eta(ray, hit), rng) {
+                Some((wi, weight, pdf)) if pdf > 0.0 => Bounce {
+                    ray: Ray::with_color(
+                        hit.point,
+                        frame.to_world(&wi).unit(),
+                        ray.color * tint * weight,
+                    ),
+                    pdf: Some(pdf),
+                },
+                _ => Bounce::specular(Ray::with_color(hit.point, hit.normal, Vec3R::default())),
+            };
+        }
+        let etai_over_etat = 1.0 / self.eta(ray, hit);
         let cos_theta = (-ray.direction.vec()).dot(hit.normal.vec());
         let cos_theta_min = cos_theta.min(1.0);
         let sin_theta = (1.0 - cos_theta_min * cos_theta_min).sqrt();
         if etai_over_etat * sin_theta > 1.0
             || Dieletric::reflection_probability(cos_theta_min, etai_over_etat)
-                > thread_rng().gen_range(0.0, 1.0)
+                > rng.gen_range(0.0, 1.0)
         {
             // reflect
             let reflected = Metal::reflect(&ray.direction, &hit.normal);
-            Ray::with_color(hit.point, reflected.unit(), ray.color * self.albedo)
+            Bounce::specular(Ray::with_color(
+                hit.point,
+                reflected.unit(),
+                ray.color * tint,
+            ))
         } else {
             // refract
             let refracted =
                 Dieletric::refract(&ray.direction, &hit.normal, etai_over_etat, cos_theta).unit();
-            Ray::with_color(hit.point, refracted, ray.color * self.albedo)
+            Bounce::specular(Ray::with_color(hit.point, refracted, ray.color * tint))
         }
     }
 }
+
+// ------- EMISSIVE -------
+
+/// Light source, emits `color * intensity` from its front face (or both faces
+/// when `two_sided`) and absorbs everything it receives.
+#[derive(Deserialize, Debug)]
+pub struct Emissive {
+    pub color: Vec3R,
+    #[serde(default = "Emissive::default_intensity")]
+    pub intensity: Real,
+    #[serde(default)]
+    pub two_sided: bool,
+}
+
+impl Emissive {
+    fn default_intensity() -> Real {
+        1.0
+    }
+}
+
+impl Material for Emissive {
+    fn bounce(&self, _ray: &Ray, hit: &Hit, _rng: &mut dyn RngCore) -> Bounce {
+        Bounce::specular(Ray::with_color(hit.point, hit.normal, Vec3R::default()))
+    }
+
+    fn emitted(&self, ray: &Ray, hit: &Hit) -> Vec3R {
+        if hit.is_front_face || self.two_sided {
+            ray.color_of(&self.color) * self.intensity
+        } else {
+            Vec3R::default()
+        }
+    }
+
+    fn is_light(&self) -> bool {
+        self.intensity > 0.0 && self.color.max_component() > 0.0
+    }
+
+    fn albedo(&self, _ray: &Ray, _hit: &Hit) -> Vec3R {
+        self.color
+    }
+}
+
+// ------- INTERFACE -------
+
+/// Invisible surface, the boundary of the medium inside an object.
+#[derive(Deserialize, Debug)]
+pub struct Interface {}
+
+impl Material for Interface {
+    fn bounce(&self, ray: &Ray, hit: &Hit, _rng: &mut dyn RngCore) -> Bounce {
+        Bounce::specular(Ray::with_color(hit.point, ray.direction, ray.color))
+    }
+
+    fn is_interface(&self) -> bool {
+        true
+    }
+}
+
+// ------- PRINCIPLED -------
+
+/// Disney-style material that covers plastic, metal and glass, loosely following
+/// Burley 2012, "Physically Based Shading at Disney". The layers are weighted by
+/// what the layers above let through, so it never reflects more than it receives.
+/// All the parameters except `ior` go from 0 to 1.
+#[derive(Deserialize, Debug)]
+pub struct Principled {
+    pub base_color: Albedo,
+    #[serde(default)]
+    pub metallic: Real,
+    #[serde(default = "Principled::default_half")]
+    pub roughness: Real,
+    /// reflectance of the non metallic part, 0.5 is the usual 4% at normal incidence
+    #[serde(default = "Principled::default_half")]
+    pub specular: Real,
+    /// tints the non metallic reflection towards the base color
+    #[serde(default)]
+    pub specular_tint: Real,
+    /// strength of a second, white, specular layer on top
+    #[serde(default)]
+    pub clearcoat: Real,
+    #[serde(default = "Principled::default_one")]
+    pub clearcoat_gloss: Real,
+    /// soft reflection at grazing angles, like on cloth
+    #[serde(default)]
+    pub sheen: Real,
+    #[serde(default = "Principled::default_half")]
+    pub sheen_tint: Real,
+    /// fraction of the non metallic part refracted inside, as smooth glass tinted by the base color
+    #[serde(default)]
+    pub transmission: Real,
+    #[serde(default = "Principled::default_ior")]
+    pub ior: Real,
+}
+
+/// The principled lobes at a hit, for a given outgoing direction.
+struct PrincipledLobes {
+    base: Vec3R,
+    sheen: Vec3R,
+    /// specular reflectance at normal incidence
+    f0: Vec3R,
+    /// reflectance at normal incidence of the non metallic part
+    dielectric_f0: Real,
+    ggx: Ggx,
+    coat: Gtr1,
+    /// fraction of the light going through the clear coat
+    coat_transmittance: Real,
+    /// fraction of the light going through the specular layer, towards the outgoing direction
+    base_transmittance: Real,
+    /// probabilities of sampling specular, clear coat, diffuse and transmission
+    probabilities: [Real; 4],
+}
+
+impl Principled {
+    #[cfg(test)]
+    pub fn new(base_color: Vec3R) -> Principled {
+        Principled {
+            base_color: base_color.into(),
+            metallic: 0.0,
+            roughness: 0.5,
+            specular: 0.5,
+            specular_tint: 0.0,
+            clearcoat: 0.0,
+            clearcoat_gloss: 1.0,
+            sheen: 0.0,
+            sheen_tint: 0.5,
+            transmission: 0.0,
+            ior: 1.5,
+        }
+    }
+
+    fn default_half() -> Real {
+        0.5
+    }
+
+    fn default_one() -> Real {
+        1.0
+    }
+
+    fn default_ior() -> Real {
+        1.5
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let parameters = [
+            self.metallic,
+            self.roughness,
+            self.specular,
+            self.specular_tint,
+            self.clearcoat,
+            self.clearcoat_gloss,
+            self.sheen,
+            self.sheen_tint,
+            self.transmission,
+        ];
+        if parameters.iter().any(|p| !(*p >= 0.0 && *p <= 1.0)) {
+            Err("principled parameters must be between 0 and 1".to_string())
+        } else if self.ior.is_nan() || self.ior <= 0.0 {
+            Err("principled ior must be positive".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn lobes(&self, ray: &Ray, hit: &Hit, cos_o: Real) -> PrincipledLobes {
+        let white = Vec3R::new(1.0, 1.0, 1.0);
+        let base = self.base_color.value(hit);
+        let luminance = base.luminance();
+        let tint = if luminance > 0.0 {
+            ray.color_of(&(base / luminance))
+        } else {
+            white
+        };
+        let base = ray.color_of(&base);
+        let dielectric_f0 = white.lerp(&tint, self.specular_tint) * (0.08 * self.specular);
+        let weight = schlick_weight(cos_o);
+
+        let coat_reflectance = self.clearcoat * (0.04 + 0.96 * weight);
+        let coat_transmittance = 1.0 - coat_reflectance;
+        let f0 = dielectric_f0.lerp(&base, self.metallic);
+        let dielectric_f0 = dielectric_f0.max_component();
+        let base_transmittance = 1.0 - (dielectric_f0 + (1.0 - dielectric_f0) * weight);
+        let dielectric = coat_transmittance * (1.0 - self.metallic) * base_transmittance;
+        let weights = [
+            coat_transmittance * schlick(&f0, cos_o).luminance(),
+            coat_reflectance,
+            dielectric * (1.0 - self.transmission),
+            dielectric * self.transmission,
+        ];
+        let total: Real = weights.iter().sum();
+        let probabilities = if total > 0.0 {
+            [
+                weights[0] / total,
+                weights[1] / total,
+                weights[2] / total,
+                weights[3] / total,
+            ]
+        } else {
+            [1.0, 0.0, 0.0, 0.0]
+        };
+
+        PrincipledLobes {
+            base,
+            sheen: white.lerp(&tint, self.sheen_tint),
+            f0,
+            dielectric_f0,
+            ggx: Ggx::from_roughness(self.roughness),
+            coat: Gtr1 {
+                alpha: 0.1 + (0.001 - 0.1) * self.clearcoat_gloss,
+            },
+            coat_transmittance,
+            base_transmittance,
+            probabilities,
+        }
+    }
+
+    /// Bsdf (without the cosine) and pdf of the non specular lobes, in local space.
+    fn eval_local(&self, lobes: &PrincipledLobes, wo: &Vec3R, wi: &Vec3R) -> (Vec3R, Real) {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return (Vec3R::default(), 0.0);
+        }
+        let h = (wo + wi).normalize();
+        let cos_d = wi.dot(&h);
+        let weight = schlick_weight(cos_d);
+
+        let specular = schlick(&lobes.f0, cos_d)
+            * (lobes.ggx.d(&h) * lobes.ggx.g(wo, wi) / (4.0 * wo.z * wi.z));
+        // the sheen takes the place of the diffuse light at grazing angles
+        let diffuse = (lobes.base * (1.0 - self.sheen * weight)
+            + lobes.sheen * (self.sheen * weight))
+            * ((1.0 - self.metallic)
+                * (1.0 - self.transmission)
+                * lobes.base_transmittance
+                * (1.0
+                    - (lobes.dielectric_f0 + (1.0 - lobes.dielectric_f0) * schlick_weight(wi.z)))
+                / PI);
+        let coat = self.clearcoat
+            * (0.04 + 0.96 * weight)
+            * lobes.coat.d(&h)
+            * Ggx { alpha: 0.25 }.g(wo, wi)
+            / (4.0 * wo.z * wi.z);
+        let bsdf = lobes.coat_transmittance * (specular + diffuse) + Vec3R::new(coat, coat, coat);
+
+        let [specular_p, coat_p, diffuse_p, _] = lobes.probabilities;
+        let pdf = specular_p * lobes.ggx.pdf_visible(wo, &h) / (4.0 * cos_d)
+            + coat_p * lobes.coat.d(&h) * h.z / (4.0 * cos_d)
+            + diffuse_p * wi.z / PI;
+        (bsdf, pdf)
+    }
+
+    /// Refraction into the material, the specular reflection is left to the specular lobe.
+    fn transmit(&self, ray: &Ray, hit: &Hit, lobes: &PrincipledLobes, cos_o: Real) -> Bounce {
+        let probability = lobes.probabilities[3];
+        let refracted = Dieletric::refract(&ray.direction, &hit.normal, 1.0 / self.ior, cos_o);
+        let weight = lobes.coat_transmittance
+            * (1.0 - self.metallic)
+            * self.transmission
+            * lobes.base_transmittance
+            / probability;
+        Bounce::specular(Ray::with_color(
+            hit.point,
+            refracted.unit(),
+            ray.color * lobes.base * weight,
+        ))
+    }
+
+    /// Inside the material it behaves like a smooth dielectric.
+    fn bounce_inside(&self, ray: &Ray, hit: &Hit, rng: &mut dyn RngCore) -> Bounce {
+        let cos_o = (-ray.direction.vec()).dot(hit.normal.vec()).min(1.0);
+        let direction = if fresnel_dielectric(cos_o, 1.0 / self.ior) > rng.gen_range(0.0, 1.0) {
+            Metal::reflect(&ray.direction, &hit.normal)
+        } else {
+            Dieletric::refract(&ray.direction, &hit.normal, self.ior, cos_o)
+        };
+        Bounce::specular(Ray::with_color(hit.point, direction.unit(), ray.color))
+    }
+}
+
+/// Cosine distributed direction around +z.
+fn cosine_hemisphere(u: Vec2R) -> Vec3R {
+    let r = u.x.sqrt();
+    let phi = 2.0 * PI * u.y;
+    Vec3R::new(r * phi.cos(), r * phi.sin(), (1.0 - u.x).max(0.0).sqrt())
+}
+
+impl Material for Principled {
+    fn bounce(&self, ray: &Ray, hit: &Hit, rng: &mut dyn RngCore) -> Bounce {
+        if !hit.is_front_face && self.transmission > 0.0 {
+            return self.bounce_inside(ray, hit, rng);
+        }
+        let absorbed = Bounce::specular(Ray::with_color(hit.point, hit.normal, Vec3R::default()));
+        let frame = Frame::new(&hit.normal);
+        let wo = frame.to_local(&-ray.direction.vec());
+        if wo.z <= 0.0 {
+            return absorbed;
+        }
+        let lobes = self.lobes(ray, hit, wo.z);
+        let u = Vec2R::new(rng.gen(), rng.gen());
+        let choice: Real = rng.gen();
+        let [specular_p, coat_p, diffuse_p, _] = lobes.probabilities;
+        let wi = if choice < specular_p {
+            reflect(&wo, &lobes.ggx.sample_visible(&wo, u))
+        } else if choice < specular_p + coat_p {
+            reflect(&wo, &lobes.coat.sample(u))
+        } else if choice < specular_p + coat_p + diffuse_p {
+            cosine_hemisphere(u)
+        } else {
+            return self.transmit(ray, hit, &lobes, wo.z);
+        };
+        let (bsdf, pdf) = self.eval_local(&lobes, &wo, &wi);
+        if pdf <= 0.0 {
+            return absorbed;
+        }
+        Bounce {
+            ray: Ray::with_color(
+                hit.point,
+                frame.to_world(&wi).unit(),
+                ray.color * bsdf * (wi.z / pdf),
+            ),
+            pdf: Some(pdf),
+        }
+    }
+
+    fn eval(&self, ray: &Ray, hit: &Hit, direction: &Unit3R) -> (Vec3R, Real) {
+        if !hit.is_front_face && self.transmission > 0.0 {
+            return (Vec3R::default(), 0.0);
+        }
+        let frame = Frame::new(&hit.normal);
+        let wo = frame.to_local(&-ray.direction.vec());
+        let wi = frame.to_local(direction.vec());
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return (Vec3R::default(), 0.0);
+        }
+        let (bsdf, pdf) = self.eval_local(&self.lobes(ray, hit, wo.z), &wo, &wi);
+        (bsdf * wi.z, pdf)
+    }
+
+    fn albedo(&self, _ray: &Ray, hit: &Hit) -> Vec3R {
+        self.base_color.value(hit)
+    }
+}
+
+/// Bounces rays off the material checking that the sampled directions have the
+/// same bsdf and pdf when evaluated, returns the average color of the bounces.
+#[cfg(test)]
+fn check_sampling_matches_eval(material: &dyn Material, ray: &Ray, hit: &Hit) -> Vec3R {
+    let samples = 10000;
+    let mut total = Vec3R::default();
+    for sample in 0..samples {
+        let mut rng = super::random::SampleRng::new(0, 0, 0, sample);
+        let bounce = material.bounce(ray, hit, &mut rng);
+        let color = bounce.ray.color;
+        assert!(color.min_component() >= 0.0);
+        if let Some(pdf) = bounce.pdf {
+            let (bsdf, eval_pdf) = material.eval(ray, hit, &bounce.ray.direction);
+            assert!((eval_pdf - pdf).abs() <= 1e-6 * pdf, "{} {}", eval_pdf, pdf);
+            assert!((bsdf / pdf - color).abs().max_component() < 1e-6);
+        }
+        total += color;
+    }
+    total / samples as Real
+}
+
+#[test]
+fn test_principled_furnace() {
+    let hit = test_hit(Point3R::default(), true);
+    let white = Vec3R::new(1.0, 1.0, 1.0);
+    let mut materials = Vec::new();
+    for &roughness in &[0.05, 0.5, 1.0] {
+        for &metallic in &[0.0, 0.5, 1.0] {
+            let mut plastic = Principled::new(white);
+            plastic.roughness = roughness;
+            plastic.metallic = metallic;
+            plastic.specular = 1.0;
+            materials.push(plastic);
+        }
+        let mut coated = Principled::new(white);
+        coated.roughness = roughness;
+        coated.clearcoat = 1.0;
+        coated.clearcoat_gloss = 0.3;
+        coated.sheen = 1.0;
+        materials.push(coated);
+        let mut glass = Principled::new(white);
+        glass.roughness = roughness;
+        glass.transmission = 1.0;
+        glass.specular_tint = 1.0;
+        materials.push(glass);
+    }
+
+    for material in &materials {
+        for &cos in &[1.0, 0.7, 0.3, 0.05] {
+            let sin: Real = (1.0 - cos * cos as Real).sqrt();
+            let ray = Ray::new(
+                Point3R::new(-sin, cos, 0.0),
+                Vec3R::new(sin, -cos, 0.0).unit(),
+            );
+            // a white material in a white furnace never gets brighter than the furnace
+            let albedo = check_sampling_matches_eval(material, &ray, &hit);
+            assert!(
+                albedo.max_component() < 1.02,
+                "{:?} {} {:?}",
+                material,
+                cos,
+                albedo
+            );
+            if material.roughness < 0.1 && material.metallic == 1.0 {
+                // almost nothing is lost by smooth metals
+                assert!(
+                    albedo.min_component() > 0.9,
+                    "{:?} {} {:?}",
+                    material,
+                    cos,
+                    albedo
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_conductor() {
+    let hit = test_hit(Point3R::default(), true);
+    let gold: DesMaterial =
+        serde_json::from_str(r#"{"type": "conductor", "metal": "gold", "roughness": 0.3}"#)
+            .unwrap();
+    let gold = match gold {
+        DesMaterial::Conductor(gold) => gold,
+        _ => panic!("expected a conductor"),
+    };
+    // gold reflects more red than blue, at grazing angles everything is reflected
+    let ray = Ray::new(Point3R::default(), Unit3R::UP);
+    let f0 = gold.fresnel(&ray, 1.0);
+    assert!(f0.x > 0.9 && f0.x > f0.y && f0.y > f0.z);
+    assert!(gold.fresnel(&ray, 0.0).min_component() > 0.999);
+    let measured: DesMaterial = serde_json::from_str(
+        r#"{"type": "conductor", "eta": {"x": 1, "y": 1, "z": 1}, "k": {"x": 0, "y": 0, "z": 0}}"#,
+    )
+    .unwrap();
+    assert!(matches!(measured, DesMaterial::Conductor(_)));
+    assert!(Conductor::new(
+        ConductorIor::Preset {
+            metal: MetalPreset::Gold
+        },
+        1.5
+    )
+    .validate()
+    .is_err());
+
+    // a perfect reflector loses energy only to masking, sampling and evaluation agree
+    let white = ConductorIor::Measured {
+        eta: Vec3R::new(1.0, 1.0, 1.0),
+        k: Vec3R::new(1e6, 1e6, 1e6),
+    };
+    for &roughness in &[0.0, 0.1, 0.5, 1.0] {
+        let material = Conductor::new(white, roughness);
+        for &cos in &[1.0, 0.5, 0.1] {
+            let sin: Real = (1.0 - cos * cos as Real).sqrt();
+            let ray = Ray::new(
+                Point3R::new(-sin, cos, 0.0),
+                Vec3R::new(sin, -cos, 0.0).unit(),
+            );
+            let albedo = check_sampling_matches_eval(&material, &ray, &hit).x;
+            assert!(albedo <= 1.0 + 1e-9);
+            if roughness <= 0.1 {
+                assert!(albedo > 0.95, "{} {} {}", roughness, cos, albedo);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_dieletric() {
+    let mut rng = super::random::SampleRng::new(0, 0, 0, 0);
+    // with the same refraction index on both sides the light goes straight through,
+    // inside it's absorbed proportionally to the traveled distance
+    let mut glass = Dieletric::new(Vec3R::new(1.0, 1.0, 1.0), 1.0);
+    glass.absorption_color = Some(Vec3R::new(0.5, 0.8, 1.0));
+    glass.absorption_distance = 2.0;
+    let ray = Ray::new(
+        Point3R::new(0.0, 4.0, 0.0),
+        Vec3R::new(0.0, -1.0, 0.0).unit(),
+    );
+    let entering = glass
+        .bounce(&ray, &test_hit(Point3R::default(), true), &mut rng)
+        .ray;
+    assert_eq!(entering.color, Vec3R::new(1.0, 1.0, 1.0));
+    let exiting = glass
+        .bounce(&ray, &test_hit(Point3R::default(), false), &mut rng)
+        .ray;
+    assert!(
+        (exiting.color - Vec3R::new(0.25, 0.64, 1.0))
+            .abs()
+            .max_component()
+            < 1e-9
+    );
+    assert!(glass.validate().is_ok());
+    glass.absorption_color = Some(Vec3R::new(0.0, 0.5, 0.5));
+    assert!(glass.validate().is_err());
+
+    // rough glass doesn't create energy, sampling and evaluation agree
+    for &roughness in &[0.05, 0.3, 1.0] {
+        let mut glass = Dieletric::new(Vec3R::new(1.0, 1.0, 1.0), 1.5);
+        glass.roughness = roughness;
+        for &is_front_face in &[true, false] {
+            for &cos in &[1.0, 0.5, 0.1] {
+                let sin: Real = (1.0 - cos * cos as Real).sqrt();
+                let ray = Ray::new(
+                    Point3R::new(-sin, cos, 0.0),
+                    Vec3R::new(sin, -cos, 0.0).unit(),
+                );
+                let hit = test_hit(Point3R::default(), is_front_face);
+                let albedo = check_sampling_matches_eval(&glass, &ray, &hit).x;
+                assert!(albedo <= 1.0 + 1e-9);
+                if roughness < 0.1 {
+                    assert!(albedo > 0.95, "{} {} {}", roughness, cos, albedo);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_dispersion() {
+    use super::spectrum::{Wavelengths, LAMBDA_MAX, LAMBDA_MIN};
+    let mut rng = super::random::SampleRng::new(0, 0, 0, 0);
+    let hit = test_hit(Point3R::default(), true);
+    let mut prism = Dieletric::new(Vec3R::new(1.0, 1.0, 1.0), 1.5);
+    prism.dispersion = Some(Dispersion::Cauchy { a: 1.5, b: 0.02 });
+    let direction = Vec3R::new(1.0, -1.0, 0.0).unit();
+    let mut refracted = |lambda: Real| {
+        let mut ray = Ray::new(Point3R::new(-1.0, 1.0, 0.0), direction);
+        ray.wavelengths = Some(Wavelengths::sample(
+            (lambda - LAMBDA_MIN) / (LAMBDA_MAX - LAMBDA_MIN),
+        ));
+        // keep sampling until the ray is refracted
+        loop {
+            let bounce = prism.bounce(&ray, &hit, &mut rng).ray;
+            if bounce.direction.y() < 0.0 {
+                // only the hero wavelength goes on
+                assert!(bounce.wavelengths.unwrap().is_single());
+                assert!(bounce.color.x > 0.0 && bounce.color.x == bounce.color.z);
+                return bounce.direction;
+            }
+        }
+    };
+    // blue bends more towards the normal than red
+    assert!(refracted(450.0).x() < refracted(650.0).x());
+
+    // without wavelengths the fixed refraction index is used
+    let ray = Ray::new(Point3R::new(-1.0, 1.0, 0.0), direction);
+    assert_eq!(prism.ior(&ray), 1.5);
+}
@@ -0,0 +1,264 @@
+use super::defs::{Real, PI};
+use super::material::{Bounce, Material};
+use super::primitives::*;
+use rand::{Rng, RngCore};
+use serde::Deserialize;
+
+/*
+    A medium fills the inside of a closed geometry, or the space around all the
+    objects. Rays traveling through it can be absorbed or scattered in a new
+    direction before reaching the next surface. Media don't nest: leaving the
+    inside of an object always gets back to the medium around all the objects.
+*/
+
+/// Homogeneous participating medium, like fog, smoke or the inside of wax.
+/// The coefficients are per unit of distance, for each color channel.
+#[derive(Deserialize, Debug)]
+pub struct Medium {
+    /// fraction of light absorbed
+    #[serde(default)]
+    pub absorption: Vec3R,
+    /// fraction of light scattered in another direction
+    #[serde(default)]
+    pub scattering: Vec3R,
+    /// Henyey-Greenstein asymmetry in (-1, 1), positive scatters forward,
+    /// negative backward and zero in every direction alike
+    #[serde(default)]
+    pub anisotropy: Real,
+    /// multiplies both the coefficients
+    #[serde(default = "Medium::default_density")]
+    pub density: Real,
+}
+
+/// How far a ray travels through a medium.
+pub enum Flight {
+    /// the ray scatters `distance` away from its origin
+    Scatter { distance: Real, weight: Vec3R },
+    /// the ray reaches the end of the medium
+    Pass { weight: Vec3R },
+}
+
+impl Medium {
+    #[cfg(test)]
+    pub fn new(absorption: Vec3R, scattering: Vec3R, anisotropy: Real) -> Medium {
+        Medium {
+            absorption,
+            scattering,
+            anisotropy,
+            density: 1.0,
+        }
+    }
+
+    fn default_density() -> Real {
+        1.0
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.absorption.min_component() >= 0.0 && self.scattering.min_component() >= 0.0) {
+            return Err("absorption and scattering can't be negative".to_string());
+        }
+        if self.density.is_nan() || self.density < 0.0 {
+            return Err("density can't be negative".to_string());
+        }
+        if self.anisotropy.is_nan() || self.anisotropy.abs() >= 1.0 {
+            return Err("anisotropy must be in (-1, 1)".to_string());
+        }
+        Ok(())
+    }
+
+    fn scattering(&self, ray: &Ray) -> Vec3R {
+        ray.color_of(&self.scattering) * self.density
+    }
+
+    fn extinction(&self, ray: &Ray) -> Vec3R {
+        ray.color_of(&(self.absorption + self.scattering)) * self.density
+    }
+
+    /// Fraction of the light that goes straight through `distance` (Beer-Lambert law).
+    pub fn transmittance(&self, ray: &Ray, distance: Real) -> Vec3R {
+        let extinction = self.extinction(ray);
+        let channel = |sigma: Real| {
+            if sigma > 0.0 {
+                (-sigma * distance).exp()
+            } else {
+                1.0
+            }
+        };
+        Vec3R::new(
+            channel(extinction.x),
+            channel(extinction.y),
+            channel(extinction.z),
+        )
+    }
+
+    /// Samples the distance of the first interaction of the ray, if it comes before
+    /// `max_distance`. The weight multiplies the color of the ray and makes the
+    /// estimate unbiased for every channel, though only one channel is sampled.
+    pub fn sample(&self, ray: &Ray, max_distance: Real, rng: &mut dyn RngCore) -> Flight {
+        let extinction = self.extinction(ray);
+        let sigma = extinction[rng.gen_range(0, 3)];
+        let distance = if sigma > 0.0 {
+            -(1.0 - rng.gen::<Real>()).ln() / sigma
+        } else {
+            Real::INFINITY
+        };
+        if distance < max_distance {
+            let transmittance = self.transmittance(ray, distance);
+            // the channels are chosen with the same probability
+            let pdf = average(&(extinction * transmittance));
+            Flight::Scatter {
+                distance,
+                weight: if pdf > 0.0 {
+                    self.scattering(ray) * transmittance / pdf
+                } else {
+                    Vec3R::default()
+                },
+            }
+        } else {
+            let transmittance = self.transmittance(ray, max_distance);
+            let pdf = average(&transmittance);
+            Flight::Pass {
+                weight: if pdf > 0.0 {
+                    transmittance / pdf
+                } else {
+                    Vec3R::default()
+                },
+            }
+        }
+    }
+
+    /// Henyey-Greenstein phase function, density of scattering towards `direction`.
+    pub fn phase(&self, ray: &Ray, direction: &Unit3R) -> Real {
+        let g = self.anisotropy;
+        let cosine = ray.direction.vec().dot(direction.vec());
+        let k = 1.0 + g * g - 2.0 * g * cosine;
+        (1.0 - g * g) / (4.0 * PI * k * k.sqrt())
+    }
+
+    /// Direction with density `phase`.
+    pub fn sample_phase(&self, ray: &Ray, u: Vec2R) -> Unit3R {
+        let g = self.anisotropy;
+        let cosine = if g.abs() < 1e-3 {
+            1.0 - 2.0 * u.x
+        } else {
+            let k = (1.0 - g * g) / (1.0 - g + 2.0 * g * u.x);
+            (1.0 + g * g - k * k) / (2.0 * g)
+        }
+        .clamp(-1.0, 1.0);
+        let sine = (1.0 - cosine * cosine).sqrt();
+        let phi = 2.0 * PI * u.y;
+        let (s, t) = ray.direction.basis();
+        Unit3R::normalized(
+            cosine * ray.direction.vec() + sine * phi.cos() * s + sine * phi.sin() * t,
+        )
+    }
+}
+
+fn average(v: &Vec3R) -> Real {
+    (v.x + v.y + v.z) / 3.0
+}
+
+/// Point where a ray scatters inside a medium, to be shaded with the phase function.
+pub fn scattering_hit(ray: &Ray, distance: Real) -> Hit {
+    let point = ray.at(distance);
+    Hit {
+        point,
+        local_point: point,
+        normal: -ray.direction,
+        geometric_normal: -ray.direction,
+        is_front_face: true,
+        barycentric: Vec2R::default(),
+        uv: Vec2R::default(),
+    }
+}
+
+/// Where a ray scatters the medium takes the place of the material,
+/// with the phase function as bsdf, there is no surface and no cosine.
+impl Material for Medium {
+    fn bounce(&self, ray: &Ray, hit: &Hit, rng: &mut dyn RngCore) -> Bounce {
+        let direction = self.sample_phase(ray, Vec2R::new(rng.gen(), rng.gen()));
+        let mut bounced = Ray::with_color(hit.point, direction, ray.color);
+        bounced.wavelengths = ray.wavelengths;
+        Bounce {
+            pdf: Some(self.phase(ray, &direction)),
+            ray: bounced,
+        }
+    }
+
+    fn eval(&self, ray: &Ray, _hit: &Hit, direction: &Unit3R) -> (Vec3R, Real) {
+        let phase = self.phase(ray, direction);
+        (Vec3R::new(phase, phase, phase), phase)
+    }
+}
+
+#[test]
+fn test_phase() {
+    let ray = Ray::new(
+        Point3R::default(),
+        Unit3R::normalized(Vec3R::new(0.0, 0.0, 1.0)),
+    );
+    let mut rng = super::random::SampleRng::new(0, 0, 0, 0);
+    for &g in &[0.0, 0.5, -0.7, 0.9] {
+        let medium = Medium::new(Vec3R::default(), Vec3R::new(1.0, 1.0, 1.0), g);
+        // integrates to 1 over the sphere
+        let n = 1000;
+        let mut integral = 0.0;
+        for i in 0..n {
+            let theta = PI * (i as Real + 0.5) / n as Real;
+            let direction = Unit3R::normalized(Vec3R::new(theta.sin(), 0.0, theta.cos()));
+            integral += medium.phase(&ray, &direction) * 2.0 * PI * theta.sin() * PI / n as Real;
+        }
+        assert!((integral - 1.0).abs() < 0.01, "{} {}", g, integral);
+        // the mean cosine of the sampled directions is the anisotropy
+        let samples = 20000;
+        let mut mean = 0.0;
+        for _ in 0..samples {
+            let direction = medium.sample_phase(&ray, Vec2R::new(rng.gen(), rng.gen()));
+            assert!((direction.vec().length() - 1.0).abs() < 1e-9);
+            mean += direction.z() / samples as Real;
+        }
+        assert!((mean - g).abs() < 0.02, "{} {}", g, mean);
+    }
+    assert!(Medium::new(Vec3R::default(), Vec3R::default(), 1.0)
+        .validate()
+        .is_err());
+}
+
+#[test]
+fn test_medium_flight() {
+    // the weights average to the transmittance and to the single scattering albedo
+    let medium = Medium::new(Vec3R::new(0.5, 0.1, 0.0), Vec3R::new(0.5, 1.0, 2.0), 0.0);
+    let ray = Ray::new(
+        Point3R::default(),
+        Unit3R::normalized(Vec3R::new(1.0, 0.0, 0.0)),
+    );
+    let length = 0.8;
+    let samples = 100000;
+    let mut passed = Vec3R::default();
+    let mut scattered = Vec3R::default();
+    let mut rng = super::random::SampleRng::new(0, 0, 0, 0);
+    for _ in 0..samples {
+        match medium.sample(&ray, length, &mut rng) {
+            Flight::Scatter { distance, weight } => {
+                assert!(distance >= 0.0 && distance < length);
+                scattered += weight / samples as Real;
+            }
+            Flight::Pass { weight } => passed += weight / samples as Real,
+        }
+    }
+    let transmittance = medium.transmittance(&ray, length);
+    let albedo = Vec3R::new(0.5, 1.0 / 1.1, 1.0);
+    let expected = albedo * (Vec3R::new(1.0, 1.0, 1.0) - transmittance);
+    assert!(
+        (passed - transmittance).abs().max_component() < 0.02,
+        "{:?} {:?}",
+        passed,
+        transmittance
+    );
+    assert!(
+        (scattered - expected).abs().max_component() < 0.02,
+        "{:?} {:?}",
+        scattered,
+        expected
+    );
+}
@@ -0,0 +1,630 @@
+use super::bvh::Bvh;
+use super::defs::Real;
+use super::geometry::Geometry;
+use super::primitives::*;
+use rand::{Rng, RngCore};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/*
+    Watertight ray/triangle intersection (Woop, Benthin, Wald 2013).
+    The ray is permuted so that its largest direction component is z and then sheared
+    so that it points along +z starting from the origin. In that space the test is a
+    2D edge test in the xy plane, and edges shared by two triangles give exactly the
+    same (but opposite) edge functions, so rays never slip between adjacent triangles.
+*/
+struct ShearedRay {
+    origin: Point3R,
+    kx: usize,
+    ky: usize,
+    kz: usize,
+    sx: Real,
+    sy: Real,
+    sz: Real,
+}
+
+impl ShearedRay {
+    fn new(ray: &Ray) -> ShearedRay {
+        let direction = ray.direction.vec();
+        let abs_direction = direction.abs();
+        let kz = if abs_direction.x >= abs_direction.y && abs_direction.x >= abs_direction.z {
+            Vec3R::X
+        } else if abs_direction.y >= abs_direction.z {
+            Vec3R::Y
+        } else {
+            Vec3R::Z
+        };
+        let mut kx = (kz + 1) % 3;
+        let mut ky = (kx + 1) % 3;
+        // keep the winding of the triangle
+        if direction[kz] < 0.0 {
+            std::mem::swap(&mut kx, &mut ky);
+        }
+        ShearedRay {
+            origin: ray.origin,
+            kx,
+            ky,
+            kz,
+            sx: direction[kx] / direction[kz],
+            sy: direction[ky] / direction[kz],
+            sz: 1.0 / direction[kz],
+        }
+    }
+
+    /// Returns the time of the hit and the barycentric weights of `v1` and `v2`.
+    fn intersect(
+        &self,
+        v0: &Point3R,
+        v1: &Point3R,
+        v2: &Point3R,
+        t_min: Real,
+        t_max: Real,
+    ) -> Option<(Real, Vec2R)> {
+        let a = v0 - self.origin;
+        let b = v1 - self.origin;
+        let c = v2 - self.origin;
+
+        let ax = a[self.kx] - self.sx * a[self.kz];
+        let ay = a[self.ky] - self.sy * a[self.kz];
+        let bx = b[self.kx] - self.sx * b[self.kz];
+        let by = b[self.ky] - self.sy * b[self.kz];
+        let cx = c[self.kx] - self.sx * c[self.kz];
+        let cy = c[self.ky] - self.sy * c[self.kz];
+
+        // edge functions, the hit is inside when they all have the same sign
+        let u = cx * by - cy * bx;
+        let v = ax * cy - ay * cx;
+        let w = bx * ay - by * ax;
+        if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+            return None;
+        }
+        let det = u + v + w;
+        if det == 0.0 {
+            return None;
+        }
+
+        let az = self.sz * a[self.kz];
+        let bz = self.sz * b[self.kz];
+        let cz = self.sz * c[self.kz];
+        let t = (u * az + v * bz + w * cz) / det;
+        if t > t_min && t < t_max {
+            Some((t, Vec2R::new(v / det, w / det)))
+        } else {
+            None
+        }
+    }
+}
+
+/// Random point uniformly distributed on the triangle.
+fn sample_triangle(v0: &Point3R, v1: &Point3R, v2: &Point3R, rng: &mut dyn RngCore) -> Point3R {
+    let su = rng.gen::<Real>().sqrt();
+    let r = rng.gen::<Real>();
+    v0 * (1.0 - su) + v1 * (su * (1.0 - r)) + v2 * (su * r)
+}
+
+fn triangle_area(v0: &Point3R, v1: &Point3R, v2: &Point3R) -> Real {
+    (v1 - v0).cross(&(v2 - v0)).length() * 0.5
+}
+
+/// Builds the hit of a triangle, `barycentric` holds the weights of `v1` and `v2`.
+/// When vertex normals are given the shading normal is interpolated between them
+/// and they also decide which side is the front face, like in most modelling tools.
+/// Without texture coordinates the barycentric coordinates are used.
+fn triangle_hit(
+    ray: &Ray,
+    time: Real,
+    vertices: [&Point3R; 3],
+    normals: Option<[&Normal3; 3]>,
+    uvs: Option<[&Vec2R; 3]>,
+    barycentric: Vec2R,
+) -> Hit {
+    let w0 = 1.0 - barycentric.x - barycentric.y;
+    let mut geometric_normal = (vertices[1] - vertices[0]).cross(&(vertices[2] - vertices[0]));
+    let outward_normal = if let Some(normals) = normals {
+        let shading_normal =
+            w0 * normals[0] + barycentric.x * normals[1] + barycentric.y * normals[2];
+        if shading_normal.dot(&geometric_normal) < 0.0 {
+            geometric_normal = -geometric_normal;
+        }
+        shading_normal
+    } else {
+        geometric_normal
+    };
+    let is_front_face = ray.direction.vec().dot(&geometric_normal) <= 0.0;
+    if !is_front_face {
+        geometric_normal = -geometric_normal;
+    }
+    let point = ray.at(time);
+    Hit {
+        point,
+        local_point: point,
+        normal: if is_front_face {
+            outward_normal.unit()
+        } else {
+            (-outward_normal).unit()
+        },
+        geometric_normal: geometric_normal.unit(),
+        is_front_face,
+        barycentric,
+        uv: match uvs {
+            Some(uvs) => w0 * uvs[0] + barycentric.x * uvs[1] + barycentric.y * uvs[2],
+            None => barycentric,
+        },
+    }
+}
+
+// ---- TRIANGLE ------
+
+/// Single triangle, the front face is the one where the vertices are counter-clockwise.
+#[derive(Deserialize, Debug)]
+pub struct Triangle {
+    pub vertices: [Point3R; 3],
+    #[serde(default)]
+    pub normals: Option<[Normal3; 3]>,
+    /// texture coordinates of the vertices
+    #[serde(default)]
+    pub uvs: Option<[Vec2R; 3]>,
+}
+
+impl Triangle {
+    #[cfg(test)]
+    pub fn new(v0: Point3R, v1: Point3R, v2: Point3R) -> Triangle {
+        Triangle {
+            vertices: [v0, v1, v2],
+            normals: None,
+            uvs: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_normals(vertices: [Point3R; 3], normals: [Normal3; 3]) -> Triangle {
+        Triangle {
+            vertices,
+            normals: Some(normals),
+            uvs: None,
+        }
+    }
+}
+
+impl Geometry for Triangle {
+    fn intersect(&self, ray: &Ray, t_min: Real, t_max: Real) -> Real {
+        let [v0, v1, v2] = &self.vertices;
+        match ShearedRay::new(ray).intersect(v0, v1, v2, t_min, t_max) {
+            Some((t, _)) => t,
+            None => Real::INFINITY,
+        }
+    }
+
+    fn hit(&self, ray: &Ray, time: Real) -> Hit {
+        let [v0, v1, v2] = &self.vertices;
+        let barycentric = ShearedRay::new(ray)
+            .intersect(v0, v1, v2, Real::NEG_INFINITY, Real::INFINITY)
+            .map(|(_, barycentric)| barycentric)
+            .unwrap_or_default();
+        let normals = self.normals.as_ref().map(|[n0, n1, n2]| [n0, n1, n2]);
+        let uvs = self.uvs.as_ref().map(|[uv0, uv1, uv2]| [uv0, uv1, uv2]);
+        triangle_hit(ray, time, [v0, v1, v2], normals, uvs, barycentric)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let [v0, v1, v2] = &self.vertices;
+        Aabb::new(*v0, *v1).grow(v2)
+    }
+
+    fn area(&self) -> Real {
+        let [v0, v1, v2] = &self.vertices;
+        triangle_area(v0, v1, v2)
+    }
+
+    fn sample_point(&self, rng: &mut dyn RngCore) -> Option<Point3R> {
+        let [v0, v1, v2] = &self.vertices;
+        Some(sample_triangle(v0, v1, v2, rng))
+    }
+}
+
+#[test]
+fn test_triangle_intersection() {
+    let triangle = Triangle::new(
+        Point3R::new(-1.0, -1.0, 2.0),
+        Point3R::new(1.0, -1.0, 2.0),
+        Point3R::new(-1.0, 1.0, 2.0),
+    );
+    let ray = Ray::new(
+        Point3R::new(-0.5, -0.5, -1.0),
+        Vec3R::new(0.0, 0.0, 1.0).unit(),
+    );
+    let t = triangle.intersect(&ray, 0.0, Real::INFINITY);
+    assert_eq!(t, 3.0);
+    assert_eq!(triangle.intersect(&ray, 0.0, 2.0), Real::INFINITY);
+    let hit = triangle.hit(&ray, t);
+    assert_eq!(hit.point, Point3R::new(-0.5, -0.5, 2.0));
+    assert_eq!(hit.barycentric, Vec2R::new(0.25, 0.25));
+    // vertices are clockwise when seen from the ray
+    assert!(!hit.is_front_face);
+    assert_eq!(*hit.normal.vec(), Vec3R::new(0.0, 0.0, -1.0));
+
+    // outside the hypotenuse
+    let ray = Ray::new(
+        Point3R::new(0.5, 0.5, -1.0),
+        Vec3R::new(0.0, 0.0, 1.0).unit(),
+    );
+    assert_eq!(
+        triangle.intersect(&ray, 0.0, Real::INFINITY),
+        Real::INFINITY
+    );
+
+    // a ray through a shared edge hits at least one of the two triangles
+    let other = Triangle::new(
+        Point3R::new(1.0, -1.0, 2.0),
+        Point3R::new(1.0, 1.0, 2.0),
+        Point3R::new(-1.0, 1.0, 2.0),
+    );
+    let ray = Ray::new(
+        Point3R::new(-1.0, -1.0, -1.0),
+        Vec3R::new(0.3, 0.3, 1.0).unit(),
+    );
+    assert!(
+        triangle.intersect(&ray, 0.0, Real::INFINITY) < Real::INFINITY
+            || other.intersect(&ray, 0.0, Real::INFINITY) < Real::INFINITY
+    );
+
+    // smooth shading
+    let triangle = Triangle::with_normals(
+        triangle.vertices,
+        [
+            Vec3R::new(0.0, 0.0, 1.0),
+            Vec3R::new(1.0, 0.0, 0.0),
+            Vec3R::new(0.0, 1.0, 0.0),
+        ],
+    );
+    let ray = Ray::new(
+        Point3R::new(-0.5, -0.5, 3.0),
+        Vec3R::new(0.0, 0.0, -1.0).unit(),
+    );
+    let t = triangle.intersect(&ray, 0.0, Real::INFINITY);
+    let hit = triangle.hit(&ray, t);
+    assert!(hit.is_front_face);
+    let expected = Vec3R::new(0.25, 0.25, 0.5).normalize();
+    assert!((hit.normal.vec() - expected).length() < 1e-9);
+    // the light sampling pdf measures the flat triangle
+    assert!((hit.geometric_normal.vec() - Vec3R::new(0.0, 0.0, 1.0)).length() < 1e-9);
+}
+
+// ---- MESH ------
+
+/// Indexed triangle mesh as written in the scene file.
+/// `normals` and `uvs`, when not empty, have one entry for each position.
+#[derive(Deserialize, Debug)]
+pub struct DesMesh {
+    pub positions: Vec<Point3R>,
+    #[serde(default)]
+    pub normals: Vec<Normal3>,
+    #[serde(default)]
+    pub uvs: Vec<Vec2R>,
+    pub indices: Vec<[usize; 3]>,
+}
+
+/// Indexed triangle mesh, the triangles are stored in a bvh.
+pub struct Mesh {
+    positions: Vec<Point3R>,
+    normals: Vec<Normal3>,
+    uvs: Vec<Vec2R>,
+    indices: Vec<[usize; 3]>,
+    bvh: Bvh,
+    /// running sum of the triangles area, to pick them proportionally to their size
+    areas_cdf: Vec<Real>,
+}
+
+impl Mesh {
+    pub fn new(
+        positions: Vec<Point3R>,
+        normals: Vec<Normal3>,
+        uvs: Vec<Vec2R>,
+        indices: Vec<[usize; 3]>,
+    ) -> Result<Mesh, String> {
+        Mesh::validate(&positions, &normals, &uvs, &indices)?;
+        let bounding_boxes: Vec<Aabb> = indices
+            .iter()
+            .map(|&[i0, i1, i2]| Aabb::new(positions[i0], positions[i1]).grow(&positions[i2]))
+            .collect();
+        let mut total_area = 0.0;
+        let areas_cdf = indices
+            .iter()
+            .map(|&[i0, i1, i2]| {
+                total_area += triangle_area(&positions[i0], &positions[i1], &positions[i2]);
+                total_area
+            })
+            .collect();
+        Ok(Mesh {
+            bvh: Bvh::new(&bounding_boxes),
+            areas_cdf,
+            positions,
+            normals,
+            uvs,
+            indices,
+        })
+    }
+
+    /// Checks that normals, texture coordinates and indices refer to existing positions.
+    fn validate(
+        positions: &[Point3R],
+        normals: &[Normal3],
+        uvs: &[Vec2R],
+        indices: &[[usize; 3]],
+    ) -> Result<(), String> {
+        if !normals.is_empty() && normals.len() != positions.len() {
+            return Err(format!(
+                "mesh has {} normals for {} positions",
+                normals.len(),
+                positions.len()
+            ));
+        }
+        if !uvs.is_empty() && uvs.len() != positions.len() {
+            return Err(format!(
+                "mesh has {} texture coordinates for {} positions",
+                uvs.len(),
+                positions.len()
+            ));
+        }
+        for triangle in indices {
+            for &index in triangle {
+                if index >= positions.len() {
+                    return Err(format!(
+                        "mesh index {} out of range, there are {} positions",
+                        index,
+                        positions.len()
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn from_obj(path: &Path) -> Result<Mesh, String> {
+        match std::fs::read_to_string(path) {
+            Ok(data) => Mesh::parse_obj(&data),
+            Err(err) => Err(format!("cannot read '{}': {}", path.display(), err)),
+        }
+    }
+
+    /// Parses the geometry of a Wavefront OBJ file: `v`, `vt`, `vn` and `f` statements.
+    /// Polygons are split in a triangle fan, everything else is ignored.
+    pub fn parse_obj(data: &str) -> Result<Mesh, String> {
+        let mut obj_positions: Vec<Point3R> = Vec::new();
+        let mut obj_uvs: Vec<Vec2R> = Vec::new();
+        let mut obj_normals: Vec<Normal3> = Vec::new();
+        // the mesh has one vertex for each distinct (position, uv, normal) triple
+        let mut vertices: HashMap<(usize, Option<usize>, Option<usize>), usize> = HashMap::new();
+        let mut positions: Vec<Point3R> = Vec::new();
+        let mut uvs: Vec<Vec2R> = Vec::new();
+        let mut normals: Vec<Normal3> = Vec::new();
+        let mut indices: Vec<[usize; 3]> = Vec::new();
+        let mut has_uvs = true;
+        let mut has_normals = true;
+
+        for (line_index, line) in data.lines().enumerate() {
+            let error = |msg: &str| format!("obj line {}: {}", line_index + 1, msg);
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("v") => obj_positions.push(parse_obj_vec3(&mut tokens).map_err(error)?),
+                Some("vn") => obj_normals.push(parse_obj_vec3(&mut tokens).map_err(error)?),
+                Some("vt") => obj_uvs.push(parse_obj_vec2(&mut tokens).map_err(error)?),
+                Some("f") => {
+                    let mut face: Vec<usize> = Vec::new();
+                    for token in tokens {
+                        let mut refs = token.split('/');
+                        let position = parse_obj_index(refs.next(), obj_positions.len())
+                            .map_err(error)?
+                            .ok_or_else(|| error("face vertex without position"))?;
+                        let uv = parse_obj_index(refs.next(), obj_uvs.len()).map_err(error)?;
+                        let normal =
+                            parse_obj_index(refs.next(), obj_normals.len()).map_err(error)?;
+                        has_uvs &= uv.is_some();
+                        has_normals &= normal.is_some();
+                        let index = *vertices.entry((position, uv, normal)).or_insert_with(|| {
+                            positions.push(obj_positions[position]);
+                            uvs.push(uv.map(|uv| obj_uvs[uv]).unwrap_or_default());
+                            normals.push(normal.map(|n| obj_normals[n]).unwrap_or_default());
+                            positions.len() - 1
+                        });
+                        face.push(index);
+                    }
+                    if face.len() < 3 {
+                        return Err(error("face with less than 3 vertices"));
+                    }
+                    for i in 1..face.len() - 1 {
+                        indices.push([face[0], face[i], face[i + 1]]);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if !has_uvs {
+            uvs.clear();
+        }
+        if !has_normals {
+            normals.clear();
+        }
+        Mesh::new(positions, normals, uvs, indices)
+    }
+
+    fn vertices(&self, triangle: usize) -> [&Point3R; 3] {
+        let [i0, i1, i2] = self.indices[triangle];
+        [
+            &self.positions[i0],
+            &self.positions[i1],
+            &self.positions[i2],
+        ]
+    }
+
+    /// Triangle hit at `time`, with its barycentric coordinates.
+    fn triangle_at(&self, ray: &Ray, time: Real) -> Option<(usize, Vec2R)> {
+        let sheared_ray = ShearedRay::new(ray);
+        // search again just before the hit, with some room for rounding errors
+        let t_min = time - (time.abs() + 1.0) * 1e-9;
+        let mut barycentric = Vec2R::default();
+        self.bvh
+            .closest_hit(ray, t_min, Real::INFINITY, |triangle, max_time| {
+                let [v0, v1, v2] = self.vertices(triangle);
+                match sheared_ray.intersect(v0, v1, v2, t_min, max_time) {
+                    Some((t, hit_barycentric)) => {
+                        barycentric = hit_barycentric;
+                        t
+                    }
+                    None => Real::INFINITY,
+                }
+            })
+            .map(|(triangle, _)| (triangle, barycentric))
+    }
+}
+
+fn parse_obj_vec3<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Result<Vec3R, &'static str> {
+    let mut component = || -> Result<Real, &'static str> {
+        match tokens.next().map(|token| token.parse::<Real>()) {
+            Some(Ok(value)) if !value.is_nan() => Ok(value),
+            _ => Err("expected 3 numbers"),
+        }
+    };
+    Ok(Vec3R::new(component()?, component()?, component()?))
+}
+
+/// Texture coordinates, the optional third one is ignored.
+fn parse_obj_vec2<'a>(tokens: &mut impl Iterator<Item = &'a str>) -> Result<Vec2R, &'static str> {
+    let mut component = || -> Result<Real, &'static str> {
+        match tokens.next().map(|token| token.parse::<Real>()) {
+            Some(Ok(value)) if !value.is_nan() => Ok(value),
+            _ => Err("expected 2 numbers"),
+        }
+    };
+    Ok(Vec2R::new(component()?, component()?))
+}
+
+/// OBJ indices start from 1, negative indices are relative to the end of the list.
+fn parse_obj_index(token: Option<&str>, count: usize) -> Result<Option<usize>, &'static str> {
+    match token {
+        None | Some("") => Ok(None),
+        Some(token) => match token.parse::<i64>() {
+            Ok(index) if index > 0 && index as usize <= count => Ok(Some(index as usize - 1)),
+            Ok(index) if index < 0 && (-index) as usize <= count => {
+                Ok(Some(count - (-index) as usize))
+            }
+            _ => Err("invalid or out of range index"),
+        },
+    }
+}
+
+impl Geometry for Mesh {
+    fn intersect(&self, ray: &Ray, t_min: Real, t_max: Real) -> Real {
+        let sheared_ray = ShearedRay::new(ray);
+        match self
+            .bvh
+            .closest_hit(ray, t_min, t_max, |triangle, max_time| {
+                let [v0, v1, v2] = self.vertices(triangle);
+                match sheared_ray.intersect(v0, v1, v2, t_min, max_time) {
+                    Some((t, _)) => t,
+                    None => Real::INFINITY,
+                }
+            }) {
+            Some((_, t)) => t,
+            None => Real::INFINITY,
+        }
+    }
+
+    fn hit(&self, ray: &Ray, time: Real) -> Hit {
+        let (triangle, barycentric) = self.triangle_at(ray, time).unwrap_or_default();
+        let [i0, i1, i2] = self.indices[triangle];
+        let normals = if self.normals.is_empty() {
+            None
+        } else {
+            Some([&self.normals[i0], &self.normals[i1], &self.normals[i2]])
+        };
+        let uvs = if self.uvs.is_empty() {
+            None
+        } else {
+            Some([&self.uvs[i0], &self.uvs[i1], &self.uvs[i2]])
+        };
+        triangle_hit(
+            ray,
+            time,
+            self.vertices(triangle),
+            normals,
+            uvs,
+            barycentric,
+        )
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.bvh.bounds()
+    }
+
+    fn area(&self) -> Real {
+        self.areas_cdf.last().copied().unwrap_or(0.0)
+    }
+
+    fn sample_point(&self, rng: &mut dyn RngCore) -> Option<Point3R> {
+        if self.indices.is_empty() {
+            return None;
+        }
+        let r = rng.gen::<Real>() * self.area();
+        let triangle = match self
+            .areas_cdf
+            .binary_search_by(|area| area.partial_cmp(&r).unwrap())
+        {
+            Ok(index) => index + 1,
+            Err(index) => index,
+        };
+        let [v0, v1, v2] = self.vertices(triangle.min(self.indices.len() - 1));
+        Some(sample_triangle(v0, v1, v2, rng))
+    }
+}
+
+#[test]
+fn test_obj_mesh() {
+    let obj = "
+        # unit quad on the z = 1 plane, split by the fan triangulation
+        v -1 -1 1
+        v 1 -1 1
+        v 1 1 1
+        v -1 1 1
+        vn 0 0 -1
+        f 1//1 2//1 3//1 -1//-1
+    ";
+    let mesh = Mesh::parse_obj(obj).unwrap();
+    assert_eq!(mesh.positions.len(), 4);
+    assert_eq!(mesh.normals.len(), 4);
+    assert_eq!(mesh.indices, vec![[0, 1, 2], [0, 2, 3]]);
+
+    let ray = Ray::new(
+        Point3R::new(-0.5, 0.5, 0.0),
+        Vec3R::new(0.0, 0.0, 1.0).unit(),
+    );
+    let t = mesh.intersect(&ray, 0.0, Real::INFINITY);
+    assert_eq!(t, 1.0);
+    let hit = mesh.hit(&ray, t);
+    assert_eq!(hit.point, Point3R::new(-0.5, 0.5, 1.0));
+    assert_eq!(*hit.normal.vec(), Vec3R::new(0.0, 0.0, -1.0));
+    // second triangle, weights of vertices 3 and 4
+    assert_eq!(hit.barycentric, Vec2R::new(0.25, 0.5));
+
+    // no normals when a face vertex lacks one
+    let mesh = Mesh::parse_obj("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3").unwrap();
+    assert!(mesh.normals.is_empty());
+    assert!(mesh.uvs.is_empty());
+
+    // texture coordinates are interpolated
+    let mesh =
+        Mesh::parse_obj("v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 0 0.5 0\nf 1/1 2/2 3/3")
+            .unwrap();
+    let ray = Ray::new(
+        Point3R::new(0.5, 0.25, 1.0),
+        Vec3R::new(0.0, 0.0, -1.0).unit(),
+    );
+    let hit = mesh.hit(&ray, mesh.intersect(&ray, 0.0, Real::INFINITY));
+    assert_eq!(hit.uv, Vec2R::new(0.5, 0.125));
+
+    assert!(Mesh::parse_obj("v 0 0 0\nv 1 0 0\nf 1 2 3").is_err());
+    assert!(Mesh::parse_obj("v 0 0\n").is_err());
+    assert!(Mesh::parse_obj("v 0 0 0\nv 1 0 0\nf 1 2").is_err());
+}
@@ -0,0 +1,254 @@
+use super::defs::{Real, PI};
+use super::primitives::*;
+
+/*
+    The functions in this module work in the local shading space,
+    where the normal is +z and both directions point away from the surface.
+*/
+
+/// below this the distribution is so sharp that it breaks the floating point math
+const MIN_ALPHA: Real = 1e-3;
+
+/// Orthonormal basis around a normal.
+pub struct Frame {
+    s: Vec3R,
+    t: Vec3R,
+    n: Vec3R,
+}
+
+impl Frame {
+    pub fn new(normal: &Unit3R) -> Frame {
+        let (s, t) = normal.basis();
+        Frame {
+            s,
+            t,
+            n: *normal.vec(),
+        }
+    }
+
+    pub fn to_local(&self, v: &Vec3R) -> Vec3R {
+        Vec3R::new(v.dot(&self.s), v.dot(&self.t), v.dot(&self.n))
+    }
+
+    pub fn to_world(&self, v: &Vec3R) -> Vec3R {
+        v.x * self.s + v.y * self.t + v.z * self.n
+    }
+}
+
+/// Isotropic GGX (Trowbridge-Reitz) distribution of microfacet normals.
+pub struct Ggx {
+    pub alpha: Real,
+}
+
+impl Ggx {
+    /// Uses the common remapping alpha = roughness^2, perceptually more linear.
+    pub fn from_roughness(roughness: Real) -> Ggx {
+        Ggx {
+            alpha: (roughness * roughness).max(MIN_ALPHA),
+        }
+    }
+
+    /// Whether the distribution is too sharp to be sampled, the surface is a mirror.
+    pub fn is_smooth(&self) -> bool {
+        self.alpha <= MIN_ALPHA
+    }
+
+    /// Density of the microfacet normal `h`, projected on the macro surface it integrates to 1.
+    pub fn d(&self, h: &Vec3R) -> Real {
+        if h.z <= 0.0 {
+            return 0.0;
+        }
+        let a2 = self.alpha * self.alpha;
+        let k = h.z * h.z * (a2 - 1.0) + 1.0;
+        a2 / (PI * k * k)
+    }
+
+    fn lambda(&self, w: &Vec3R) -> Real {
+        let cos2 = w.z * w.z;
+        if cos2 <= 0.0 {
+            return Real::INFINITY;
+        }
+        let tan2 = (1.0 - cos2).max(0.0) / cos2;
+        0.5 * ((1.0 + self.alpha * self.alpha * tan2).sqrt() - 1.0)
+    }
+
+    /// Smith masking, fraction of the microfacets visible from `w`.
+    pub fn g1(&self, w: &Vec3R) -> Real {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    /// Height correlated Smith masking-shadowing.
+    pub fn g(&self, wo: &Vec3R, wi: &Vec3R) -> Real {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    /// Microfacet normal visible from `wo`, with density `pdf_visible`
+    /// (Heitz 2018, "Sampling the GGX Distribution of Visible Normals").
+    pub fn sample_visible(&self, wo: &Vec3R, u: Vec2R) -> Vec3R {
+        // stretch the view so that the distribution becomes a hemisphere
+        let v = Vec3R::new(self.alpha * wo.x, self.alpha * wo.y, wo.z).normalize();
+        let length2 = v.x * v.x + v.y * v.y;
+        let t1 = if length2 > 0.0 {
+            Vec3R::new(-v.y, v.x, 0.0) / length2.sqrt()
+        } else {
+            Vec3R::new(1.0, 0.0, 0.0)
+        };
+        let t2 = v.cross(&t1);
+        // uniform point on the projected half disk
+        let r = u.x.sqrt();
+        let phi = 2.0 * PI * u.y;
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + v.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+        let n = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * v;
+        Vec3R::new(self.alpha * n.x, self.alpha * n.y, n.z.max(0.0)).normalize()
+    }
+
+    /// Density of `sample_visible` returning `h`.
+    pub fn pdf_visible(&self, wo: &Vec3R, h: &Vec3R) -> Real {
+        if wo.z <= 0.0 {
+            return 0.0;
+        }
+        self.g1(wo) * wo.dot(h).max(0.0) * self.d(h) / wo.z
+    }
+}
+
+/// Generalized Trowbridge-Reitz with exponent 1, the long tailed lobe of clear coats.
+pub struct Gtr1 {
+    pub alpha: Real,
+}
+
+impl Gtr1 {
+    pub fn d(&self, h: &Vec3R) -> Real {
+        if h.z <= 0.0 {
+            return 0.0;
+        }
+        let a2 = self.alpha * self.alpha;
+        (a2 - 1.0) / (PI * a2.ln() * (1.0 + (a2 - 1.0) * h.z * h.z))
+    }
+
+    /// Microfacet normal with density `d(h) * h.z`.
+    pub fn sample(&self, u: Vec2R) -> Vec3R {
+        let a2 = self.alpha * self.alpha;
+        let cos2 = ((1.0 - a2.powf(1.0 - u.x)) / (1.0 - a2)).clamp(0.0, 1.0);
+        let sin = (1.0 - cos2).sqrt();
+        let phi = 2.0 * PI * u.y;
+        Vec3R::new(sin * phi.cos(), sin * phi.sin(), cos2.sqrt())
+    }
+}
+
+/// Reflection of `w` around `n`, both pointing away from the surface.
+pub fn reflect(w: &Vec3R, n: &Vec3R) -> Vec3R {
+    2.0 * w.dot(n) * n - w
+}
+
+/// `(1 - cosine)^5`, the angular falloff of Schlick's approximation.
+pub fn schlick_weight(cosine: Real) -> Real {
+    (1.0 - cosine).clamp(0.0, 1.0).powi(5)
+}
+
+/// Schlick's approximation of the Fresnel reflectance, `f0` is the reflectance at normal incidence.
+pub fn schlick(f0: &Vec3R, cosine: Real) -> Vec3R {
+    f0.lerp(&Vec3R::new(1.0, 1.0, 1.0), schlick_weight(cosine))
+}
+
+/// Unpolarized Fresnel reflectance of a dielectric interface, `eta` is the index
+/// of refraction of the other side divided by the one of the incoming side.
+pub fn fresnel_dielectric(cos_i: Real, eta: Real) -> Real {
+    let cos_i = cos_i.clamp(0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        // total internal reflection
+        return 1.0;
+    }
+    let cos_t = (1.0 - sin2_t).sqrt();
+    let parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (parallel * parallel + perpendicular * perpendicular)
+}
+
+/// Fresnel reflectance of a conductor with complex index of refraction `eta + i k`,
+/// for each color channel.
+pub fn fresnel_conductor(cos_i: Real, eta: &Vec3R, k: &Vec3R) -> Vec3R {
+    let cos2 = cos_i.clamp(0.0, 1.0).powi(2);
+    let sin2 = 1.0 - cos2;
+    let channel = |eta: Real, k: Real| {
+        let t0 = eta * eta - k * k - sin2;
+        let a2_plus_b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+        let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+        let t1 = a2_plus_b2 + cos2;
+        let t2 = 2.0 * cos2.sqrt() * a;
+        let perpendicular = (t1 - t2) / (t1 + t2);
+        let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let parallel = perpendicular * (t3 - t4) / (t3 + t4);
+        0.5 * (parallel + perpendicular)
+    };
+    Vec3R::new(
+        channel(eta.x, k.x),
+        channel(eta.y, k.y),
+        channel(eta.z, k.z),
+    )
+}
+
+#[test]
+fn test_ggx() {
+    use rand::Rng;
+    let mut rng = super::random::SampleRng::new(0, 0, 0, 0);
+    for &roughness in &[0.3, 0.6, 1.0] {
+        let ggx = Ggx::from_roughness(roughness);
+        // the projected normal density integrates to 1
+        let n = 400;
+        let mut integral = 0.0;
+        for i in 0..n {
+            let theta = 0.5 * PI * (i as Real + 0.5) / n as Real;
+            let h = Vec3R::new(theta.sin(), 0.0, theta.cos());
+            integral += ggx.d(&h) * h.z * theta.sin() * 2.0 * PI * (0.5 * PI / n as Real);
+        }
+        assert!((integral - 1.0).abs() < 0.01, "{} {}", roughness, integral);
+
+        // visible normals face the viewer and have the expected density
+        let wo = Vec3R::new(0.6, 0.0, 0.8);
+        for _ in 0..100 {
+            let h = ggx.sample_visible(&wo, Vec2R::new(rng.gen(), rng.gen()));
+            assert!((h.length() - 1.0).abs() < 1e-9);
+            assert!(h.z >= 0.0 && h.dot(&wo) >= 0.0);
+        }
+        let mut integral = 0.0;
+        for i in 0..n {
+            for j in 0..n {
+                let theta = 0.5 * PI * (i as Real + 0.5) / n as Real;
+                let phi = 2.0 * PI * (j as Real + 0.5) / n as Real;
+                let h = Vec3R::new(
+                    theta.sin() * phi.cos(),
+                    theta.sin() * phi.sin(),
+                    theta.cos(),
+                );
+                let d_omega = theta.sin() * (0.5 * PI / n as Real) * (2.0 * PI / n as Real);
+                integral += ggx.pdf_visible(&wo, &h) * d_omega;
+            }
+        }
+        assert!((integral - 1.0).abs() < 0.01, "{} {}", roughness, integral);
+    }
+
+    assert!((fresnel_dielectric(1.0, 1.5) - 0.04).abs() < 1e-9);
+    assert_eq!(fresnel_dielectric(0.1, 1.0 / 1.5), 1.0);
+    assert_eq!(fresnel_dielectric(0.0, 1.5), 1.0);
+
+    // at normal incidence ((n - 1)^2 + k^2) / ((n + 1)^2 + k^2)
+    let eta = Vec3R::new(0.2, 1.0, 1.5);
+    let k = Vec3R::new(3.9, 2.0, 0.0);
+    let f0 = fresnel_conductor(1.0, &eta, &k);
+    let expected = |n: Real, k: Real| ((n - 1.0).powi(2) + k * k) / ((n + 1.0).powi(2) + k * k);
+    assert!((f0.x - expected(0.2, 3.9)).abs() < 1e-9);
+    assert!((f0.y - expected(1.0, 2.0)).abs() < 1e-9);
+    // without absorption it's a dielectric
+    assert!((f0.z - fresnel_dielectric(1.0, 1.5)).abs() < 1e-9);
+    assert!((fresnel_conductor(0.4, &eta, &k).z - fresnel_dielectric(0.4, 1.5)).abs() < 1e-9);
+    assert!(
+        (fresnel_conductor(0.0, &eta, &k) - Vec3R::new(1.0, 1.0, 1.0))
+            .abs()
+            .max_component()
+            < 1e-9
+    );
+}
@@ -1,9 +1,18 @@
+pub mod background;
+pub mod bvh;
 pub mod camera;
 pub mod defs;
+pub mod distribution;
 pub mod geometry;
 pub mod material;
+pub mod medium;
+pub mod mesh;
+pub mod microfacet;
 pub mod object;
 pub mod primitives;
+pub mod random;
 pub mod renderer;
 pub mod scene;
+pub mod spectrum;
+pub mod texture;
 pub mod transform;
@@ -1,7 +1,14 @@
 use super::geometry::Geometry;
 use super::material::Material;
+use super::medium::Medium;
 
 pub struct Object<'a, 'b> {
+    /// position in the objects of the scene
+    pub index: usize,
+    /// position in the materials of the scene, sorted by name
+    pub material_index: usize,
     pub geometry: &'b dyn Geometry,
     pub material: &'a dyn Material,
+    /// medium filling the inside of the geometry
+    pub medium: Option<&'a Medium>,
 }
@@ -1,6 +1,7 @@
 pub use super::defs::Real;
 
 extern crate overload;
+use super::spectrum::Wavelengths;
 use overload::overload;
 use rand::Rng;
 use serde::Deserialize;
@@ -22,7 +23,6 @@ fn max(a: Real, b: Real) -> Real {
     }
 }
 
-pub type Point2R = Vec2R;
 pub type Point3R = Vec3R;
 
 pub type Normal2 = Vec2R;
@@ -285,6 +285,11 @@ impl Vec3R {
         max(max(self.x, self.y), self.z)
     }
 
+    /// Relative luminance of a linear Rec. 709 color.
+    pub fn luminance(&self) -> Real {
+        0.2126 * self.x + 0.7152 * self.y + 0.0722 * self.z
+    }
+
     pub fn distance(&self, other: &Vec3R) -> Real {
         (self - other).length()
     }
@@ -361,13 +366,25 @@ impl Unit3R {
             Unit3R(src)
         }
     }
-    pub fn random() -> Unit3R {
-        let mut rng = rand::thread_rng();
+    /// Uniformly distributed on the sphere, drawn from `rng`.
+    pub fn random_with<R: Rng + ?Sized>(rng: &mut R) -> Unit3R {
         let a: Real = rng.gen_range(0.0, 2.0 * std::f64::consts::PI as Real);
         let z: Real = rng.gen_range(-1.0, 1.0);
         let r = (1.0 - z * z).sqrt();
         Unit3R(Vec3R::new(r * a.cos(), r * a.sin(), z))
     }
+    /// Two unit vectors that form an orthonormal basis with this one
+    /// (Duff et al. 2017, "Building an Orthonormal Basis, Revisited").
+    pub fn basis(&self) -> (Vec3R, Vec3R) {
+        let n = self.0;
+        let sign = 1.0_f64.copysign(n.z);
+        let a = -1.0 / (sign + n.z);
+        let b = n.x * n.y * a;
+        (
+            Vec3R::new(1.0 + sign * n.x * n.x * a, sign * b, -sign * n.x),
+            Vec3R::new(b, sign + n.y * n.y * a, -n.y),
+        )
+    }
     pub fn vec(&self) -> &Vec3R {
         &self.0
     }
@@ -386,10 +403,24 @@ overload!(- (a: ? Unit3R) -> Unit3R { Unit3R(-a.vec()) });
 
 #[test]
 fn test_unit_vec() {
+    let mut rng = rand::thread_rng();
     let unit = Unit3R::normalized(Vec3R::new(10.0, -5.0, 0.3));
     assert!((unit.vec().length() - 1.0).abs() < 100.0 * Real::EPSILON);
     for _ in 0..1000 {
-        assert!((Unit3R::random().vec().length_squared() - 1.0).abs() < 100.0 * Real::EPSILON);
+        assert!(
+            (Unit3R::random_with(&mut rng).vec().length_squared() - 1.0).abs()
+                < 100.0 * Real::EPSILON
+        );
+    }
+    for _ in 0..100 {
+        let n = Unit3R::random_with(&mut rng);
+        let (t, b) = n.basis();
+        assert!((t.length() - 1.0).abs() < 1e-9, "basis tangent is unit");
+        assert!((b.length() - 1.0).abs() < 1e-9, "basis bitangent is unit");
+        assert!(
+            t.dot(n.vec()).abs() < 1e-9 && b.dot(n.vec()).abs() < 1e-9 && t.dot(&b).abs() < 1e-9,
+            "orthogonal basis"
+        );
     }
 }
 
@@ -529,11 +560,14 @@ fn vec3r_test() {
     );
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Ray {
     pub origin: Point3R,
     pub direction: Unit3R,
+    /// in spectral mode the components are the values at the ray wavelengths
     pub color: Vec3R,
+    /// None unless rendering in spectral mode
+    pub wavelengths: Option<Wavelengths>,
 }
 
 impl Ray {
@@ -543,6 +577,7 @@ impl Ray {
             origin: origin,
             direction,
             color: Vec3R::new(1.0, 1.0, 1.0),
+            wavelengths: None,
         }
     }
     pub fn with_color(origin: Point3R, direction: Unit3R, color: Vec3R) -> Ray {
@@ -550,6 +585,14 @@ impl Ray {
             origin,
             direction,
             color,
+            wavelengths: None,
+        }
+    }
+    /// An RGB color of the scene as carried by this ray.
+    pub fn color_of(&self, rgb: &Vec3R) -> Vec3R {
+        match &self.wavelengths {
+            Some(wavelengths) => wavelengths.rgb_to_spectrum(rgb),
+            None => *rgb,
         }
     }
     pub fn at(&self, time: Real) -> Point3R {
@@ -572,6 +615,168 @@ fn ray_test() {
 
 pub struct Hit {
     pub point: Point3R,
+    /// `point` in the space of the object, before its transform, where solid textures are evaluated
+    pub local_point: Point3R,
+    /// shading normal, it can be interpolated, facing the ray like `geometric_normal`
     pub normal: Unit3R,
+    /// normal of the surface itself, the one to measure its area with
+    pub geometric_normal: Unit3R,
     pub is_front_face: bool,
+    /// weights of the second and third vertex when a triangle is hit
+    pub barycentric: Vec2R,
+    /// texture coordinates, usually in [0, 1]
+    pub uv: Vec2R,
+}
+
+/// Hit at `point` of a surface facing up, for the tests of materials and textures.
+#[cfg(test)]
+pub fn test_hit(point: Point3R, is_front_face: bool) -> Hit {
+    Hit {
+        point,
+        local_point: point,
+        normal: Unit3R::UP,
+        geometric_normal: Unit3R::UP,
+        is_front_face,
+        barycentric: Vec2R::default(),
+        uv: Vec2R::default(),
+    }
+}
+
+/// Axis aligned bounding box, empty when `min` is greater than `max`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Point3R,
+    pub max: Point3R,
+}
+
+impl Aabb {
+    pub fn new(corner_a: Point3R, corner_b: Point3R) -> Aabb {
+        Aabb {
+            min: corner_a.min(&corner_b),
+            max: corner_a.max(&corner_b),
+        }
+    }
+
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vec3R::new(Real::INFINITY, Real::INFINITY, Real::INFINITY),
+            max: Vec3R::new(Real::NEG_INFINITY, Real::NEG_INFINITY, Real::NEG_INFINITY),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(&other.min),
+            max: self.max.max(&other.max),
+        }
+    }
+
+    pub fn grow(&self, point: &Point3R) -> Aabb {
+        Aabb {
+            min: self.min.min(point),
+            max: self.max.max(point),
+        }
+    }
+
+    pub fn size(&self) -> Vec3R {
+        self.max - self.min
+    }
+
+    pub fn centroid(&self) -> Point3R {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> Real {
+        if self.is_empty() {
+            0.0
+        } else {
+            let size = self.size();
+            2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+        }
+    }
+
+    /// Index of the axis along which the box is widest.
+    pub fn largest_axis(&self) -> usize {
+        let size = self.size();
+        if size.x >= size.y && size.x >= size.z {
+            Vec3R::X
+        } else if size.y >= size.z {
+            Vec3R::Y
+        } else {
+            Vec3R::Z
+        }
+    }
+
+    /// Slab test, returns the time the ray enters the box (clamped to `t_min`)
+    /// or infinity when the box is missed in the given range.
+    /// `inv_direction` is the component wise inverse of the ray direction.
+    pub fn intersect(
+        &self,
+        origin: &Point3R,
+        inv_direction: &Vec3R,
+        t_min: Real,
+        t_max: Real,
+    ) -> Real {
+        let tx0 = (self.min.x - origin.x) * inv_direction.x;
+        let tx1 = (self.max.x - origin.x) * inv_direction.x;
+        let ty0 = (self.min.y - origin.y) * inv_direction.y;
+        let ty1 = (self.max.y - origin.y) * inv_direction.y;
+        let tz0 = (self.min.z - origin.z) * inv_direction.z;
+        let tz1 = (self.max.z - origin.z) * inv_direction.z;
+        // f64 min and max ignore NaN, which happens when the origin lies on a plane
+        let t_enter = t_min.max(tx0.min(tx1)).max(ty0.min(ty1)).max(tz0.min(tz1));
+        let t_exit = t_max.min(tx0.max(tx1)).min(ty0.max(ty1)).min(tz0.max(tz1));
+        if t_enter <= t_exit {
+            t_enter
+        } else {
+            Real::INFINITY
+        }
+    }
+}
+
+#[test]
+fn aabb_test() {
+    let aabb = Aabb::new(Point3R::new(1.0, 2.0, 3.0), Point3R::new(-1.0, 0.0, 0.0));
+    assert_eq!(aabb.min, Point3R::new(-1.0, 0.0, 0.0), "min corner");
+    assert_eq!(aabb.max, Point3R::new(1.0, 2.0, 3.0), "max corner");
+    assert_eq!(aabb.surface_area(), 2.0 * (4.0 + 6.0 + 6.0), "surface area");
+    assert_eq!(aabb.largest_axis(), Vec3R::Z, "largest axis");
+    assert_eq!(aabb.centroid(), Point3R::new(0.0, 1.0, 1.5), "centroid");
+
+    assert!(Aabb::empty().is_empty(), "empty");
+    assert_eq!(Aabb::empty().surface_area(), 0.0, "empty area");
+    assert_eq!(Aabb::empty().union(&aabb), aabb, "union with empty");
+    assert_eq!(
+        Aabb::empty().grow(&Point3R::new(1.0, 1.0, 1.0)).size(),
+        Vec3R::default(),
+        "grow empty"
+    );
+
+    let origin = Point3R::new(0.0, 1.0, -2.0);
+    let inv_direction = Vec3R::new(Real::INFINITY, Real::INFINITY, 1.0);
+    assert_eq!(
+        aabb.intersect(&origin, &inv_direction, 0.0, Real::INFINITY),
+        2.0,
+        "hit"
+    );
+    assert_eq!(
+        aabb.intersect(&origin, &inv_direction, 0.0, 1.0),
+        Real::INFINITY,
+        "too far"
+    );
+    assert_eq!(
+        aabb.intersect(&origin, &inv_direction, 3.0, 4.0),
+        3.0,
+        "from inside"
+    );
+    let origin = Point3R::new(0.0, 3.0, -2.0);
+    assert_eq!(
+        aabb.intersect(&origin, &inv_direction, 0.0, Real::INFINITY),
+        Real::INFINITY,
+        "miss"
+    );
 }
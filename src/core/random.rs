@@ -0,0 +1,106 @@
+use rand::{Error, RngCore};
+
+/*
+    Every random number of a render comes from the generator of the pixel sample
+    being computed, seeded with the pixel, the sample index and the seed of the
+    scene. The same seed gives the same image whatever the threads do.
+*/
+
+/// PCG32 generator (O'Neill 2014, "PCG: A Family of Simple Fast Space-Efficient
+/// Statistically Good Algorithms for Random Number Generation").
+#[derive(Debug, Clone)]
+pub struct SampleRng {
+    state: u64,
+    increment: u64,
+}
+
+const MULTIPLIER: u64 = 6_364_136_223_846_793_005;
+
+/// SplitMix64 finalizer, scrambles the bits so that close inputs give unrelated outputs.
+fn mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+impl SampleRng {
+    /// Generator of the sample number `sample` of the pixel at `row` and `col`.
+    pub fn new(seed: u64, row: usize, col: usize, sample: usize) -> SampleRng {
+        let hash = mix(mix(mix(mix(seed) ^ row as u64) ^ col as u64) ^ sample as u64);
+        SampleRng::from_seed(hash, mix(hash))
+    }
+
+    fn from_seed(state: u64, stream: u64) -> SampleRng {
+        let mut rng = SampleRng {
+            state: 0,
+            increment: (stream << 1) | 1,
+        };
+        rng.step();
+        rng.state = rng.state.wrapping_add(state);
+        rng.step();
+        rng
+    }
+
+    fn step(&mut self) {
+        self.state = self
+            .state
+            .wrapping_mul(MULTIPLIER)
+            .wrapping_add(self.increment);
+    }
+}
+
+impl RngCore for SampleRng {
+    fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.step();
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        xorshifted.rotate_right((old >> 59) as u32)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        (u64::from(self.next_u32()) << 32) | u64::from(self.next_u32())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_sample_rng() {
+    use rand::Rng;
+    // reference output of the PCG32 demo, seeded with 42 on stream 54
+    let mut rng = SampleRng::from_seed(42, 54);
+    let expected = [
+        0xa15c_02b7,
+        0x7b47_f409,
+        0xba1d_3330,
+        0x83d2_f293,
+        0xbfa4_784b,
+        0xcbed_606e,
+    ];
+    for value in &expected {
+        assert_eq!(rng.next_u32(), *value);
+    }
+
+    // same pixel sample, same numbers, any other changes them
+    let numbers = |rng: &mut SampleRng| (0..8).map(|_| rng.gen::<u64>()).collect::<Vec<_>>();
+    let reference = numbers(&mut SampleRng::new(7, 10, 20, 3));
+    assert_eq!(numbers(&mut SampleRng::new(7, 10, 20, 3)), reference);
+    assert_ne!(numbers(&mut SampleRng::new(8, 10, 20, 3)), reference);
+    assert_ne!(numbers(&mut SampleRng::new(7, 20, 10, 3)), reference);
+    assert_ne!(numbers(&mut SampleRng::new(7, 10, 20, 4)), reference);
+
+    let mut rng = SampleRng::new(0, 0, 0, 0);
+    let mean = (0..10000).map(|_| rng.gen::<f64>()).sum::<f64>() / 10000.0;
+    assert!((mean - 0.5).abs() < 0.01, "{}", mean);
+}
@@ -0,0 +1,236 @@
+use super::renderer_buffer::*;
+use rayon::prelude::*;
+
+/// below this luminance the error is compared to it instead of the pixel mean,
+/// otherwise dark pixels would never converge
+const MIN_LUMINANCE: Real = 0.01;
+/// 95% of the normal distribution is within this many standard deviations
+const CONFIDENCE_Z: Real = 1.96;
+
+/// When the pixels of an `AdaptiveBuffer` stop being sampled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdaptiveSampling {
+    /// half width of the confidence interval of the mean, relative to the mean luminance
+    pub threshold: Real,
+    /// samples taken before checking the error, at least 2 to estimate the variance
+    pub min_spp: usize,
+    pub max_spp: usize,
+}
+
+impl Default for AdaptiveSampling {
+    fn default() -> Self {
+        AdaptiveSampling {
+            threshold: 0.02,
+            min_spp: 16,
+            max_spp: 1024,
+        }
+    }
+}
+
+/// Buffer that keeps sampling each pixel until its mean is known well enough.
+pub struct AdaptiveBuffer {
+    pixels: Vec<PixelStats>,
+    settings: AdaptiveSampling,
+    width: usize,
+    height: usize,
+}
+
+/// Running mean and variance of the samples of a pixel (Welford's algorithm).
+#[derive(Clone, Default)]
+struct PixelStats {
+    count: usize,
+    mean: RgbReal,
+    luminance_mean: Real,
+    /// sum of the squared differences from the mean of the luminance
+    luminance_m2: Real,
+    converged: bool,
+}
+
+impl PixelStats {
+    fn add(&mut self, sample: RgbReal, settings: &AdaptiveSampling) {
+        self.count += 1;
+        let n = self.count as Real;
+        self.mean.0 += (sample.0 - self.mean.0) / n;
+        self.mean.1 += (sample.1 - self.mean.1) / n;
+        self.mean.2 += (sample.2 - self.mean.2) / n;
+        let luminance = luminance(sample);
+        let delta = luminance - self.luminance_mean;
+        self.luminance_mean += delta / n;
+        self.luminance_m2 += delta * (luminance - self.luminance_mean);
+        self.converged = self.count >= settings.max_spp
+            || (self.count >= settings.min_spp.max(2)
+                && self.error() <= settings.threshold * self.luminance_mean.max(MIN_LUMINANCE));
+    }
+
+    /// Half width of the confidence interval of the mean luminance.
+    fn error(&self) -> Real {
+        let n = self.count as Real;
+        let variance = self.luminance_m2 / (n - 1.0);
+        CONFIDENCE_Z * (variance / n).sqrt()
+    }
+}
+
+impl AdaptiveBuffer {
+    pub fn new(width: usize, height: usize, settings: AdaptiveSampling) -> AdaptiveBuffer {
+        AdaptiveBuffer {
+            pixels: vec![PixelStats::default(); width * height],
+            settings,
+            width,
+            height,
+        }
+    }
+
+    /// Whether every pixel stopped sampling.
+    pub fn is_converged(&self) -> bool {
+        self.pixels.iter().all(|pixel| pixel.converged)
+    }
+
+    pub fn average_spp(&self) -> Real {
+        let total: usize = self.pixels.iter().map(|pixel| pixel.count).sum();
+        total as Real / self.pixels.len().max(1) as Real
+    }
+
+    /// RGBA image of the samples taken by each pixel, from blue for the fewest
+    /// through red to yellow for the most.
+    pub fn to_heatmap(&self) -> Vec<u8> {
+        let min = self
+            .pixels
+            .iter()
+            .map(|pixel| pixel.count)
+            .min()
+            .unwrap_or(0);
+        let max = self
+            .pixels
+            .iter()
+            .map(|pixel| pixel.count)
+            .max()
+            .unwrap_or(0);
+        let mut img = new_rgbau8_vec(self.width, self.height);
+        img.as_mut_slice()
+            .par_iter_mut()
+            .zip(self.pixels.par_iter())
+            .for_each(|(rgba, pixel)| {
+                let t = if max > min {
+                    (pixel.count - min) as Real / (max - min) as Real
+                } else {
+                    0.0
+                };
+                let (r, g, b) = if t < 0.5 {
+                    (2.0 * t, 0.0, 1.0 - 2.0 * t)
+                } else {
+                    (1.0, 2.0 * t - 1.0, 0.0)
+                };
+                rgba.0 = (255.0 * r).round() as u8;
+                rgba.1 = (255.0 * g).round() as u8;
+                rgba.2 = (255.0 * b).round() as u8;
+            });
+        rgbau8_vec_to_u8_vec(img)
+    }
+}
+
+impl RendererBuffer for AdaptiveBuffer {
+    fn sample_pixels<F: Fn(usize, usize, usize) -> RgbReal + Send + Sync>(&mut self, sampler: F) {
+        let w = self.width;
+        let settings = &self.settings;
+        self.pixels
+            .par_iter_mut()
+            .enumerate()
+            .filter(|(_, pixel)| !pixel.converged)
+            .for_each(|(pixel_index, pixel)| {
+                let sampled = sampler(pixel_index / w, pixel_index % w, pixel.count);
+                pixel.add(sampled, settings);
+            });
+    }
+
+    fn to_linear(&self) -> Vec<RgbReal> {
+        self.pixels.par_iter().map(|pixel| pixel.mean).collect()
+    }
+
+    fn width(&self) -> usize {
+        self.width
+    }
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn reset(&mut self) {
+        for pixel in &mut self.pixels {
+            *pixel = PixelStats::default();
+        }
+    }
+}
+
+#[test]
+fn test_adaptive_buffer() {
+    let settings = AdaptiveSampling {
+        threshold: 0.05,
+        min_spp: 4,
+        max_spp: 200,
+    };
+
+    // Welford matches the two pass mean and variance
+    let values = [0.3, 0.9, 0.1, 0.4, 0.8, 0.2];
+    let mut stats = PixelStats::default();
+    for &value in &values {
+        stats.add((value, value, value), &settings);
+    }
+    let n = values.len() as Real;
+    let mean = values.iter().sum::<Real>() / n;
+    let variance = values.iter().map(|v| (v - mean) * (v - mean)).sum::<Real>() / (n - 1.0);
+    assert!((stats.mean.0 - mean).abs() < 1e-12);
+    assert!((stats.luminance_mean - mean).abs() < 1e-9);
+    assert!((stats.error() - CONFIDENCE_Z * (variance / n).sqrt()).abs() < 1e-9);
+
+    // the left half is noisy, the right half flat
+    let (width, height) = (8, 4);
+    let mut buffer = AdaptiveBuffer::new(width, height, settings.clone());
+    let sampler = |_row: usize, col: usize, sample: usize| {
+        let value = if col < 4 {
+            // deterministic noise around 0.5
+            let hash = (sample as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 11;
+            hash as Real / (1u64 << 53) as Real
+        } else {
+            0.5
+        };
+        (value, value, value)
+    };
+    for _ in 0..1000 {
+        buffer.sample_pixels(sampler);
+        if buffer.is_converged() {
+            break;
+        }
+    }
+    assert!(buffer.is_converged());
+    for (index, pixel) in buffer.pixels.iter().enumerate() {
+        if index % width < 4 {
+            assert!(pixel.count > settings.min_spp && pixel.count <= settings.max_spp);
+            assert!((pixel.mean.0 - 0.5).abs() < 0.1, "{}", pixel.mean.0);
+        } else {
+            assert_eq!(pixel.count, settings.min_spp);
+        }
+    }
+    // converged pixels aren't sampled anymore
+    let counts: Vec<usize> = buffer.pixels.iter().map(|pixel| pixel.count).collect();
+    buffer.sample_pixels(sampler);
+    assert_eq!(
+        buffer
+            .pixels
+            .iter()
+            .map(|pixel| pixel.count)
+            .collect::<Vec<_>>(),
+        counts
+    );
+
+    let display = super::DisplaySettings::default();
+    let image = buffer.to_img(&display);
+    assert_eq!(image[4 * 4], display.to_rgba((0.5, 0.5, 0.5), 0, 4).0);
+    assert_eq!(buffer.to_linear()[4], (0.5, 0.5, 0.5));
+    let heatmap = buffer.to_heatmap();
+    // the least sampled pixels are blue, the most sampled are not
+    assert_eq!(&heatmap[4 * 4..4 * 4 + 4], &[0, 0, 255, 255]);
+    assert!(heatmap[..4 * 4].chunks(4).any(|rgba| rgba[2] < 255));
+
+    buffer.reset();
+    assert!(!buffer.is_converged());
+    assert_eq!(buffer.average_spp(), 0.0);
+}
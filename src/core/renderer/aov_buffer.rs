@@ -0,0 +1,395 @@
+use super::super::primitives::*;
+use super::super::random::SampleRng;
+use super::display::DisplaySettings;
+use super::hdr_image::ExrChannel;
+use super::renderer_buffer::*;
+use rand::Rng;
+use rayon::prelude::*;
+
+/// Values of a camera sample besides its color, taken at the first surface it hits,
+/// interfaces and media don't count.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AovSample {
+    pub albedo: Vec3R,
+    /// shading normal, facing the camera
+    pub normal: Vec3R,
+    pub position: Point3R,
+    /// distance from the camera along its view direction
+    pub depth: Real,
+    /// index of the object hit, None for the background
+    pub object: Option<usize>,
+    pub material: Option<usize>,
+    /// bounces of the whole path
+    pub bounces: usize,
+}
+
+/// Arbitrary output variable, a per pixel value written besides the color.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aov {
+    Albedo,
+    Normal,
+    Depth,
+    Position,
+    Object,
+    Material,
+    Bounces,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 7] = [
+        Aov::Albedo,
+        Aov::Normal,
+        Aov::Depth,
+        Aov::Position,
+        Aov::Object,
+        Aov::Material,
+        Aov::Bounces,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::Object => "object",
+            Aov::Material => "material",
+            Aov::Bounces => "bounces",
+        }
+    }
+}
+
+impl std::str::FromStr for Aov {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Aov::ALL
+            .iter()
+            .find(|aov| aov.name() == name)
+            .copied()
+            .ok_or_else(|| format!("unknown output variable '{}'", name))
+    }
+}
+
+/// Buffer of the color and of the output variables of the pixels.
+pub struct AovBuffer {
+    pixels: Vec<AovPixel>,
+    samples_count: usize,
+    width: usize,
+    height: usize,
+}
+
+#[derive(Clone, Default)]
+struct AovPixel {
+    /// sums of the samples, the geometric ones only over the samples that hit a surface
+    color: RgbReal,
+    albedo: Vec3R,
+    normal: Vec3R,
+    position: Point3R,
+    depth: Real,
+    bounces: usize,
+    /// samples that hit a surface
+    hits: usize,
+    /// indices of the first sample, they can't be averaged
+    object: Option<usize>,
+    material: Option<usize>,
+}
+
+impl AovPixel {
+    fn add(&mut self, color: RgbReal, aov: &AovSample, is_first: bool) {
+        self.color.0 += color.0;
+        self.color.1 += color.1;
+        self.color.2 += color.2;
+        self.albedo += aov.albedo;
+        self.bounces += aov.bounces;
+        if aov.object.is_some() {
+            self.normal += aov.normal;
+            self.position += aov.position;
+            self.depth += aov.depth;
+            self.hits += 1;
+        }
+        if is_first {
+            self.object = aov.object;
+            self.material = aov.material;
+        }
+    }
+}
+
+fn to_u8(x: Real) -> u8 {
+    (255.0 * x).round().clamp(0.0, 255.0) as u8
+}
+
+/// Color of an index, the same index always gets the same color.
+fn index_color(index: Option<usize>) -> RgbaU8 {
+    match index {
+        Some(index) => {
+            let mut rng = SampleRng::new(0, index, 0, 0);
+            let mut channel = || to_u8(rng.gen_range(0.2, 1.0));
+            (channel(), channel(), channel(), 255)
+        }
+        None => (0, 0, 0, 255),
+    }
+}
+
+impl AovBuffer {
+    pub fn new(width: usize, height: usize) -> AovBuffer {
+        AovBuffer {
+            pixels: vec![AovPixel::default(); width * height],
+            samples_count: 0,
+            width,
+            height,
+        }
+    }
+
+    /// Values of the output variable of the pixels, row major, scalars are repeated
+    /// in the three components. The values are averaged over the samples, the geometric
+    /// ones over those that hit a surface, and without any the depth is infinite.
+    /// The indices are the ones of the first sample, -1 for the background.
+    pub fn aov(&self, aov: Aov) -> Vec<RgbReal> {
+        let samples = self.samples_count.max(1) as Real;
+        let index = |index: Option<usize>| {
+            let value = index.map_or(-1.0, |index| index as Real);
+            (value, value, value)
+        };
+        self.pixels
+            .par_iter()
+            .map(|pixel| {
+                let hits = pixel.hits.max(1) as Real;
+                let rgb = |v: Vec3R| (v.x, v.y, v.z);
+                match aov {
+                    Aov::Albedo => rgb(pixel.albedo / samples),
+                    Aov::Normal => rgb(pixel.normal / hits),
+                    Aov::Position => rgb(pixel.position / hits),
+                    Aov::Depth => {
+                        let depth = if pixel.hits > 0 {
+                            pixel.depth / hits
+                        } else {
+                            Real::INFINITY
+                        };
+                        (depth, depth, depth)
+                    }
+                    Aov::Object => index(pixel.object),
+                    Aov::Material => index(pixel.material),
+                    Aov::Bounces => {
+                        let bounces = pixel.bounces as Real / samples;
+                        (bounces, bounces, bounces)
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Channels of the output variable, to be written in an OpenEXR file with the color.
+    pub fn exr_channels(&self, aov: Aov) -> Vec<ExrChannel> {
+        let values = self.aov(aov);
+        let channel = |name: &str| ExrChannel {
+            name: name.to_string(),
+            values: values.iter().map(|rgb| rgb.0).collect(),
+        };
+        let vector = |layer: &str| {
+            let mut channels = ExrChannel::rgb(Some(layer), &values);
+            for (channel, axis) in channels.iter_mut().zip(&["X", "Y", "Z"]) {
+                channel.name = format!("{}.{}", layer, axis);
+            }
+            channels
+        };
+        match aov {
+            Aov::Albedo => ExrChannel::rgb(Some("albedo"), &values),
+            Aov::Normal => vector("normal"),
+            Aov::Position => vector("position"),
+            // the name OpenEXR readers expect for the depth
+            Aov::Depth => vec![channel("Z")],
+            Aov::Object | Aov::Material | Aov::Bounces => vec![channel(aov.name())],
+        }
+    }
+
+    /// RGBA image to look at the output variable: the albedo in sRGB, the normals
+    /// from [-1, 1] to [0, 1], the positions within the box of the ones hit,
+    /// the depth from white for the closest to black for the farthest, the bounces
+    /// from black for none to white for the most and a random color for each index.
+    pub fn aov_img(&self, aov: Aov) -> Vec<u8> {
+        let values = self.aov(aov);
+        if aov == Aov::Albedo {
+            return DisplaySettings::default().to_img(&values, self.width);
+        }
+        // range of the values, without the background for the geometric ones
+        let (min, max) = values
+            .iter()
+            .zip(&self.pixels)
+            .filter(|(_, pixel)| aov == Aov::Bounces || pixel.hits > 0)
+            .fold(
+                (Real::INFINITY, Real::NEG_INFINITY),
+                |(min, max), (rgb, _)| {
+                    (
+                        min.min(rgb.0).min(rgb.1).min(rgb.2),
+                        max.max(rgb.0).max(rgb.1).max(rgb.2),
+                    )
+                },
+            );
+        let scale = |x: Real, low: Real, high: Real| {
+            if high > low {
+                to_u8((x - low) / (high - low))
+            } else {
+                0
+            }
+        };
+        let mut img = new_rgbau8_vec(self.width, self.height);
+        img.as_mut_slice()
+            .par_iter_mut()
+            .zip(values.par_iter().zip(self.pixels.par_iter()))
+            .for_each(|(rgba, (value, pixel))| {
+                *rgba = match aov {
+                    Aov::Albedo => unreachable!(),
+                    Aov::Normal => (
+                        to_u8(0.5 * value.0 + 0.5),
+                        to_u8(0.5 * value.1 + 0.5),
+                        to_u8(0.5 * value.2 + 0.5),
+                        255,
+                    ),
+                    Aov::Position if pixel.hits > 0 => (
+                        scale(value.0, min, max),
+                        scale(value.1, min, max),
+                        scale(value.2, min, max),
+                        255,
+                    ),
+                    Aov::Depth if pixel.hits > 0 => {
+                        let gray = 255 - scale(value.0, min, max);
+                        (gray, gray, gray, 255)
+                    }
+                    Aov::Position | Aov::Depth => (0, 0, 0, 255),
+                    Aov::Bounces => {
+                        let gray = scale(value.0, 0.0, max);
+                        (gray, gray, gray, 255)
+                    }
+                    Aov::Object => index_color(pixel.object),
+                    Aov::Material => index_color(pixel.material),
+                }
+            });
+        rgbau8_vec_to_u8_vec(img)
+    }
+}
+
+impl RendererBuffer for AovBuffer {
+    fn sample_pixels<F: Fn(usize, usize, usize) -> RgbReal + Send + Sync>(&mut self, sampler: F) {
+        self.sample_pixels_with_aovs(|row, col, sample| {
+            (sampler(row, col, sample), AovSample::default())
+        });
+    }
+
+    fn keeps_aovs(&self) -> bool {
+        true
+    }
+
+    fn sample_pixels_with_aovs<F: Fn(usize, usize, usize) -> (RgbReal, AovSample) + Send + Sync>(
+        &mut self,
+        sampler: F,
+    ) {
+        let w = self.width;
+        let sample_index = self.samples_count;
+        self.pixels
+            .par_iter_mut()
+            .enumerate()
+            .for_each(|(pixel_index, pixel)| {
+                let (color, aov) = sampler(pixel_index / w, pixel_index % w, sample_index);
+                pixel.add(color, &aov, sample_index == 0);
+            });
+        self.samples_count += 1;
+    }
+
+    fn to_linear(&self) -> Vec<RgbReal> {
+        let sc = self.samples_count.max(1) as Real;
+        self.pixels
+            .par_iter()
+            .map(|pixel| (pixel.color.0 / sc, pixel.color.1 / sc, pixel.color.2 / sc))
+            .collect()
+    }
+
+    fn width(&self) -> usize {
+        self.width
+    }
+    fn height(&self) -> usize {
+        self.height
+    }
+
+    fn reset(&mut self) {
+        self.samples_count = 0;
+        for pixel in &mut self.pixels {
+            *pixel = AovPixel::default();
+        }
+    }
+}
+
+#[test]
+fn test_aov_buffer() {
+    let (width, height) = (4, 2);
+    let mut buffer = AovBuffer::new(width, height);
+    // the left half hits object 3 with material 1, the right half only in odd samples
+    let sampler = |_row: usize, col: usize, sample: usize| {
+        let hit = col < 2 || sample % 2 == 1;
+        let aov = if hit {
+            AovSample {
+                albedo: Vec3R::new(0.5, 0.25, 1.0),
+                normal: Vec3R::new(0.0, 0.0, 1.0),
+                position: Point3R::new(col as Real, 1.0, -2.0),
+                depth: 2.0 + sample as Real,
+                object: Some(3),
+                material: Some(1),
+                bounces: 2,
+            }
+        } else {
+            AovSample::default()
+        };
+        ((0.5, 0.5, 0.5), aov)
+    };
+    assert!(buffer.keeps_aovs());
+    for _ in 0..4 {
+        buffer.sample_pixels_with_aovs(sampler);
+    }
+    assert_eq!(buffer.to_linear()[0], (0.5, 0.5, 0.5));
+    assert_eq!(buffer.aov(Aov::Albedo)[0], (0.5, 0.25, 1.0));
+    assert_eq!(buffer.aov(Aov::Albedo)[3], (0.25, 0.125, 0.5));
+    assert_eq!(buffer.aov(Aov::Normal)[3], (0.0, 0.0, 1.0));
+    assert_eq!(buffer.aov(Aov::Position)[1], (1.0, 1.0, -2.0));
+    // the mean over the samples that hit, 2, 3, 4, 5 and 3, 5
+    assert_eq!(buffer.aov(Aov::Depth)[0].0, 3.5);
+    assert_eq!(buffer.aov(Aov::Depth)[3].0, 4.0);
+    assert_eq!(buffer.aov(Aov::Bounces)[3].0, 1.0);
+    // the first sample of the right half hits the background
+    assert_eq!(buffer.aov(Aov::Object)[0].0, 3.0);
+    assert_eq!(buffer.aov(Aov::Object)[2].0, -1.0);
+    assert_eq!(buffer.aov(Aov::Material)[1].0, 1.0);
+
+    let names = |aov: Aov| -> Vec<String> {
+        buffer
+            .exr_channels(aov)
+            .into_iter()
+            .map(|channel| {
+                assert_eq!(channel.values.len(), width * height);
+                channel.name
+            })
+            .collect()
+    };
+    assert_eq!(names(Aov::Albedo), ["albedo.R", "albedo.G", "albedo.B"]);
+    assert_eq!(names(Aov::Normal), ["normal.X", "normal.Y", "normal.Z"]);
+    assert_eq!(names(Aov::Depth), ["Z"]);
+    assert_eq!(names(Aov::Object), ["object"]);
+
+    for &aov in &Aov::ALL {
+        assert_eq!(aov.name().parse::<Aov>(), Ok(aov));
+        let img = buffer.aov_img(aov);
+        assert_eq!(img.len(), width * height * 4);
+        assert!(img.chunks(4).all(|rgba| rgba[3] == 255));
+    }
+    assert!("beauty".parse::<Aov>().is_err());
+    // the same index has the same color, the background is black
+    let objects = buffer.aov_img(Aov::Object);
+    assert_eq!(objects[0..4], objects[4..8]);
+    assert_ne!(objects[0..3], [0, 0, 0]);
+    assert_eq!(objects[8..12], [0, 0, 0, 255]);
+    let depth = buffer.aov_img(Aov::Depth);
+    assert!(depth[0] > depth[12]);
+
+    buffer.reset();
+    assert_eq!(buffer.aov(Aov::Depth)[0].0, Real::INFINITY);
+    assert_eq!(buffer.aov(Aov::Object)[0].0, -1.0);
+}
@@ -0,0 +1,334 @@
+use super::super::random::SampleRng;
+use super::renderer_buffer::*;
+use rand::Rng;
+use rayon::prelude::*;
+use serde::Deserialize;
+
+/*
+    The renders are linear radiance, the display pipeline turns them into 8 bit
+    sRGB images: the exposure scales the radiance, the tone mapping compresses
+    it into [0, 1], the sRGB transfer function encodes it and the optional
+    dithering hides the banding of the quantization.
+*/
+
+/// How radiance above 1 is brought into the range of the display.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ToneMapping {
+    /// values above 1 are cut, bright areas lose their details
+    #[default]
+    Clamp,
+    /// luminance L becomes L / (1 + L), never reaching white
+    Reinhard,
+    /// Reinhard with the luminance `white` mapped to white
+    ExtendedReinhard {
+        #[serde(default = "ToneMapping::default_white")]
+        white: Real,
+    },
+    /// Narkowicz's fit of the ACES filmic curve, saturated and contrasted
+    Aces,
+    /// AgX-like curve, bright colors fade to white instead of skewing in hue
+    Agx,
+}
+
+impl ToneMapping {
+    pub fn default_white() -> Real {
+        4.0
+    }
+}
+
+impl std::str::FromStr for ToneMapping {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "clamp" => Ok(ToneMapping::Clamp),
+            "reinhard" => Ok(ToneMapping::Reinhard),
+            "extended-reinhard" => Ok(ToneMapping::ExtendedReinhard {
+                white: ToneMapping::default_white(),
+            }),
+            "aces" => Ok(ToneMapping::Aces),
+            "agx" => Ok(ToneMapping::Agx),
+            _ => Err(format!("unknown tone mapping '{}'", name)),
+        }
+    }
+}
+
+/// Settings of the conversion of the linear radiance to displayed colors.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct DisplaySettings {
+    /// exposure value, every unit doubles the brightness
+    #[serde(default)]
+    pub exposure: Real,
+    #[serde(default)]
+    pub tone_mapping: ToneMapping,
+    /// adds noise of one level before the quantization to 8 bits
+    #[serde(default)]
+    pub dither: bool,
+}
+
+/// Rows of the matrices of the AgX curve (Troy Sobotka's AgX, as fitted by
+/// Benjamin Wrensch), into and out of its working space.
+const AGX_INSET: [[Real; 3]; 3] = [
+    [0.842_479_062_253_094, 0.078_433_6, 0.079_223_745_147_764_3],
+    [
+        0.042_328_242_261_012_3,
+        0.878_468_636_469_772,
+        0.079_166_127_460_543_4,
+    ],
+    [0.042_375_654_905_705_1, 0.078_433_6, 0.879_142_973_793_104],
+];
+const AGX_OUTSET: [[Real; 3]; 3] = [
+    [
+        1.196_879_005_120_17,
+        -0.098_020_881_140_136_8,
+        -0.099_029_744_079_720_5,
+    ],
+    [
+        -0.052_896_851_757_456_2,
+        1.151_903_129_904_17,
+        -0.098_961_176_844_843_3,
+    ],
+    [
+        -0.052_971_635_514_443_8,
+        -0.098_043_450_117_124_1,
+        1.151_073_672_641_16,
+    ],
+];
+/// exposure range of the AgX curve, in stops around middle grey
+const AGX_MIN_EV: Real = -12.473_93;
+const AGX_MAX_EV: Real = 4.026_069;
+
+fn multiply(matrix: &[[Real; 3]; 3], rgb: RgbReal) -> RgbReal {
+    let row = |r: &[Real; 3]| r[0] * rgb.0 + r[1] * rgb.1 + r[2] * rgb.2;
+    (row(&matrix[0]), row(&matrix[1]), row(&matrix[2]))
+}
+
+fn map(rgb: RgbReal, f: impl Fn(Real) -> Real) -> RgbReal {
+    (f(rgb.0), f(rgb.1), f(rgb.2))
+}
+
+/// Scales the color to the new luminance, keeping its hue.
+fn with_luminance(rgb: RgbReal, new_luminance: impl Fn(Real) -> Real) -> RgbReal {
+    let l = luminance(rgb);
+    if l > 0.0 {
+        let scale = new_luminance(l) / l;
+        map(rgb, |x| x * scale)
+    } else {
+        rgb
+    }
+}
+
+fn aces(x: Real) -> Real {
+    // the fit expects the input scaled like this
+    let x = 0.6 * x;
+    (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+}
+
+fn agx(rgb: RgbReal) -> RgbReal {
+    let encoded = map(multiply(&AGX_INSET, rgb), |x| {
+        let ev = x.max(1e-10).log2().clamp(AGX_MIN_EV, AGX_MAX_EV);
+        let x = (ev - AGX_MIN_EV) / (AGX_MAX_EV - AGX_MIN_EV);
+        // polynomial fit of the sigmoid of AgX
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.002_32
+    });
+    // the curve gives display values, back to linear with a 2.2 gamma
+    map(multiply(&AGX_OUTSET, encoded), |x| x.max(0.0).powf(2.2))
+}
+
+/// sRGB transfer function, from linear to encoded values.
+pub fn srgb_encode(x: Real) -> Real {
+    if x <= 0.003_130_8 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+impl DisplaySettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.exposure.is_finite() {
+            return Err("exposure must be a number".to_string());
+        }
+        if let ToneMapping::ExtendedReinhard { white } = self.tone_mapping {
+            if white.is_nan() || white <= 0.0 {
+                return Err("white must be positive".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// Linear color of the radiance on the display, in [0, 1].
+    pub fn tone_map(&self, radiance: RgbReal) -> RgbReal {
+        // not a number, from a bad sample, and colors out of the sRGB gamut
+        // in spectral mode become black
+        let rgb = map(radiance, |x| if x > 0.0 { x } else { 0.0 });
+        let rgb = map(rgb, |x| x * self.exposure.exp2());
+        let rgb = match self.tone_mapping {
+            ToneMapping::Clamp => rgb,
+            ToneMapping::Reinhard => with_luminance(rgb, |l| l / (1.0 + l)),
+            ToneMapping::ExtendedReinhard { white } => {
+                with_luminance(rgb, |l| l * (1.0 + l / (white * white)) / (1.0 + l))
+            }
+            ToneMapping::Aces => map(rgb, aces),
+            ToneMapping::Agx => agx(rgb),
+        };
+        map(rgb, |x| x.min(1.0))
+    }
+
+    /// 8 bit sRGB color of the radiance of the pixel at `row` and `col`.
+    pub fn to_rgba(&self, radiance: RgbReal, row: usize, col: usize) -> RgbaU8 {
+        let mut rng = if self.dither {
+            // the same noise for the same pixel, successive images don't flicker
+            Some(SampleRng::new(0, row, col, 0))
+        } else {
+            None
+        };
+        let mut quantize = |x: Real| {
+            let noise = match &mut rng {
+                // triangular distribution, one level wide on each side
+                Some(rng) => rng.gen::<Real>() - rng.gen::<Real>(),
+                None => 0.0,
+            };
+            (255.0 * srgb_encode(x) + noise).round().clamp(0.0, 255.0) as u8
+        };
+        let rgb = self.tone_map(radiance);
+        (quantize(rgb.0), quantize(rgb.1), quantize(rgb.2), 255)
+    }
+
+    /// RGBA image of the radiance of the pixels, row major.
+    pub fn to_img(&self, pixels: &[RgbReal], width: usize) -> Vec<u8> {
+        let mut img = new_rgbau8_vec(pixels.len(), 1);
+        img.as_mut_slice()
+            .par_iter_mut()
+            .zip(pixels.par_iter())
+            .enumerate()
+            .for_each(|(index, (rgba, radiance))| {
+                *rgba = self.to_rgba(*radiance, index / width, index % width);
+            });
+        rgbau8_vec_to_u8_vec(img)
+    }
+}
+
+#[test]
+fn test_tone_mapping() {
+    let operators = [
+        ToneMapping::Clamp,
+        ToneMapping::Reinhard,
+        ToneMapping::ExtendedReinhard { white: 4.0 },
+        ToneMapping::Aces,
+        ToneMapping::Agx,
+    ];
+    for &tone_mapping in &operators {
+        let display = DisplaySettings {
+            tone_mapping,
+            ..Default::default()
+        };
+        // black stays black, the curves grow and stay in [0, 1]
+        let black = display.tone_map((0.0, 0.0, 0.0));
+        assert!(
+            black.0 < 1e-3 && black.1 < 1e-3 && black.2 < 1e-3,
+            "{:?}",
+            tone_mapping
+        );
+        let mut previous = -1.0;
+        for i in 0..200 {
+            let x = (i as Real / 10.0 - 10.0).exp2();
+            let y = display.tone_map((x, x, x));
+            assert!(y.0 >= previous - 1e-9, "{:?} {}", tone_mapping, x);
+            assert!(y.0 >= 0.0 && y.0 <= 1.0);
+            previous = y.0;
+        }
+        // middle grey stays in the middle
+        let grey = display.tone_map((0.18, 0.18, 0.18));
+        assert!(
+            grey.1 > 0.1 && grey.1 < 0.25,
+            "{:?} {:?}",
+            tone_mapping,
+            grey
+        );
+        // very bright white saturates, except with plain Reinhard
+        if tone_mapping != ToneMapping::Reinhard {
+            assert!(
+                display.tone_map((1e3, 1e3, 1e3)).1 > 0.97,
+                "{:?}",
+                tone_mapping
+            );
+        }
+    }
+    let extended = DisplaySettings {
+        tone_mapping: ToneMapping::ExtendedReinhard { white: 4.0 },
+        ..Default::default()
+    };
+    assert!((extended.tone_map((4.0, 4.0, 4.0)).0 - 1.0).abs() < 1e-9);
+    // Reinhard keeps the hue of the colors below white
+    let reinhard = DisplaySettings {
+        tone_mapping: ToneMapping::Reinhard,
+        ..Default::default()
+    };
+    let color = reinhard.tone_map((0.8, 0.4, 0.2));
+    assert!((color.0 / color.1 - 2.0).abs() < 1e-9 && (color.1 / color.2 - 2.0).abs() < 1e-9);
+
+    // each stop of exposure doubles the radiance
+    let display = DisplaySettings {
+        exposure: 2.0,
+        ..Default::default()
+    };
+    assert_eq!(display.tone_map((0.1, 0.2, 0.0)), (0.4, 0.8, 0.0));
+    let nan = display.tone_map((Real::NAN, -1.0, 10.0));
+    assert_eq!(nan, (0.0, 0.0, 1.0));
+
+    assert!("extended-reinhard".parse::<ToneMapping>().is_ok());
+    assert!("filmic".parse::<ToneMapping>().is_err());
+    let settings: DisplaySettings = serde_json::from_str(
+        r#"{ "exposure": -1, "tone_mapping": { "type": "extended-reinhard", "white": 2 } }"#,
+    )
+    .unwrap();
+    assert_eq!(
+        settings.tone_mapping,
+        ToneMapping::ExtendedReinhard { white: 2.0 }
+    );
+    assert!(!settings.dither);
+    assert!(DisplaySettings {
+        tone_mapping: ToneMapping::ExtendedReinhard { white: 0.0 },
+        ..Default::default()
+    }
+    .validate()
+    .is_err());
+}
+
+#[test]
+fn test_display_encoding() {
+    // sRGB transfer function, continuous where its two pieces meet
+    assert_eq!(srgb_encode(0.0), 0.0);
+    assert!((srgb_encode(1.0) - 1.0).abs() < 1e-12);
+    assert!((srgb_encode(0.18) - 0.461_356).abs() < 1e-5);
+    assert!((srgb_encode(0.003_130_8) - srgb_encode(0.003_130_9)).abs() < 1e-5);
+
+    let display = DisplaySettings::default();
+    assert_eq!(display.to_rgba((0.0, 1.0, 5.0), 0, 0), (0, 255, 255, 255));
+    assert_eq!(display.to_rgba((0.18, 0.18, 0.18), 0, 0).0, 118);
+
+    // dithering moves the levels by at most one, keeping their average
+    let dithered = DisplaySettings {
+        dither: true,
+        ..Default::default()
+    };
+    let width = 64;
+    let pixels = vec![(0.18, 0.18, 0.18); width * width];
+    let image = dithered.to_img(&pixels, width);
+    assert_eq!(image.len(), width * width * 4);
+    assert_eq!(image, dithered.to_img(&pixels, width));
+    let level = 255.0 * srgb_encode(0.18);
+    let mut sum = 0.0;
+    for rgba in image.chunks(4) {
+        assert!((rgba[0] as Real - level).abs() <= 1.5);
+        assert_eq!(rgba[3], 255);
+        sum += rgba[0] as Real;
+    }
+    assert!((sum / (width * width) as Real - level).abs() < 0.05);
+    assert!(image.chunks(4).any(|rgba| rgba[0] != 118));
+}
@@ -0,0 +1,372 @@
+use super::renderer_buffer::*;
+use std::path::Path;
+
+/*
+    Linear radiance written without clamping nor gamma, for grading and
+    compositing the renders in other programs.
+    OpenEXR files are written uncompressed, one scanline per block, PFM files
+    are three 32 bit floats per pixel, little endian.
+*/
+
+/// Type of the values of the channels of an OpenEXR file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExrPrecision {
+    /// 16 bit floats, half the size with about three significant digits
+    Half,
+    /// 32 bit floats
+    Float,
+}
+
+/// Named channel of an OpenEXR file, with a value per pixel, row major.
+#[derive(Debug, Clone)]
+pub struct ExrChannel {
+    pub name: String,
+    pub values: Vec<Real>,
+}
+
+impl ExrChannel {
+    /// The R, G and B channels of `pixels`, prefixed by the layer name if any,
+    /// like "albedo.R".
+    pub fn rgb(layer: Option<&str>, pixels: &[RgbReal]) -> Vec<ExrChannel> {
+        let name = |channel: &str| match layer {
+            Some(layer) => format!("{}.{}", layer, channel),
+            None => channel.to_string(),
+        };
+        vec![
+            ExrChannel {
+                name: name("R"),
+                values: pixels.iter().map(|rgb| rgb.0).collect(),
+            },
+            ExrChannel {
+                name: name("G"),
+                values: pixels.iter().map(|rgb| rgb.1).collect(),
+            },
+            ExrChannel {
+                name: name("B"),
+                values: pixels.iter().map(|rgb| rgb.2).collect(),
+            },
+        ]
+    }
+}
+
+/// Nearest 16 bit float, ties to even, too large values become infinite.
+fn to_half(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+    if exponent == 0xff {
+        // infinite or nan
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+    let round = |value: u32, rest: u32, halfway: u32| {
+        if rest > halfway || (rest == halfway && value & 1 == 1) {
+            value + 1
+        } else {
+            value
+        }
+    };
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        sign | 0x7c00
+    } else if exponent <= 0 {
+        // subnormal, the implicit bit becomes explicit
+        if exponent < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        let rest = mantissa & ((1 << shift) - 1);
+        sign | round(mantissa >> shift, rest, 1 << (shift - 1)) as u16
+    } else {
+        // a carry of the rounding goes to the exponent, up to infinite
+        let half = ((exponent as u32) << 10) | (mantissa >> 13);
+        sign | round(half, mantissa & 0x1fff, 0x1000) as u16
+    }
+}
+
+fn exr_attribute(out: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    out.extend_from_slice(kind.as_bytes());
+    out.push(0);
+    out.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    out.extend_from_slice(value);
+}
+
+fn exr_box(width: usize, height: usize) -> Vec<u8> {
+    [0, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|v| v.to_le_bytes().to_vec())
+        .collect()
+}
+
+/// OpenEXR file of the channels, which are sorted by name as the format requires.
+pub fn encode_exr(
+    width: usize,
+    height: usize,
+    channels: &[ExrChannel],
+    precision: ExrPrecision,
+) -> Vec<u8> {
+    let mut channels: Vec<&ExrChannel> = channels.iter().collect();
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+    for channel in &channels {
+        assert_eq!(channel.values.len(), width * height, "{}", channel.name);
+    }
+    let (pixel_type, value_size) = match precision {
+        ExrPrecision::Half => (1i32, 2),
+        ExrPrecision::Float => (2i32, 4),
+    };
+
+    let mut out = Vec::new();
+    // magic number and version 2, single part scanline file
+    out.extend_from_slice(&20_000_630i32.to_le_bytes());
+    out.extend_from_slice(&2i32.to_le_bytes());
+
+    let mut channel_list = Vec::new();
+    for channel in &channels {
+        channel_list.extend_from_slice(channel.name.as_bytes());
+        channel_list.push(0);
+        channel_list.extend_from_slice(&pixel_type.to_le_bytes());
+        // perceptually linear and reserved bytes, then x and y sampling
+        channel_list.extend_from_slice(&[0, 0, 0, 0]);
+        channel_list.extend_from_slice(&1i32.to_le_bytes());
+        channel_list.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channel_list.push(0);
+    exr_attribute(&mut out, "channels", "chlist", &channel_list);
+    // no compression
+    exr_attribute(&mut out, "compression", "compression", &[0]);
+    exr_attribute(&mut out, "dataWindow", "box2i", &exr_box(width, height));
+    exr_attribute(&mut out, "displayWindow", "box2i", &exr_box(width, height));
+    // increasing y
+    exr_attribute(&mut out, "lineOrder", "lineOrder", &[0]);
+    exr_attribute(&mut out, "pixelAspectRatio", "float", &1f32.to_le_bytes());
+    exr_attribute(&mut out, "screenWindowCenter", "v2f", &[0; 8]);
+    exr_attribute(&mut out, "screenWindowWidth", "float", &1f32.to_le_bytes());
+    out.push(0);
+
+    let block_data_size = width * channels.len() * value_size;
+    let first_block = out.len() + 8 * height;
+    for y in 0..height {
+        let offset = first_block + y * (8 + block_data_size);
+        out.extend_from_slice(&(offset as u64).to_le_bytes());
+    }
+    for y in 0..height {
+        out.extend_from_slice(&(y as i32).to_le_bytes());
+        out.extend_from_slice(&(block_data_size as i32).to_le_bytes());
+        for channel in &channels {
+            for &value in &channel.values[y * width..(y + 1) * width] {
+                match precision {
+                    ExrPrecision::Half => {
+                        out.extend_from_slice(&to_half(value as f32).to_le_bytes())
+                    }
+                    ExrPrecision::Float => out.extend_from_slice(&(value as f32).to_le_bytes()),
+                }
+            }
+        }
+    }
+    out
+}
+
+/// PFM file of the pixels, the rows go from the bottom to the top of the image.
+pub fn encode_pfm(width: usize, height: usize, pixels: &[RgbReal]) -> Vec<u8> {
+    assert_eq!(pixels.len(), width * height);
+    // a negative scale means little endian
+    let mut out = format!("PF\n{} {}\n-1.0\n", width, height).into_bytes();
+    for row in pixels.chunks(width.max(1)).rev() {
+        for rgb in row {
+            for &value in &[rgb.0, rgb.1, rgb.2] {
+                out.extend_from_slice(&(value as f32).to_le_bytes());
+            }
+        }
+    }
+    out
+}
+
+fn write_file(path: &Path, data: &[u8]) -> Result<(), String> {
+    std::fs::write(path, data).map_err(|err| format!("cannot write '{}': {}", path.display(), err))
+}
+
+pub fn save_exr(
+    path: &Path,
+    width: usize,
+    height: usize,
+    channels: &[ExrChannel],
+    precision: ExrPrecision,
+) -> Result<(), String> {
+    write_file(path, &encode_exr(width, height, channels, precision))
+}
+
+pub fn save_pfm(
+    path: &Path,
+    width: usize,
+    height: usize,
+    pixels: &[RgbReal],
+) -> Result<(), String> {
+    write_file(path, &encode_pfm(width, height, pixels))
+}
+
+#[test]
+fn test_to_half() {
+    let cases: &[(f32, u16)] = &[
+        (0.0, 0x0000),
+        (-0.0, 0x8000),
+        (1.0, 0x3c00),
+        (0.5, 0x3800),
+        (-2.0, 0xc000),
+        (0.1, 0x2e66),
+        (1.0 / 3.0, 0x3555),
+        (65504.0, 0x7bff),
+        (65520.0, 0x7c00),
+        (1e6, 0x7c00),
+        (f32::INFINITY, 0x7c00),
+        (f32::NEG_INFINITY, 0xfc00),
+        // smallest normal and subnormals
+        (6.103_515_6e-5, 0x0400),
+        (5.960_464_5e-8, 0x0001),
+        (2.0e-8, 0x0000),
+        (3.0e-8, 0x0001),
+        (1.0e-10, 0x0000),
+        // ties to even
+        (1.0 + 1.0 / 2048.0, 0x3c00),
+        (1.0 + 3.0 / 2048.0, 0x3c02),
+    ];
+    for &(value, half) in cases {
+        assert_eq!(to_half(value), half, "{}", value);
+    }
+    assert_eq!(to_half(f32::NAN) & 0x7c00, 0x7c00);
+    assert_ne!(to_half(f32::NAN) & 0x3ff, 0);
+}
+
+#[test]
+fn test_encode_hdr_images() {
+    use std::collections::HashMap;
+    fn from_half(half: u16) -> f32 {
+        let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+        let exponent = ((half >> 10) & 0x1f) as i32;
+        let mantissa = (half & 0x3ff) as f32;
+        sign * if exponent == 0 {
+            mantissa * (2f32).powi(-24)
+        } else {
+            (1.0 + mantissa / 1024.0) * (2f32).powi(exponent - 15)
+        }
+    }
+    let i32_at = |data: &[u8], at: usize| {
+        i32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+    };
+    let c_str = |data: &[u8], at: usize| {
+        let end = at + data[at..].iter().position(|b| *b == 0).unwrap();
+        (String::from_utf8(data[at..end].to_vec()).unwrap(), end + 1)
+    };
+
+    let (width, height) = (3, 2);
+    let pixels: Vec<RgbReal> = (0..width * height)
+        .map(|i| (i as Real * 10.0, 0.25, -(i as Real) / 8.0))
+        .collect();
+    let mut channels = ExrChannel::rgb(None, &pixels);
+    channels.push(ExrChannel {
+        name: "Z".to_string(),
+        values: (0..width * height).map(|i| 100.0 + i as Real).collect(),
+    });
+
+    for &precision in &[ExrPrecision::Half, ExrPrecision::Float] {
+        let data = encode_exr(width, height, &channels, precision);
+        assert_eq!(i32_at(&data, 0), 20_000_630);
+        assert_eq!(i32_at(&data, 4), 2);
+        let mut attributes = HashMap::new();
+        let mut at = 8;
+        loop {
+            let (name, next) = c_str(&data, at);
+            if name.is_empty() {
+                at = next;
+                break;
+            }
+            let (kind, next) = c_str(&data, next);
+            let size = i32_at(&data, next) as usize;
+            attributes.insert(name, (kind, data[next + 4..next + 4 + size].to_vec()));
+            at = next + 4 + size;
+        }
+        for name in &[
+            "channels",
+            "compression",
+            "dataWindow",
+            "displayWindow",
+            "lineOrder",
+            "pixelAspectRatio",
+            "screenWindowCenter",
+            "screenWindowWidth",
+        ] {
+            assert!(attributes.contains_key(*name), "{}", name);
+        }
+        let (kind, data_window) = &attributes["dataWindow"];
+        assert_eq!(kind, "box2i");
+        assert_eq!(
+            (i32_at(data_window, 8), i32_at(data_window, 12)),
+            (width as i32 - 1, height as i32 - 1)
+        );
+        // the channels are listed alphabetically
+        let channel_list = &attributes["channels"].1;
+        let mut names = Vec::new();
+        let mut list_at = 0;
+        while channel_list[list_at] != 0 {
+            let (name, next) = c_str(channel_list, list_at);
+            let pixel_type = i32_at(channel_list, next);
+            assert_eq!(
+                pixel_type,
+                if precision == ExrPrecision::Half {
+                    1
+                } else {
+                    2
+                }
+            );
+            names.push(name);
+            list_at = next + 16;
+        }
+        assert_eq!(names, ["B", "G", "R", "Z"]);
+
+        let value_size = if precision == ExrPrecision::Half {
+            2
+        } else {
+            4
+        };
+        let value = |at: usize| {
+            if precision == ExrPrecision::Half {
+                from_half(u16::from_le_bytes([data[at], data[at + 1]]))
+            } else {
+                f32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+            }
+        };
+        for y in 0..height {
+            let offset_at = at + 8 * y;
+            let mut offset_bytes = [0u8; 8];
+            offset_bytes.copy_from_slice(&data[offset_at..offset_at + 8]);
+            let offset = u64::from_le_bytes(offset_bytes) as usize;
+            assert_eq!(i32_at(&data, offset), y as i32);
+            assert_eq!(i32_at(&data, offset + 4) as usize, width * 4 * value_size);
+            for x in 0..width {
+                let index = y * width + x;
+                let channel_at = |channel: usize| offset + 8 + (channel * width + x) * value_size;
+                assert_eq!(value(channel_at(0)), pixels[index].2 as f32);
+                assert_eq!(value(channel_at(1)), 0.25);
+                assert_eq!(value(channel_at(2)), pixels[index].0 as f32);
+                assert_eq!(value(channel_at(3)), 100.0 + index as f32);
+            }
+        }
+        let last = at + 8 * height + height * (8 + width * 4 * value_size);
+        assert_eq!(data.len(), last);
+    }
+
+    let data = encode_pfm(width, height, &pixels);
+    let header = b"PF\n3 2\n-1.0\n";
+    assert_eq!(&data[..header.len()], header);
+    assert_eq!(data.len(), header.len() + width * height * 12);
+    let float_at = |at: usize| {
+        let at = header.len() + at * 4;
+        f32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+    };
+    // the bottom row comes first
+    assert_eq!(float_at(0), pixels[3].0 as f32);
+    assert_eq!(float_at(2), pixels[3].2 as f32);
+    assert_eq!(float_at(9), pixels[0].0 as f32);
+}
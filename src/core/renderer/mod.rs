@@ -1,7 +1,15 @@
+mod adaptive_buffer;
+mod aov_buffer;
+mod display;
+mod hdr_image;
 mod partitioned_buffer;
 mod pixel_buffer;
 mod render;
 pub mod renderer_buffer;
+pub use adaptive_buffer::{AdaptiveBuffer, AdaptiveSampling};
+pub use aov_buffer::{Aov, AovBuffer};
+pub use display::{DisplaySettings, ToneMapping};
+pub use hdr_image::*;
 pub use partitioned_buffer::PartitionedBuffer;
 pub use pixel_buffer::PixelBuffer;
 pub use render::*;
@@ -1,30 +1,44 @@
+use super::super::random::SampleRng;
+use super::display::DisplaySettings;
 use super::renderer_buffer::*;
 use rand::prelude::*;
 use rayon::prelude::*;
 
 pub struct PartitionedBuffer {
-    pixels: Vec<RgbReal>,
+    /// row major, the last partitions of each row and column may be smaller
     partitions: Vec<Partition>,
-    // active_partitions: Vec<(&'a mut [RgbReal], &'a mut Partition)>,
+    /// number of partitions in a row
+    partitions_per_row: usize,
     partition_width: usize,
     width: usize,
     height: usize,
     min_error: Real,
-    target_time: Real,
+    /// nanoseconds a pass should take, `min_error` is tuned to meet it when set
+    target_time: Option<Real>,
     debug_error: bool,
     total_partitions_processed: u32,
 }
 
 #[derive(Clone)]
 struct Partition {
+    /// position of the top left pixel in the image
+    x: usize,
+    y: usize,
+    width: usize,
+    /// sums of the samples, row major
+    pixels: Vec<RgbReal>,
     samples_count: usize,
     error_sums: RgbReal,
     error: Real,
 }
 
 impl Partition {
-    fn new() -> Partition {
+    fn new(x: usize, y: usize, width: usize, height: usize) -> Partition {
         Partition {
+            x,
+            y,
+            width,
+            pixels: vec![(0.0, 0.0, 0.0); width * height],
             samples_count: 0,
             error_sums: (0.0, 0.0, 0.0),
             error: 0.0,
@@ -32,6 +46,7 @@ impl Partition {
     }
 
     fn clear(&mut self) {
+        self.pixels.zero_memory();
         self.samples_count = 0;
         self.error_sums = (0.0, 0.0, 0.0);
         self.error = 0.0;
@@ -42,26 +57,30 @@ impl PartitionedBuffer {
     const PARTITION_WIDTH: usize = 16;
     #[allow(dead_code)]
     pub fn new(width: usize, height: usize) -> PartitionedBuffer {
-        if width % Self::PARTITION_WIDTH != 0 || height % Self::PARTITION_WIDTH != 0 {
-            panic!(format!(
-                "buffer width or height is not a multiple of {}",
-                Self::PARTITION_WIDTH
-            ));
-        }
-        let partitions_count = (width / Self::PARTITION_WIDTH) * (height / Self::PARTITION_WIDTH);
-        let mut partitions = Vec::with_capacity(partitions_count);
-        for _ in 0..partitions_count {
-            partitions.push(Partition::new());
+        let partition_width = Self::PARTITION_WIDTH;
+        let partitions_per_row = width.div_ceil(partition_width);
+        let partitions_per_column = height.div_ceil(partition_width);
+        let mut partitions = Vec::with_capacity(partitions_per_row * partitions_per_column);
+        for row in 0..partitions_per_column {
+            for col in 0..partitions_per_row {
+                let x = col * partition_width;
+                let y = row * partition_width;
+                partitions.push(Partition::new(
+                    x,
+                    y,
+                    partition_width.min(width - x),
+                    partition_width.min(height - y),
+                ));
+            }
         }
         PartitionedBuffer {
-            pixels: vec![(0.0, 0.0, 0.0); width * height],
             partitions,
-            //active_partitions: (0..partitions_count).collect(),
-            partition_width: Self::PARTITION_WIDTH,
+            partitions_per_row,
+            partition_width,
             width,
             height,
             min_error: 1.0 / (60.0 * 255.0),
-            target_time: std::time::Duration::from_millis(12).as_nanos() as Real,
+            target_time: None,
             debug_error: false,
             total_partitions_processed: 0,
         }
@@ -74,49 +93,78 @@ impl PartitionedBuffer {
     pub fn debug_error(&mut self, v: bool) {
         self.debug_error = v;
     }
+
+    /// Skips more or less partitions so that a pass takes about `target`,
+    /// the image then depends on the speed of the machine and isn't reproducible.
+    pub fn set_target_time(&mut self, target: std::time::Duration) {
+        self.target_time = Some(target.as_nanos() as Real);
+    }
+
+    /// Mean of the samples of each pixel, row major, with the error
+    /// of the partitions in place of the red channel if `debug_error`.
+    fn pixels(&self, debug_error: bool) -> Vec<RgbReal> {
+        let mut pixels = vec![(0.0, 0.0, 0.0); self.width * self.height];
+        pixels
+            .par_chunks_mut(self.width.max(1))
+            .enumerate()
+            .for_each(|(y, row)| {
+                let partitions_row = (y / self.partition_width) * self.partitions_per_row;
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    let partition = &self.partitions[partitions_row + x / self.partition_width];
+                    let sc = partition.samples_count as Real;
+                    if sc == 0.0 {
+                        continue;
+                    }
+                    let sum =
+                        partition.pixels[(y - partition.y) * partition.width + (x - partition.x)];
+                    *pixel = (sum.0 / sc, sum.1 / sc, sum.2 / sc);
+                    if debug_error {
+                        pixel.0 = (300.0 * partition.error).min(1.0);
+                    }
+                }
+            });
+        pixels
+    }
 }
 
 impl RendererBuffer for PartitionedBuffer {
-    fn sample_pixels<'a, F: Fn(usize, usize) -> RgbReal + Send + Sync>(&'a mut self, sampler: F) {
+    fn sample_pixels<F: Fn(usize, usize, usize) -> RgbReal + Send + Sync>(&mut self, sampler: F) {
         //let timer = Timer::new("sample_pixels");
-        let partition_width = self.partition_width;
-        let partions_width = self.width / self.partition_width;
-        let partions_height = self.height / self.partition_width;
-        let partition_size = (partition_width * partition_width) as Real;
-        let error_div = partition_size * 3.0;
         let min_error = self.min_error;
         let sample_start_time = std::time::Instant::now();
         self.total_partitions_processed = self
-            .pixels
-            .par_chunks_mut(self.partition_width * self.partition_width)
-            .zip(self.partitions.par_iter_mut())
+            .partitions
+            .par_iter_mut()
             .enumerate()
             .fold_with(
                 0u32,
-                |total_partitions_processed, (partition_index, (chunk, partition))| {
-                    let mut rng = rand::thread_rng();
+                |total_partitions_processed, (partition_index, partition)| {
+                    // the choice of the partitions to sample doesn't depend on the scene
+                    let mut rng = SampleRng::new(0, partition_index, 0, partition.samples_count);
                     if partition.samples_count <= 10
                         || partition.error > rng.gen::<Real>() * min_error
                     {
-                        let buffer_x = (partition_index % partions_width) * partition_width;
-                        let buffer_y = (partition_index / partions_height) * partition_width;
+                        let sample_index = partition.samples_count;
                         partition.samples_count += 1;
                         let samples = partition.samples_count as Real;
-                        for (local_index, color_sum) in chunk.into_iter().enumerate() {
-                            let px = local_index % partition_width;
-                            let py = local_index / partition_width;
-                            let sampled_color = sampler(buffer_y + py, buffer_x + px);
+                        let error_div = (partition.pixels.len() * 3) as Real;
+                        let mut error_sums = partition.error_sums;
+                        for (local_index, color_sum) in partition.pixels.iter_mut().enumerate() {
+                            let px = local_index % partition.width;
+                            let py = local_index / partition.width;
+                            let sampled_color =
+                                sampler(partition.y + py, partition.x + px, sample_index);
                             color_sum.0 += sampled_color.0;
                             color_sum.1 += sampled_color.1;
                             color_sum.2 += sampled_color.2;
-                            partition.error_sums.0 += color_sum.0 / samples - sampled_color.0;
-                            partition.error_sums.1 += color_sum.1 / samples - sampled_color.1;
-                            partition.error_sums.2 += color_sum.2 / samples - sampled_color.2;
+                            error_sums.0 += color_sum.0 / samples - sampled_color.0;
+                            error_sums.1 += color_sum.1 / samples - sampled_color.1;
+                            error_sums.2 += color_sum.2 / samples - sampled_color.2;
                         }
-                        partition.error = (partition.error_sums.0.abs()
-                            + partition.error_sums.1.abs()
-                            + partition.error_sums.2.abs())
-                            / (samples * error_div);
+                        partition.error_sums = error_sums;
+                        partition.error =
+                            (error_sums.0.abs() + error_sums.1.abs() + error_sums.2.abs())
+                                / (samples * error_div);
                         total_partitions_processed + 1
                     } else {
                         partition.error *= 1.02;
@@ -125,48 +173,21 @@ impl RendererBuffer for PartitionedBuffer {
                 },
             )
             .sum::<u32>();
-        if let Some(partition) = self.partitions.first() {
+        if let (Some(partition), Some(target_time)) = (self.partitions.first(), self.target_time) {
             if partition.samples_count > 10 {
-                let time_error = sample_start_time.elapsed().as_nanos() as Real / self.target_time;
+                let time_error = sample_start_time.elapsed().as_nanos() as Real / target_time;
                 self.min_error = self.min_error * time_error * 0.2 + self.min_error * 0.8;
             }
         }
         //timer.log();
     }
 
-    fn to_img(&self) -> Vec<u8> {
-        let partions_width = self.width / self.partition_width;
-        let mut img = new_rgbau8_vec(self.width(), self.height());
-        // split into rows of partitions
-        img.as_mut_slice()
-            .par_chunks_mut(self.width * self.partition_width)
-            .enumerate()
-            .for_each(|(partition_row_index, partition_row)| {
-                for p in 0..partions_width {
-                    let partition_index = partition_row_index * partions_width + p;
-                    let partition = &self.partitions[partition_index];
+    fn to_img(&self, display: &DisplaySettings) -> Vec<u8> {
+        display.to_img(&self.pixels(self.debug_error), self.width)
+    }
 
-                    let buffer_offset =
-                        partition_index * self.partition_width * self.partition_width;
-                    let sc = partition.samples_count as Real;
-                    let img_offset = p * self.partition_width;
-                    for py in 0..self.partition_width {
-                        for px in 0..self.partition_width {
-                            let buffer_color =
-                                self.pixels[buffer_offset + py * self.partition_width + px];
-                            let image_color = &mut partition_row[py * self.width + img_offset + px];
-                            image_color.0 = to_channel(if self.debug_error {
-                                (300.0 * partition.error).min(1.0)
-                            } else {
-                                buffer_color.0 / sc
-                            });
-                            image_color.1 = to_channel(buffer_color.1 / sc);
-                            image_color.2 = to_channel(buffer_color.2 / sc);
-                        }
-                    }
-                }
-            });
-        rgbau8_vec_to_u8_vec(img)
+    fn to_linear(&self) -> Vec<RgbReal> {
+        self.pixels(false)
     }
 
     fn width(&self) -> usize {
@@ -177,9 +198,93 @@ impl RendererBuffer for PartitionedBuffer {
     }
 
     fn reset(&mut self) {
-        self.pixels.zero_memory();
         for partition in &mut self.partitions {
             partition.clear();
         }
     }
 }
+
+#[test]
+fn test_partitioned_buffer_layout() {
+    use super::PixelBuffer;
+    let display = DisplaySettings::default();
+    // a different color for each pixel, with some noise to keep the partitions busy
+    let sampler = |width: usize, height: usize| {
+        move |row: usize, col: usize, sample: usize| {
+            let noise = if sample.is_multiple_of(2) { 0.05 } else { -0.05 };
+            (
+                (col as Real + 0.5) / width as Real,
+                (row as Real + 0.5) / height as Real,
+                0.5 + noise,
+            )
+        }
+    };
+    for &(width, height) in &[(32, 16), (16, 48), (37, 21), (5, 3), (1, 1)] {
+        let mut partitioned = PartitionedBuffer::new(width, height);
+        let mut pixels = PixelBuffer::new(width, height);
+        assert_eq!(
+            partitioned.to_img(&display),
+            pixels.to_img(&display),
+            "{}x{} before sampling",
+            width,
+            height
+        );
+        for _ in 0..2 {
+            partitioned.sample_pixels(sampler(width, height));
+            pixels.sample_pixels(sampler(width, height));
+        }
+        assert_eq!(partitioned.ratio_processed(), 1.0);
+        let image = partitioned.to_img(&display);
+        assert_eq!(image.len(), width * height * 4);
+        assert_eq!(image, pixels.to_img(&display), "{}x{}", width, height);
+        assert_eq!(partitioned.to_linear(), pixels.to_linear());
+
+        // skipped partitions keep their own samples count
+        for _ in 0..40 {
+            partitioned.sample_pixels(sampler(width, height));
+        }
+        let difference = partitioned
+            .to_img(&display)
+            .iter()
+            .zip(pixels.to_img(&display).iter())
+            .map(|(a, b)| (*a as i32 - *b as i32).abs())
+            .max();
+        assert!(
+            difference <= Some(3),
+            "{}x{} {:?}",
+            width,
+            height,
+            difference
+        );
+
+        partitioned.reset();
+        pixels.reset();
+        assert_eq!(partitioned.to_img(&display), pixels.to_img(&display));
+    }
+}
+
+#[test]
+fn test_partitioned_buffer_is_reproducible() {
+    use std::time::Duration;
+    // the noise grows from one partition to the next, so that some of them are
+    // skipped, and the sampling of the last one can be slowed down
+    let render = |delay: Duration| {
+        let mut buffer = PartitionedBuffer::new(64, 16);
+        let mut processed = Vec::new();
+        for _ in 0..40 {
+            buffer.sample_pixels(|row, col, sample| {
+                if row == 0 && col == 48 {
+                    std::thread::sleep(delay);
+                }
+                let sign = if sample.is_multiple_of(2) { 1.0 } else { -1.0 };
+                (0.5 + sign * 2e-3 * (col / 16) as Real, 0.5, 0.5)
+            });
+            processed.push(buffer.ratio_processed());
+        }
+        (buffer.to_linear(), processed)
+    };
+    let (pixels, processed) = render(Duration::from_millis(0));
+    assert!(processed.iter().any(|&ratio| ratio > 0.0 && ratio < 1.0));
+    // slow passes sample the same partitions as fast ones
+    assert_eq!(render(Duration::from_millis(30)), (pixels, processed));
+}
@@ -20,13 +20,14 @@ impl PixelBuffer {
 }
 
 impl RendererBuffer for PixelBuffer {
-    fn sample_pixels<'a, F: Fn(usize, usize) -> RgbReal + Send + Sync>(&'a mut self, sampler: F) {
+    fn sample_pixels<F: Fn(usize, usize, usize) -> RgbReal + Send + Sync>(&mut self, sampler: F) {
         let w = self.width;
+        let sample_index = self.samples_count;
         self.rbg_summed
             .par_iter_mut()
             .enumerate()
             .for_each(|(pixel_index, rgb)| {
-                let sampled = sampler(pixel_index / w, pixel_index % w);
+                let sampled = sampler(pixel_index / w, pixel_index % w, sample_index);
                 rgb.0 += sampled.0;
                 rgb.1 += sampled.1;
                 rgb.2 += sampled.2;
@@ -34,21 +35,12 @@ impl RendererBuffer for PixelBuffer {
         self.samples_count += 1;
     }
 
-    fn to_img(&self) -> Vec<u8> {
-        let mut img = new_rgbau8_vec(self.width(), self.height());
-        if self.samples_count > 0 {
-            let sc = self.samples_count as Real;
-            img.as_mut_slice()
-                .par_iter_mut()
-                .enumerate()
-                .for_each(|(index, rbga)| {
-                    let rgb_sum = &self.rbg_summed[index];
-                    rbga.0 = to_channel(rgb_sum.0 / sc);
-                    rbga.1 = to_channel(rgb_sum.1 / sc);
-                    rbga.2 = to_channel(rgb_sum.2 / sc);
-                });
-        }
-        rgbau8_vec_to_u8_vec(img)
+    fn to_linear(&self) -> Vec<RgbReal> {
+        let sc = self.samples_count.max(1) as Real;
+        self.rbg_summed
+            .par_iter()
+            .map(|rgb_sum| (rgb_sum.0 / sc, rgb_sum.1 / sc, rgb_sum.2 / sc))
+            .collect()
     }
 
     fn width(&self) -> usize {
@@ -1,66 +1,307 @@
+use super::super::material::Material;
+use super::super::medium::{scattering_hit, Flight, Medium};
+use super::super::object::Object;
 use super::super::primitives::*;
+use super::super::random::SampleRng;
 use super::super::scene::*;
+use super::super::spectrum::Wavelengths;
+use super::aov_buffer::AovSample;
 use super::renderer_buffer::*;
 use rand::prelude::*;
+use rand::RngCore;
 
-fn background_color(ray: &Ray) -> Vec3R {
-    let top_color = Vec3R::new(0.3, 0.5, 1.0);
-    let bottom_color = Vec3R::new(1.0, 1.0, 1.0);
-    let k = (ray.direction.vec().y + 1.0) * 0.5;
-    let color = bottom_color.lerp(&top_color, k);
-    debug_assert!(color.max_component() <= 1.0);
-    ray.color * color
+fn background_color(ray: &Ray, scene: &Scene) -> Vec3R {
+    ray.color * ray.color_of(&scene.background.color(&ray.direction))
 }
 
 const MIN_HIT_DISTANCE: Real = 10000.0 * Real::EPSILON;
 
-fn bounce_ray(ray: &Ray, scene: &Scene) -> Option<Ray> {
-    let mut max_time = Real::INFINITY;
-    let mut maybe_object = None;
-    for object in scene.objects_iter() {
-        let time = object.geometry.intersect(&ray, MIN_HIT_DISTANCE, max_time);
-        if time < max_time {
-            maybe_object = Some(object);
-            max_time = time;
+/// Weight of the sampling strategy with pdf `a`, combined with the one with pdf `b`,
+/// using the power heuristic with exponent 2.
+fn power_heuristic(a: Real, b: Real) -> Real {
+    let a2 = a * a;
+    let b2 = b * b;
+    if a2 + b2 > 0.0 {
+        a2 / (a2 + b2)
+    } else {
+        0.0
+    }
+}
+
+/// Medium a ray leaving the surface of `object` towards `direction` travels in,
+/// the one inside the object when it goes in, the one around the objects otherwise.
+fn medium_towards<'a>(
+    object: &Object<'a, 'a>,
+    hit: &Hit,
+    direction: &Unit3R,
+    scene: &'a Scene,
+) -> Option<&'a Medium> {
+    // the normal faces the incoming ray
+    let goes_in = direction.vec().dot(hit.normal.vec()) < 0.0;
+    if goes_in == hit.is_front_face {
+        object.medium
+    } else {
+        scene.medium()
+    }
+}
+
+/// Fraction of the light that reaches the ray origin from `distance` along the ray,
+/// zero if a surface is in the way. Interfaces let the light through, into the media they bound.
+fn transmittance<'a>(
+    ray: &Ray,
+    medium: Option<&'a Medium>,
+    distance: Real,
+    scene: &'a Scene,
+) -> Vec3R {
+    let mut transmittance = Vec3R::new(1.0, 1.0, 1.0);
+    let mut medium = medium;
+    let mut start = 0.0;
+    loop {
+        let closest = scene.closest_hit(ray, start + MIN_HIT_DISTANCE, distance);
+        if let Some(medium) = medium {
+            let end = closest.as_ref().map_or(distance, |(_, time)| *time);
+            transmittance *= medium.transmittance(ray, end - start);
+        }
+        match closest {
+            Some((object, time)) if object.material.is_interface() => {
+                let hit = object.geometry.hit(ray, time);
+                medium = medium_towards(&object, &hit, &ray.direction, scene);
+                start = time;
+            }
+            Some(_) => return Vec3R::default(),
+            None => return transmittance,
+        }
+    }
+}
+
+/// Next event estimation: light reaching the hit point directly from a random point
+/// on a random light, weighted against the chance of the material bouncing there.
+/// `medium_towards` gives the medium that shadow rays leaving in a direction travel in.
+fn sample_light<'a>(
+    ray: &Ray,
+    hit: &Hit,
+    material: &dyn Material,
+    scene: &'a Scene,
+    medium_towards: &dyn Fn(&Unit3R) -> Option<&'a Medium>,
+    rng: &mut dyn RngCore,
+) -> Vec3R {
+    match scene.random_light(rng) {
+        Some(Light::Object(light)) => {
+            sample_object_light(ray, hit, material, &light, scene, medium_towards, rng)
+        }
+        Some(Light::Background) => {
+            sample_background_light(ray, hit, material, scene, medium_towards, rng)
         }
+        None => Vec3R::default(),
+    }
+}
+
+fn sample_object_light<'a>(
+    ray: &Ray,
+    hit: &Hit,
+    material: &dyn Material,
+    light: &Object,
+    scene: &'a Scene,
+    medium_towards: &dyn Fn(&Unit3R) -> Option<&'a Medium>,
+    rng: &mut dyn RngCore,
+) -> Vec3R {
+    let point = match light.geometry.sample_point(rng) {
+        Some(point) => point,
+        None => return Vec3R::default(),
+    };
+    let to_light = point - hit.point;
+    let distance = to_light.length();
+    if distance <= MIN_HIT_DISTANCE {
+        return Vec3R::default();
     }
-    if let Some(object) = maybe_object {
-        let hit = object.geometry.hit(ray, max_time);
-        Some(object.material.bounce(ray, &hit))
+    let mut shadow_ray = Ray::new(hit.point, to_light.unit());
+    shadow_ray.wavelengths = ray.wavelengths;
+    let (bsdf, bsdf_pdf) = material.eval(ray, hit, &shadow_ray.direction);
+    if bsdf.max_component() <= 0.0 {
+        return Vec3R::default();
+    }
+    // stop just before the light so that it doesn't occlude itself
+    let t_max = distance * (1.0 - 1e-7);
+    let medium = medium_towards(&shadow_ray.direction);
+    let transmittance = transmittance(&shadow_ray, medium, t_max, scene);
+    if transmittance.max_component() <= 0.0 {
+        return Vec3R::default();
+    }
+    let light_hit = light.geometry.hit(&shadow_ray, distance);
+    let light_pdf = scene.light_pdf(light, &shadow_ray, &light_hit, distance);
+    if light_pdf > 0.0 {
+        let emitted = light.material.emitted(&shadow_ray, &light_hit) * transmittance;
+        ray.color * bsdf * emitted * (power_heuristic(light_pdf, bsdf_pdf) / light_pdf)
     } else {
-        None
+        Vec3R::default()
     }
 }
 
-fn ray_color(ray: &Ray, scene: &Scene, bounces: usize) -> Vec3R {
-    debug_assert!(ray.color.max_component() <= 1.0);
+fn sample_background_light<'a>(
+    ray: &Ray,
+    hit: &Hit,
+    material: &dyn Material,
+    scene: &'a Scene,
+    medium_towards: &dyn Fn(&Unit3R) -> Option<&'a Medium>,
+    rng: &mut dyn RngCore,
+) -> Vec3R {
+    let direction = match scene.background.sample(rng) {
+        Some((direction, _)) => direction,
+        None => return Vec3R::default(),
+    };
+    let (bsdf, bsdf_pdf) = material.eval(ray, hit, &direction);
+    if bsdf.max_component() <= 0.0 {
+        return Vec3R::default();
+    }
+    let mut shadow_ray = Ray::new(hit.point, direction);
+    shadow_ray.wavelengths = ray.wavelengths;
+    let medium = medium_towards(&direction);
+    let transmittance = transmittance(&shadow_ray, medium, Real::INFINITY, scene);
+    if transmittance.max_component() <= 0.0 {
+        return Vec3R::default();
+    }
+    let light_pdf = scene.background_light_pdf(&direction);
+    if light_pdf > 0.0 {
+        let emitted = ray.color_of(&scene.background.color(&direction)) * transmittance;
+        ray.color * bsdf * emitted * (power_heuristic(light_pdf, bsdf_pdf) / light_pdf)
+    } else {
+        Vec3R::default()
+    }
+}
+
+/// `bounce_pdf` is the pdf of the bounce that generated the ray,
+/// None for camera rays and specular bounces, which can't be sampled by lights.
+/// `medium` is the one the ray travels in.
+/// `path_bounces` is set to the bounces of the path, the last ray included.
+fn ray_color<'a>(
+    ray: &Ray,
+    scene: &'a Scene,
+    bounces: usize,
+    bounce_pdf: Option<Real>,
+    medium: Option<&'a Medium>,
+    path_bounces: &mut usize,
+    rng: &mut dyn RngCore,
+) -> Vec3R {
+    *path_bounces = bounces - 1;
     if bounces > scene.max_bounces {
         return Vec3R::default();
     }
-    if let Some(bounced_ray) = bounce_ray(ray, scene) {
-        if bounced_ray.color.max_component() < 0.1 / 256.0 {
-            bounced_ray.color
-        } else {
-            ray_color(&bounced_ray, scene, bounces + 1)
+    let mut ray = ray.clone();
+    let mut medium = medium;
+    // the ray goes on through interfaces, starting again from the last one,
+    // so that materials see where it crossed the previous surface
+    let mut traveled = 0.0;
+    loop {
+        let closest = scene.closest_hit(&ray, MIN_HIT_DISTANCE, Real::INFINITY);
+        if let Some(medium) = medium {
+            let end = closest.as_ref().map_or(Real::INFINITY, |(_, time)| *time);
+            match medium.sample(&ray, end, rng) {
+                Flight::Scatter { distance, weight } => {
+                    ray.color *= weight;
+                    let hit = scattering_hit(&ray, distance);
+                    let color = sample_light(&ray, &hit, medium, scene, &|_| Some(medium), rng);
+                    let bounce = medium.bounce(&ray, &hit, rng);
+                    return if bounce.ray.color.max_component() < 0.1 / 256.0 {
+                        color
+                    } else {
+                        color
+                            + ray_color(
+                                &bounce.ray,
+                                scene,
+                                bounces + 1,
+                                bounce.pdf,
+                                Some(medium),
+                                path_bounces,
+                                rng,
+                            )
+                    };
+                }
+                Flight::Pass { weight } => ray.color *= weight,
+            }
+        }
+        match closest {
+            Some((object, time)) if object.material.is_interface() => {
+                let hit = object.geometry.hit(&ray, time);
+                medium = medium_towards(&object, &hit, &ray.direction, scene);
+                ray.origin = hit.point;
+                traveled += time;
+            }
+            Some((object, time)) => {
+                let ray = &ray;
+                let hit = object.geometry.hit(ray, time);
+                let mut color = ray.color * object.material.emitted(ray, &hit);
+                if let Some(bsdf_pdf) = bounce_pdf {
+                    // the same light is also reached by sample_light from the previous hit
+                    let light_pdf = scene.light_pdf(&object, ray, &hit, traveled + time);
+                    color *= power_heuristic(bsdf_pdf, light_pdf);
+                }
+                // also after specular bounces, the material may have non specular lobes too
+                let towards = |direction: &Unit3R| medium_towards(&object, &hit, direction, scene);
+                color += sample_light(ray, &hit, object.material, scene, &towards, rng);
+                let mut bounce = object.material.bounce(ray, &hit, rng);
+                if bounce.ray.wavelengths.is_none() {
+                    bounce.ray.wavelengths = ray.wavelengths;
+                }
+                return if bounce.ray.color.max_component() < 0.1 / 256.0 {
+                    color + bounce.ray.color
+                } else {
+                    let medium = medium_towards(&object, &hit, &bounce.ray.direction, scene);
+                    color
+                        + ray_color(
+                            &bounce.ray,
+                            scene,
+                            bounces + 1,
+                            bounce.pdf,
+                            medium,
+                            path_bounces,
+                            rng,
+                        )
+                };
+            }
+            None => {
+                let color = background_color(&ray, scene);
+                return match bounce_pdf {
+                    // the background is also reached by sample_light from the previous hit
+                    Some(bsdf_pdf) => {
+                        color
+                            * power_heuristic(bsdf_pdf, scene.background_light_pdf(&ray.direction))
+                    }
+                    None => color,
+                };
+            }
         }
-    } else {
-        background_color(ray)
     }
 }
 
-fn debug_surfaces_bounce_ray(ray: &Ray, scene: &Scene) -> Option<Ray> {
-    let mut max_time = Real::INFINITY;
-    let mut maybe_object = None;
-    for object in scene.objects_iter() {
-        let time = object.geometry.intersect(&ray, MIN_HIT_DISTANCE, max_time);
-        if time < max_time {
-            maybe_object = Some(object);
-            max_time = time;
+/// Output variables of the first surface hit by a camera ray, through interfaces
+/// and media. Draws no random number, the colors are the same with or without them.
+fn first_hit_aov(ray: &Ray, scene: &Scene) -> AovSample {
+    let mut start = 0.0;
+    loop {
+        match scene.closest_hit(ray, start + MIN_HIT_DISTANCE, Real::INFINITY) {
+            Some((object, time)) if object.material.is_interface() => start = time,
+            Some((object, time)) => {
+                let hit = object.geometry.hit(ray, time);
+                let camera = &scene.camera;
+                return AovSample {
+                    albedo: object.material.albedo(ray, &hit),
+                    normal: *hit.normal.vec(),
+                    position: hit.point,
+                    depth: (hit.point - camera.origin).dot(camera.direction().vec()),
+                    object: Some(object.index),
+                    material: Some(object.material_index),
+                    bounces: 0,
+                };
+            }
+            None => return AovSample::default(),
         }
     }
-    if let Some(object) = maybe_object {
-        let hit = object.geometry.hit(ray, max_time);
-        let mut bounced_ray = object.material.bounce(ray, &hit);
+}
+
+fn debug_surfaces_bounce_ray(ray: &Ray, scene: &Scene, rng: &mut dyn RngCore) -> Option<Ray> {
+    if let Some((object, time)) = scene.closest_hit(ray, MIN_HIT_DISTANCE, Real::INFINITY) {
+        let hit = object.geometry.hit(ray, time);
+        let mut bounced_ray = object.material.bounce(ray, &hit, rng).ray;
         if bounced_ray.color.max_component() > 1.0 / 256. {
             bounced_ray.color = if hit.is_front_face {
                 Vec3R::new(0.0, 0.0, 1.0)
@@ -76,13 +317,13 @@ fn debug_surfaces_bounce_ray(ray: &Ray, scene: &Scene) -> Option<Ray> {
     }
 }
 
-fn debug_surfaces(ray: &Ray, scene: &Scene, bounces: usize) -> Vec3R {
+fn debug_surfaces(ray: &Ray, scene: &Scene, bounces: usize, rng: &mut dyn RngCore) -> Vec3R {
     debug_assert!(ray.color.max_component() <= 1.0);
     if bounces > scene.max_bounces {
         return ray.color;
     }
-    if let Some(bounced_ray) = debug_surfaces_bounce_ray(ray, scene) {
-        debug_surfaces(&bounced_ray, scene, bounces + 1)
+    if let Some(bounced_ray) = debug_surfaces_bounce_ray(ray, scene, rng) {
+        debug_surfaces(&bounced_ray, scene, bounces + 1, rng)
     } else {
         Vec3R::new(0.0, 0.0, 0.0)
     }
@@ -95,20 +336,177 @@ pub fn render(scene: &Scene, buffer: &mut impl RendererBuffer) {
 
     let camera = &scene.camera;
 
-    buffer.sample_pixels(|row_index, col_index| {
+    let sample = |row_index: usize,
+                  col_index: usize,
+                  sample_index: usize,
+                  mut aov: Option<&mut AovSample>| {
         let w = col_index as Real / width;
         let h = (scene.height() - 1 - row_index) as Real / height;
-        let mut rng = rand::thread_rng();
+        let mut rng = SampleRng::new(scene.seed, row_index, col_index, sample_index);
         let mut color = Vec3R::default();
         let w = w + rng.gen::<Real>() / width;
         let h = h + rng.gen::<Real>() / height;
-        if scene.debug_surfaces {
-            color += debug_surfaces(&camera.ray_at(w, h), scene, 1);
-        } else {
-            color += ray_color(&camera.ray_at(w, h), scene, 1);
+        if let Some(mut ray) = camera.ray_at(w, h, &mut rng) {
+            let mut path_bounces = 0;
+            if let Some(aov) = &mut aov {
+                **aov = first_hit_aov(&ray, scene);
+            }
+            if scene.debug_surfaces {
+                color += debug_surfaces(&ray, scene, 1, &mut rng);
+            } else if scene.spectral {
+                let wavelengths = Wavelengths::sample(rng.gen());
+                ray.wavelengths = Some(wavelengths);
+                let radiance = ray_color(
+                    &ray,
+                    scene,
+                    1,
+                    None,
+                    scene.medium(),
+                    &mut path_bounces,
+                    &mut rng,
+                );
+                color += wavelengths.spectrum_to_rgb(&radiance);
+            } else {
+                color += ray_color(
+                    &ray,
+                    scene,
+                    1,
+                    None,
+                    scene.medium(),
+                    &mut path_bounces,
+                    &mut rng,
+                );
+            }
+            if let Some(aov) = aov {
+                aov.bounces = path_bounces;
+            }
         }
         (color.x, color.y, color.z)
-    });
+    };
+    if buffer.keeps_aovs() {
+        buffer.sample_pixels_with_aovs(|row_index, col_index, sample_index| {
+            let mut aov = AovSample::default();
+            let color = sample(row_index, col_index, sample_index, Some(&mut aov));
+            (color, aov)
+        });
+    } else {
+        buffer.sample_pixels(|row_index, col_index, sample_index| {
+            sample(row_index, col_index, sample_index, None)
+        });
+    }
 
     //println!("image rendered in {:.3?}", rendering_start.elapsed());
 }
+
+#[test]
+fn test_render_is_reproducible() {
+    use super::PixelBuffer;
+    use std::convert::TryFrom;
+    // lens, area light, background and fog all draw random numbers
+    let json = r#"{
+        "width": 12,
+        "height": 8,
+        "max_bounces": 4,
+        "debug_surfaces": false,
+        "camera": {
+            "origin": { "x": 0, "y": 0, "z": 3 },
+            "rotation": { "x": 0, "y": 0 },
+            "fov": 50,
+            "aperture": 0.1,
+            "focus_distance": 3
+        },
+        "media": { "fog": { "scattering": { "x": 0.2, "y": 0.2, "z": 0.2 } } },
+        "medium": "fog",
+        "materials": {
+            "white": { "type": "diffuse", "albedo": { "x": 0.8, "y": 0.8, "z": 0.8 } },
+            "light": { "type": "emissive", "color": { "x": 1, "y": 1, "z": 1 }, "intensity": 4 }
+        },
+        "geometries": {
+            "ball": { "type": "sphere", "center": { "x": 0, "y": 0, "z": 0 }, "radius": 0.8 },
+            "lamp": { "type": "sphere", "center": { "x": 1, "y": 2, "z": 1 }, "radius": 0.5 }
+        },
+        "objects": [
+            { "geometry": "ball", "material": "white" },
+            { "geometry": "lamp", "material": "light" }
+        ]
+    }"#;
+    let render_with_seed = |seed: u64| {
+        let mut scene = Scene::try_from(json).unwrap();
+        scene.seed = seed;
+        let mut buffer = PixelBuffer::new(scene.width(), scene.height());
+        for _ in 0..4 {
+            scene.render(&mut buffer);
+        }
+        buffer.to_img(&scene.display)
+    };
+    let image = render_with_seed(1);
+    assert!(image.iter().any(|&channel| channel > 0 && channel < 255));
+    assert_eq!(render_with_seed(1), image);
+    assert_ne!(render_with_seed(2), image);
+
+    // the output variables don't change the colors
+    use super::{Aov, AovBuffer};
+    let scene = Scene::try_from(json).unwrap();
+    let mut pixels = PixelBuffer::new(scene.width(), scene.height());
+    let mut aovs = AovBuffer::new(scene.width(), scene.height());
+    for _ in 0..4 {
+        scene.render(&mut pixels);
+        scene.render(&mut aovs);
+    }
+    assert_eq!(aovs.to_linear(), pixels.to_linear());
+    // the ball is in the middle, the materials are sorted by name
+    let (center, corner) = (4 * 12 + 6, 7 * 12);
+    assert_eq!(aovs.aov(Aov::Object)[center].0, 0.0);
+    assert_eq!(aovs.aov(Aov::Material)[center].0, 1.0);
+    assert!((aovs.aov(Aov::Albedo)[center].1 - 0.8).abs() < 1e-9);
+    let depth = aovs.aov(Aov::Depth)[center].0;
+    assert!((depth - 2.2).abs() < 0.1, "{}", depth);
+    assert!(aovs.aov(Aov::Normal)[center].2 > 0.9);
+    assert!(aovs.aov(Aov::Bounces)[center].0 >= 1.0);
+    assert_eq!(aovs.aov(Aov::Object)[corner].0, -1.0);
+    assert_eq!(aovs.aov(Aov::Depth)[corner].0, Real::INFINITY);
+}
+
+#[test]
+fn test_glass_absorption_through_interfaces() {
+    use std::convert::TryFrom;
+    // the glass doesn't bend the light, an interface splits its inside in three
+    let json = r#"{
+        "width": 1,
+        "height": 1,
+        "max_bounces": 4,
+        "debug_surfaces": false,
+        "camera": { "origin": { "x": 0, "y": 0, "z": 5 }, "rotation": { "x": 0, "y": 0 }, "fov": 40 },
+        "background": { "type": "color", "color": { "x": 1, "y": 1, "z": 1 } },
+        "materials": {
+            "glass": {
+                "type": "dieletric",
+                "refraction": 1,
+                "absorption_color": { "x": 0.5, "y": 0.5, "z": 0.5 }
+            },
+            "interface": { "type": "interface" }
+        },
+        "geometries": {
+            "outer": { "type": "sphere", "center": { "x": 0, "y": 0, "z": 0 }, "radius": 2 },
+            "inner": { "type": "sphere", "center": { "x": 0, "y": 0, "z": 0 }, "radius": 1 }
+        },
+        "objects": [
+            { "geometry": "outer", "material": "glass" },
+            { "geometry": "inner", "material": "interface" }
+        ]
+    }"#;
+    let scene = Scene::try_from(json).unwrap();
+    let ray = Ray::new(
+        Point3R::new(0.0, 0.0, 5.0),
+        Unit3R::normalized(Vec3R::new(0.0, 0.0, -1.0)),
+    );
+    let mut path_bounces = 0;
+    let mut rng = SampleRng::new(0, 0, 0, 0);
+    let color = ray_color(&ray, &scene, 1, None, None, &mut path_bounces, &mut rng);
+    // absorbed along the last unit of the path, after the interface
+    assert!(
+        color.distance(&Vec3R::new(0.5, 0.5, 0.5)) < 1e-9,
+        "{:?}",
+        color
+    );
+}
@@ -1,4 +1,7 @@
 pub use super::super::defs::*;
+use super::super::primitives::Vec3R;
+use super::aov_buffer::AovSample;
+use super::display::DisplaySettings;
 pub type RgbReal = (Real, Real, Real);
 pub type RgbaU8 = (u8, u8, u8, u8);
 
@@ -41,22 +44,34 @@ pub fn rgbau8_vec_to_u8_vec(memory: Vec<RgbaU8>) -> Vec<u8> {
     }
 }
 
-pub fn to_channel(mut x: Real) -> u8 {
-    debug_assert!(!x.is_nan());
-    debug_assert!(x <= 1.0);
-    debug_assert!(x >= 0.0);
-    if x.is_nan() || x < 0.0 {
-        x = 0.0;
-    } else if x > 1.0 {
-        x = 1.0;
-    }
-    (255.999 * x.sqrt()) as u8
+/// Relative luminance of a linear sRGB color.
+pub fn luminance(rgb: RgbReal) -> Real {
+    Vec3R::new(rgb.0, rgb.1, rgb.2).luminance()
 }
 
 pub trait RendererBuffer {
-    fn sample_pixels<'a, F: Fn(usize, usize) -> RgbReal + Send + Sync>(&'a mut self, sampler: F);
+    /// Adds a sample to the pixels, `sampler` gets the row, the column
+    /// and the index of the sample of the pixel.
+    fn sample_pixels<F: Fn(usize, usize, usize) -> RgbReal + Send + Sync>(&mut self, sampler: F);
+    /// Whether the buffer keeps the output variables of the samples,
+    /// otherwise they aren't computed.
+    fn keeps_aovs(&self) -> bool {
+        false
+    }
+    /// `sample_pixels` with the output variables of the samples too.
+    fn sample_pixels_with_aovs<F: Fn(usize, usize, usize) -> (RgbReal, AovSample) + Send + Sync>(
+        &mut self,
+        sampler: F,
+    ) {
+        self.sample_pixels(move |row, col, sample| sampler(row, col, sample).0);
+    }
     fn width(&self) -> usize;
     fn height(&self) -> usize;
     fn reset(&mut self);
-    fn to_img(&self) -> Vec<u8>;
+    /// RGBA image of the pixels, through the display pipeline.
+    fn to_img(&self, display: &DisplaySettings) -> Vec<u8> {
+        display.to_img(&self.to_linear(), self.width())
+    }
+    /// Mean of the samples of each pixel, row major, linear and not clamped.
+    fn to_linear(&self) -> Vec<RgbReal>;
 }
@@ -1,25 +1,55 @@
-use super::camera::Camera;
+use super::background::*;
+use super::bvh::Bvh;
+use super::camera::{Camera, DesProjection, Projection};
 use super::defs::Real;
 use super::geometry::*;
 use super::material::*;
+use super::medium::Medium;
 use super::object::Object;
 use super::primitives::*;
 use super::renderer::renderer_buffer::*;
 use super::renderer::*;
+use super::texture::{DesTexture, Texture};
+use super::transform::{DesTransform, Instance};
+use rand::{Rng, RngCore};
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[derive(Deserialize, Debug)]
 struct DesScene {
+    #[serde(default)]
+    textures: HashMap<String, DesTexture>,
     materials: HashMap<String, DesMaterial>,
+    #[serde(default)]
+    media: HashMap<String, Medium>,
+    /// medium filling the space around the objects, like fog
+    #[serde(default)]
+    medium: Option<String>,
     geometries: HashMap<String, GeometryType>,
-    objects: Vec<ObjectEntry<String>>,
+    objects: Vec<DesObject>,
     camera: DesCamera,
+    #[serde(default)]
+    background: Option<DesBackground>,
     width: u16,
     height: u16,
     max_bounces: u8,
     debug_surfaces: bool,
     debug_error: Option<bool>,
+    /// trace wavelengths instead of RGB colors, needed for dispersion
+    #[serde(default)]
+    spectral: bool,
+    /// the same seed renders the same image
+    #[serde(default)]
+    seed: u64,
+    /// how the radiance is turned into the colors of the image
+    #[serde(default)]
+    display: DisplaySettings,
+    /// directory used to resolve the paths of external files
+    #[serde(skip)]
+    base_dir: PathBuf,
 }
 
 #[derive(Deserialize, Debug)]
@@ -27,24 +57,66 @@ struct DesCamera {
     origin: Vec3R,
     rotation: Vec2R,
     fov: Real, // degrees
+    /// diameter of the lens, 0 means everything is in focus
+    #[serde(default)]
+    aperture: Real,
+    #[serde(default = "DesCamera::default_focus_distance")]
+    focus_distance: Real,
+    /// focus on what is seen at the center of the image, overrides `focus_distance`
+    #[serde(default)]
+    autofocus: bool,
+    #[serde(default)]
+    projection: DesProjection,
+}
+
+impl DesCamera {
+    fn default_focus_distance() -> Real {
+        1.0
+    }
 }
 
 #[derive(Deserialize, Debug)]
+struct DesObject {
+    geometry: String,
+    material: String,
+    /// placement of the geometry, applied in order
+    #[serde(default)]
+    transform: Option<Vec<DesTransform>>,
+    /// medium filling the inside of the geometry, which must be closed
+    #[serde(default)]
+    medium: Option<String>,
+}
+
 struct ObjectEntry<T> {
     geometry: T,
     material: T,
+    medium: Option<T>,
 }
 
 pub struct Scene {
     pub camera: Camera,
+    pub background: Background,
     objects_map: Vec<ObjectEntry<usize>>,
     materials: Vec<Box<dyn Material + Send + Sync>>,
-    geometries: Vec<Box<dyn Geometry + Send + Sync>>,
+    /// shared by the objects, instances included
+    geometries: Vec<Arc<dyn Geometry + Send + Sync>>,
+    media: Vec<Medium>,
+    /// index in `media` of the medium around the objects
+    medium: Option<usize>,
+    /// bvh over the objects, indices refer to `objects_map`
+    bvh: Bvh,
+    /// objects with an emissive material that can be sampled, indices of `objects_map`
+    lights: Vec<usize>,
     width: usize,
     height: usize,
     pub max_bounces: usize,
     pub debug_surfaces: bool,
     pub debug_error: bool,
+    /// the camera focuses with `autofocus()` at load and whenever it moves
+    pub autofocus: bool,
+    pub spectral: bool,
+    pub seed: u64,
+    pub display: DisplaySettings,
 }
 
 impl std::convert::TryFrom<&str> for Scene {
@@ -62,24 +134,75 @@ impl std::convert::TryFrom<DesScene> for Scene {
     type Error = String;
 
     fn try_from(des_scene: DesScene) -> Result<Self, Self::Error> {
+        let mut textures: HashMap<String, Arc<dyn Texture + Send + Sync>> =
+            HashMap::with_capacity(des_scene.textures.len());
+
+        for (name, des_texture) in des_scene.textures {
+            match des_texture.load(&des_scene.base_dir) {
+                Ok(texture) => {
+                    textures.insert(name, texture);
+                }
+                Err(err) => return Err(format!("cannot load texture '{}': {}", name, err)),
+            }
+        }
+
         let mut materials_indices: HashMap<String, usize> =
             HashMap::with_capacity(des_scene.materials.len());
         let mut materials: Vec<Box<dyn Material + Send + Sync>> =
             Vec::with_capacity(des_scene.materials.len());
 
-        for (name, des_mat) in des_scene.materials {
-            materials_indices.insert(name, materials.len());
-            materials.push(des_mat.into());
+        // sorted, the indices of the materials are written in the output variables
+        let mut des_materials: Vec<(String, DesMaterial)> =
+            des_scene.materials.into_iter().collect();
+        des_materials.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, des_mat) in des_materials {
+            match des_mat.load(&textures) {
+                Ok(material) => {
+                    materials_indices.insert(name, materials.len());
+                    materials.push(material);
+                }
+                Err(err) => return Err(format!("cannot load material '{}': {}", name, err)),
+            }
+        }
+
+        if let Err(err) = des_scene.display.validate() {
+            return Err(format!("invalid display settings: {}", err));
         }
 
+        let mut media_indices: HashMap<String, usize> =
+            HashMap::with_capacity(des_scene.media.len());
+        let mut media: Vec<Medium> = Vec::with_capacity(des_scene.media.len());
+
+        for (name, medium) in des_scene.media {
+            if let Err(err) = medium.validate() {
+                return Err(format!("cannot load medium '{}': {}", name, err));
+            }
+            media_indices.insert(name, media.len());
+            media.push(medium);
+        }
+
+        let find_medium = |name: Option<String>| match name {
+            Some(name) => match media_indices.get(&name) {
+                Some(&index) => Ok(Some(index)),
+                None => Err(format!("cannot find medium '{}'", name)),
+            },
+            None => Ok(None),
+        };
+        let medium = find_medium(des_scene.medium)?;
+
         let mut geometries_indices: HashMap<String, usize> =
             HashMap::with_capacity(des_scene.geometries.len());
-        let mut geometries: Vec<Box<dyn Geometry + Send + Sync>> =
+        let mut geometries: Vec<Arc<dyn Geometry + Send + Sync>> =
             Vec::with_capacity(des_scene.geometries.len());
 
         for (name, des_geo) in des_scene.geometries {
-            geometries_indices.insert(name, geometries.len());
-            geometries.push(des_geo.into());
+            match des_geo.load(&des_scene.base_dir) {
+                Ok(geometry) => {
+                    geometries_indices.insert(name, geometries.len());
+                    geometries.push(Arc::from(geometry));
+                }
+                Err(err) => return Err(format!("cannot load geometry '{}': {}", name, err)),
+            }
         }
 
         let mut objects_map: Vec<ObjectEntry<usize>> = Vec::with_capacity(des_scene.objects.len());
@@ -87,9 +210,31 @@ impl std::convert::TryFrom<DesScene> for Scene {
         for obj_entry in des_scene.objects {
             if let Some(&mat_index) = materials_indices.get(&obj_entry.material) {
                 if let Some(&geo_index) = geometries_indices.get(&obj_entry.geometry) {
+                    let geometry = match obj_entry.transform {
+                        Some(steps) => match DesTransform::load_all(steps) {
+                            Ok(transform) => {
+                                let instance =
+                                    Instance::new(geometries[geo_index].clone(), transform);
+                                geometries.push(Arc::new(instance));
+                                geometries.len() - 1
+                            }
+                            Err(err) => {
+                                return Err(format!(
+                                    "invalid transform for object with geometry '{}': {}",
+                                    obj_entry.geometry, err
+                                ))
+                            }
+                        },
+                        None => geo_index,
+                    };
+                    let medium = match find_medium(obj_entry.medium) {
+                        Ok(medium) => medium,
+                        Err(err) => return Err(format!("{} for object", err)),
+                    };
                     objects_map.push(ObjectEntry {
                         material: mat_index,
-                        geometry: geo_index,
+                        geometry,
+                        medium,
                     })
                 } else {
                     return Err(format!(
@@ -105,52 +250,169 @@ impl std::convert::TryFrom<DesScene> for Scene {
             }
         }
 
+        let bounding_boxes: Vec<Aabb> = objects_map
+            .iter()
+            .map(|entry| geometries[entry.geometry].bounding_box())
+            .collect();
+        let bvh = Bvh::new(&bounding_boxes);
+        let lights: Vec<usize> = (0..objects_map.len())
+            .filter(|&index| {
+                let entry = &objects_map[index];
+                materials[entry.material].is_light() && geometries[entry.geometry].area() > 0.0
+            })
+            .collect();
+
+        let background = match des_scene.background {
+            Some(des_background) => match des_background.load(&des_scene.base_dir) {
+                Ok(background) => background,
+                Err(err) => return Err(format!("cannot load background: {}", err)),
+            },
+            None => Background::default(),
+        };
+
         let mut camera = Camera::new(
             (des_scene.width as Real) / (des_scene.height as Real),
             des_scene.camera.fov.to_radians(),
         );
+        if des_scene.camera.aperture.is_nan() || des_scene.camera.aperture < 0.0 {
+            return Err("camera aperture can't be negative".to_string());
+        }
+        if des_scene.camera.focus_distance.is_nan() || des_scene.camera.focus_distance <= 0.0 {
+            return Err("camera focus distance must be positive".to_string());
+        }
         camera.origin = des_scene.camera.origin;
         camera.rotate(des_scene.camera.rotation);
+        camera.lens_radius = des_scene.camera.aperture * 0.5;
+        camera.projection = match Projection::try_from(des_scene.camera.projection) {
+            Ok(projection) => projection,
+            Err(err) => return Err(format!("invalid camera projection: {}", err)),
+        };
+        camera.set_focus_distance(des_scene.camera.focus_distance);
 
-        Ok(Scene {
+        let mut scene = Scene {
             camera: camera,
+            background,
             objects_map,
             materials,
             geometries,
+            media,
+            medium,
+            bvh,
+            lights,
             width: des_scene.width as usize,
             height: des_scene.height as usize,
             max_bounces: des_scene.max_bounces as usize,
             debug_surfaces: des_scene.debug_surfaces,
             debug_error: des_scene.debug_error.unwrap_or(false),
-        })
+            autofocus: des_scene.camera.autofocus,
+            spectral: des_scene.spectral,
+            seed: des_scene.seed,
+            display: des_scene.display,
+        };
+        if scene.autofocus {
+            scene.autofocus();
+        }
+        Ok(scene)
     }
 }
 
-pub struct ObjectsIterator<'a> {
-    scene: &'a Scene,
-    index: usize,
+/// Something that can be sampled by next event estimation.
+pub enum Light<'a> {
+    Object(Object<'a, 'a>),
+    Background,
 }
 
-impl<'a> Iterator for ObjectsIterator<'a> {
-    type Item = Object<'a, 'a>;
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some(object_entry) = self.scene.objects_map.get(self.index) {
-            self.index += 1;
-            Some(Object {
-                geometry: self.scene.geometries[object_entry.geometry].as_ref(),
-                material: self.scene.materials[object_entry.material].as_ref(),
+impl Scene {
+    /// Loads the scene from a json file, external files are relative to its directory.
+    pub fn from_file(path: &Path) -> Result<Scene, String> {
+        let data = match std::fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(err) => return Err(format!("cannot read '{}': {}", path.display(), err)),
+        };
+        match serde_json::from_str::<DesScene>(&data) {
+            Ok(mut des_scene) => {
+                des_scene.base_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+                Scene::try_from(des_scene)
+            }
+            Err(err) => Err(format!("Error parsing data: {}", err)),
+        }
+    }
+    fn object(&self, index: usize) -> Object<'_, '_> {
+        let object_entry = &self.objects_map[index];
+        Object {
+            index,
+            material_index: object_entry.material,
+            geometry: self.geometries[object_entry.geometry].as_ref(),
+            material: self.materials[object_entry.material].as_ref(),
+            medium: object_entry.medium.map(|index| &self.media[index]),
+        }
+    }
+    /// Medium around the objects, where the camera is.
+    pub fn medium(&self) -> Option<&Medium> {
+        self.medium.map(|index| &self.media[index])
+    }
+    /// Closest object hit by the ray in (t_min, t_max) and the time of the hit.
+    pub fn closest_hit(
+        &self,
+        ray: &Ray,
+        t_min: Real,
+        t_max: Real,
+    ) -> Option<(Object<'_, '_>, Real)> {
+        self.bvh
+            .closest_hit(ray, t_min, t_max, |index, max_time| {
+                let geometry = &self.geometries[self.objects_map[index].geometry];
+                geometry.intersect(ray, t_min, max_time)
             })
+            .map(|(index, time)| (self.object(index), time))
+    }
+    /// Focuses the camera on the closest object at the center of the image,
+    /// keeps the current focus distance if there is nothing.
+    pub fn autofocus(&mut self) {
+        let ray = Ray::new(self.camera.origin, *self.camera.direction());
+        if let Some((_, time)) = self.closest_hit(&ray, 0.0, Real::INFINITY) {
+            if time > 0.0 {
+                self.camera.set_focus_distance(time);
+            }
+        }
+    }
+    /// Number of lights, the background counts as one when it's sampled.
+    fn lights_count(&self) -> usize {
+        self.lights.len() + if self.background.is_light() { 1 } else { 0 }
+    }
+    /// Picks one of the lights uniformly.
+    pub fn random_light(&self, rng: &mut dyn RngCore) -> Option<Light<'_>> {
+        let count = self.lights_count();
+        if count == 0 {
+            return None;
+        }
+        let index = rng.gen_range(0, count);
+        if index < self.lights.len() {
+            Some(Light::Object(self.object(self.lights[index])))
         } else {
-            None
+            Some(Light::Background)
         }
     }
-}
-
-impl Scene {
-    pub fn objects_iter<'a>(&'a self) -> ObjectsIterator {
-        ObjectsIterator {
-            scene: self,
-            index: 0,
+    /// Probability density (solid angle) that light sampling, from the previous hit,
+    /// chooses the point of `hit`, `time` away on `object`. Includes the choice of the light.
+    pub fn light_pdf(&self, object: &Object, ray: &Ray, hit: &Hit, time: Real) -> Real {
+        if self.lights.is_empty() || !object.material.is_light() {
+            return 0.0;
+        }
+        let area = object.geometry.area();
+        let cosine = ray.direction.vec().dot(hit.geometric_normal.vec()).abs();
+        if area > 0.0 && cosine > 0.0 {
+            time * time / (cosine * area * self.lights_count() as Real)
+        } else {
+            0.0
+        }
+    }
+    /// Probability density (solid angle) that light sampling chooses the background
+    /// in `direction`. Includes the choice of the light.
+    pub fn background_light_pdf(&self, direction: &Unit3R) -> Real {
+        if self.background.is_light() {
+            self.background.pdf(direction) / self.lights_count() as Real
+        } else {
+            0.0
         }
     }
     pub fn new_pixel_buffer(&self) -> impl RendererBuffer {
@@ -162,6 +424,13 @@ impl Scene {
     pub fn render(&self, buffer: &mut impl RendererBuffer) {
         render(self, buffer);
     }
+    /// Changes the resolution of the image, the camera follows the new aspect ratio.
+    pub fn set_size(&mut self, width: usize, height: usize) {
+        debug_assert!(width > 0 && height > 0);
+        self.width = width;
+        self.height = height;
+        self.camera.set_aspect_ratio(width, height);
+    }
     pub fn width(&self) -> usize {
         self.width
     }
@@ -169,3 +438,37 @@ impl Scene {
         self.height
     }
 }
+
+#[test]
+fn test_scene_autofocus() {
+    use super::defs::PI;
+    let json = r#"{
+        "width": 8,
+        "height": 8,
+        "max_bounces": 4,
+        "debug_surfaces": false,
+        "camera": {
+            "origin": { "x": 0, "y": 0, "z": 5 },
+            "rotation": { "x": 0, "y": 0 },
+            "fov": 40,
+            "aperture": 0.2,
+            "autofocus": true
+        },
+        "materials": {
+            "white": { "type": "diffuse", "albedo": { "x": 0.8, "y": 0.8, "z": 0.8 } }
+        },
+        "geometries": {
+            "ball": { "type": "sphere", "center": { "x": 0, "y": 0, "z": 0 }, "radius": 1 }
+        },
+        "objects": [{ "geometry": "ball", "material": "white" }]
+    }"#;
+    let mut scene = Scene::try_from(json).unwrap();
+    assert!((scene.camera.focus_distance() - 4.0).abs() < 1e-9);
+    scene.camera.move_forward(1.5);
+    scene.autofocus();
+    assert!((scene.camera.focus_distance() - 2.5).abs() < 1e-9);
+    // looking away keeps the focus
+    scene.camera.rotate(Vec2R::new(0.0, PI));
+    scene.autofocus();
+    assert!((scene.camera.focus_distance() - 2.5).abs() < 1e-9);
+}
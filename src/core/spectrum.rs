@@ -0,0 +1,179 @@
+use super::defs::Real;
+use super::primitives::*;
+use serde::Deserialize;
+
+/*
+    In spectral mode the three components of the colors carried by a ray are
+    not red, green and blue but the values at three wavelengths: a random hero
+    wavelength and two more evenly spaced over the visible range
+    (Wilkie et al. 2014, "Hero Wavelength Spectral Sampling").
+    The RGB colors of the scene are turned into smooth spectra when needed,
+    and the radiance is converted back to RGB with the CIE color matching functions.
+*/
+
+/// visible range in nanometers
+pub const LAMBDA_MIN: Real = 360.0;
+pub const LAMBDA_MAX: Real = 830.0;
+const LAMBDA_RANGE: Real = LAMBDA_MAX - LAMBDA_MIN;
+
+/// integral of the y color matching function over the visible range
+const CIE_Y_INTEGRAL: Real = 106.922_075;
+
+/// linear sRGB of the equal energy white, used to map it to (1, 1, 1)
+const WHITE_RGB: Vec3R = Vec3R {
+    x: 1.200_278,
+    y: 0.949_689,
+    z: 0.908_369,
+};
+
+/// Wavelengths, in nanometers, carried by a ray, the first is the hero wavelength.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Wavelengths(pub [Real; 3]);
+
+impl Wavelengths {
+    /// Hero wavelength at `u` in [0, 1) of the visible range, the others rotated by a third.
+    pub fn sample(u: Real) -> Wavelengths {
+        let lambda = |offset: Real| {
+            let k = u + offset;
+            LAMBDA_MIN + (k - k.floor()) * LAMBDA_RANGE
+        };
+        Wavelengths([lambda(0.0), lambda(1.0 / 3.0), lambda(2.0 / 3.0)])
+    }
+
+    pub fn hero(&self) -> Real {
+        self.0[0]
+    }
+
+    /// All the wavelengths set to the hero one, when the others can't follow it.
+    pub fn hero_only(&self) -> Wavelengths {
+        Wavelengths([self.0[0]; 3])
+    }
+
+    pub fn is_single(&self) -> bool {
+        self.0[1] == self.0[0] && self.0[2] == self.0[0]
+    }
+
+    /// Values at the wavelengths of the smooth spectrum of an RGB color,
+    /// white stays white and colors in [0, 1] give values in [0, 1].
+    pub fn rgb_to_spectrum(&self, rgb: &Vec3R) -> Vec3R {
+        let value = |lambda: Real| {
+            let blue = 1.0 - smoothstep(440.0, 510.0, lambda);
+            let red = smoothstep(560.0, 620.0, lambda);
+            rgb.z * blue + rgb.y * (1.0 - blue - red) + rgb.x * red
+        };
+        Vec3R::new(value(self.0[0]), value(self.0[1]), value(self.0[2]))
+    }
+
+    /// Linear sRGB estimate of the spectral radiance sampled at the wavelengths.
+    pub fn spectrum_to_rgb(&self, values: &Vec3R) -> Vec3R {
+        let mut xyz = Vec3R::default();
+        for (lambda, value) in self.0.iter().zip(&[values.x, values.y, values.z]) {
+            xyz += cie_xyz(*lambda) * *value;
+        }
+        // each wavelength has pdf 1 / range
+        xyz *= LAMBDA_RANGE / (3.0 * CIE_Y_INTEGRAL);
+        let rgb = Vec3R::new(
+            3.240_479 * xyz.x - 1.537_150 * xyz.y - 0.498_535 * xyz.z,
+            -0.969_256 * xyz.x + 1.875_991 * xyz.y + 0.041_556 * xyz.z,
+            0.055_648 * xyz.x - 0.204_043 * xyz.y + 1.057_311 * xyz.z,
+        );
+        rgb / WHITE_RGB
+    }
+}
+
+fn smoothstep(edge0: Real, edge1: Real, x: Real) -> Real {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+/// CIE 1931 color matching functions, multi-lobe fit of
+/// Wyman et al. 2013, "Simple Analytic Approximations to the CIE XYZ Color Matching Functions".
+pub fn cie_xyz(lambda: Real) -> Vec3R {
+    let g = |mu: Real, sigma_low: Real, sigma_high: Real| {
+        let t = (lambda - mu) / if lambda < mu { sigma_low } else { sigma_high };
+        (-0.5 * t * t).exp()
+    };
+    Vec3R::new(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+/// Index of refraction that changes with the wavelength, the cause of dispersion.
+/// The wavelengths of the formulas are in micrometers.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Dispersion {
+    /// n = a + b / l^2
+    Cauchy { a: Real, b: Real },
+    /// n^2 = 1 + sum of b l^2 / (l^2 - c), for example BK7 glass has
+    /// b = [1.03961212, 0.231792344, 1.01046945] and c = [0.00600069867, 0.0200179144, 103.560653]
+    Sellmeier { b: [Real; 3], c: [Real; 3] },
+}
+
+impl Dispersion {
+    /// Index of refraction at `lambda` nanometers.
+    pub fn ior(&self, lambda: Real) -> Real {
+        let l2 = (lambda / 1000.0).powi(2);
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / l2,
+            Dispersion::Sellmeier { b, c } => {
+                let n2 = 1.0 + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<Real>();
+                n2.max(0.0).sqrt()
+            }
+        }
+    }
+}
+
+#[test]
+fn test_spectrum() {
+    // gray is flat, white averages back to white
+    let n = 1000;
+    let mut white = Vec3R::default();
+    let mut red = Vec3R::default();
+    for i in 0..n {
+        let wavelengths = Wavelengths::sample((i as Real + 0.5) / n as Real);
+        for lambda in &wavelengths.0 {
+            assert!(*lambda >= LAMBDA_MIN && *lambda < LAMBDA_MAX);
+        }
+        let spectrum = wavelengths.rgb_to_spectrum(&Vec3R::new(0.5, 0.5, 0.5));
+        assert!((spectrum - Vec3R::new(0.5, 0.5, 0.5)).abs().max_component() < 1e-9);
+        white +=
+            wavelengths.spectrum_to_rgb(&wavelengths.rgb_to_spectrum(&Vec3R::new(1.0, 1.0, 1.0)));
+        red +=
+            wavelengths.spectrum_to_rgb(&wavelengths.rgb_to_spectrum(&Vec3R::new(1.0, 0.0, 0.0)));
+    }
+    white /= n as Real;
+    red /= n as Real;
+    assert!(
+        (white - Vec3R::new(1.0, 1.0, 1.0)).abs().max_component() < 0.01,
+        "{:?}",
+        white
+    );
+    assert!(
+        red.x > 0.5 && red.x > 3.0 * red.y.abs() && red.x > 3.0 * red.z.abs(),
+        "{:?}",
+        red
+    );
+
+    // hero wavelengths are a third of the range apart
+    let wavelengths = Wavelengths::sample(0.9);
+    assert!((wavelengths.hero() - (LAMBDA_MIN + 0.9 * LAMBDA_RANGE)).abs() < 1e-9);
+    assert!(
+        (wavelengths.0[1] - (LAMBDA_MIN + (0.9 + 1.0 / 3.0 - 1.0) * LAMBDA_RANGE)).abs() < 1e-9
+    );
+}
+
+#[test]
+fn test_dispersion() {
+    let bk7 = Dispersion::Sellmeier {
+        b: [1.03961212, 0.231792344, 1.01046945],
+        c: [0.00600069867, 0.0200179144, 103.560653],
+    };
+    assert!((bk7.ior(587.6) - 1.5168).abs() < 1e-4);
+    // blue bends more than red
+    assert!(bk7.ior(450.0) > bk7.ior(650.0));
+    let cauchy = Dispersion::Cauchy { a: 1.5, b: 0.01 };
+    assert!((cauchy.ior(500.0) - 1.54).abs() < 1e-9);
+}
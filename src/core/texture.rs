@@ -0,0 +1,486 @@
+use super::defs::Real;
+use super::primitives::*;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum DesTexture {
+    /// png, jpeg or any format supported by the image crate, `path` is relative to the scene file
+    Image {
+        path: PathBuf,
+        #[serde(default)]
+        filter: Filter,
+    },
+    /// 3D checker board, `scale` is the number of squares per unit of the object,
+    /// it follows the transform of the object
+    Checker {
+        even: Vec3R,
+        odd: Vec3R,
+        #[serde(default = "DesTexture::default_scale")]
+        scale: Real,
+    },
+    Noise {
+        #[serde(default = "DesTexture::default_color")]
+        color: Vec3R,
+        #[serde(default = "DesTexture::default_scale")]
+        scale: Real,
+        #[serde(default)]
+        seed: u64,
+    },
+    Turbulence {
+        #[serde(default = "DesTexture::default_color")]
+        color: Vec3R,
+        #[serde(default = "DesTexture::default_scale")]
+        scale: Real,
+        #[serde(default = "DesTexture::default_depth")]
+        depth: usize,
+        #[serde(default)]
+        seed: u64,
+    },
+    /// stripes along z perturbed by turbulence
+    Marble {
+        #[serde(default = "DesTexture::default_color")]
+        color: Vec3R,
+        #[serde(default = "DesTexture::default_scale")]
+        scale: Real,
+        #[serde(default = "DesTexture::default_depth")]
+        depth: usize,
+        #[serde(default)]
+        seed: u64,
+    },
+}
+
+impl DesTexture {
+    fn default_scale() -> Real {
+        1.0
+    }
+
+    fn default_color() -> Vec3R {
+        Vec3R::new(1.0, 1.0, 1.0)
+    }
+
+    fn default_depth() -> usize {
+        7
+    }
+
+    pub fn load(self, base_dir: &Path) -> Result<Arc<dyn Texture + Send + Sync>, String> {
+        Ok(match self {
+            DesTexture::Image { path, filter } => {
+                Arc::new(ImageTexture::from_file(&base_dir.join(path), filter)?)
+            }
+            DesTexture::Checker { even, odd, scale } => Arc::new(Checker { even, odd, scale }),
+            DesTexture::Noise { color, scale, seed } => Arc::new(Noise {
+                kind: NoiseKind::Perlin,
+                perlin: Perlin::new(seed),
+                color,
+                scale,
+            }),
+            DesTexture::Turbulence {
+                color,
+                scale,
+                depth,
+                seed,
+            } => Arc::new(Noise {
+                kind: NoiseKind::Turbulence(depth),
+                perlin: Perlin::new(seed),
+                color,
+                scale,
+            }),
+            DesTexture::Marble {
+                color,
+                scale,
+                depth,
+                seed,
+            } => Arc::new(Noise {
+                kind: NoiseKind::Marble(depth),
+                perlin: Perlin::new(seed),
+                color,
+                scale,
+            }),
+        })
+    }
+}
+
+/// Color that changes over a surface.
+pub trait Texture {
+    /// Color at texture coordinates `uv` and position `point` in the space of the object.
+    fn value(&self, uv: &Vec2R, point: &Point3R) -> Vec3R;
+}
+
+// ------- ALBEDO -------
+
+/// Color of a material, either constant or read from a texture of the scene.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Albedo {
+    Color(Vec3R),
+    /// name of the texture
+    Texture(TextureRef),
+}
+
+impl Albedo {
+    /// Binds the texture name to one of `textures`.
+    pub fn resolve(
+        &mut self,
+        textures: &HashMap<String, Arc<dyn Texture + Send + Sync>>,
+    ) -> Result<(), String> {
+        if let Albedo::Texture(texture) = self {
+            match textures.get(&texture.name) {
+                Some(found) => texture.texture = Some(found.clone()),
+                None => return Err(format!("cannot find texture '{}'", texture.name)),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn value(&self, hit: &Hit) -> Vec3R {
+        match self {
+            Albedo::Color(color) => *color,
+            Albedo::Texture(texture) => texture
+                .texture
+                .as_ref()
+                .expect("textures are resolved when the scene is loaded")
+                .value(&hit.uv, &hit.local_point),
+        }
+    }
+}
+
+impl From<Vec3R> for Albedo {
+    fn from(color: Vec3R) -> Albedo {
+        Albedo::Color(color)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(from = "String")]
+pub struct TextureRef {
+    name: String,
+    texture: Option<Arc<dyn Texture + Send + Sync>>,
+}
+
+impl From<String> for TextureRef {
+    fn from(name: String) -> TextureRef {
+        TextureRef {
+            name,
+            texture: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for TextureRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TextureRef({:?})", self.name)
+    }
+}
+
+// ------- IMAGE -------
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Filter {
+    Nearest,
+    #[default]
+    Bilinear,
+}
+
+/// Image repeated over the texture coordinates, (0, 0) is the bottom left corner.
+pub struct ImageTexture {
+    /// linear colors, top row first
+    pixels: Vec<Vec3R>,
+    width: usize,
+    height: usize,
+    filter: Filter,
+}
+
+impl ImageTexture {
+    pub fn new(pixels: Vec<Vec3R>, width: usize, height: usize, filter: Filter) -> ImageTexture {
+        debug_assert!(pixels.len() == width * height && !pixels.is_empty());
+        ImageTexture {
+            pixels,
+            width,
+            height,
+            filter,
+        }
+    }
+
+    pub fn from_file(path: &Path, filter: Filter) -> Result<ImageTexture, String> {
+        let image = image::open(path)
+            .map_err(|err| format!("cannot read '{}': {}", path.display(), err))?
+            .to_rgb8();
+        if image.width() == 0 || image.height() == 0 {
+            return Err(format!("'{}' is empty", path.display()));
+        }
+        let pixels = image
+            .pixels()
+            .map(|rgb| {
+                Vec3R::new(
+                    srgb_to_linear(rgb[0]),
+                    srgb_to_linear(rgb[1]),
+                    srgb_to_linear(rgb[2]),
+                )
+            })
+            .collect();
+        Ok(ImageTexture::new(
+            pixels,
+            image.width() as usize,
+            image.height() as usize,
+            filter,
+        ))
+    }
+
+    /// Pixel at integer coordinates, wrapping around the borders.
+    fn pixel(&self, x: isize, y: isize) -> Vec3R {
+        let x = x.rem_euclid(self.width as isize) as usize;
+        let y = y.rem_euclid(self.height as isize) as usize;
+        self.pixels[y * self.width + x]
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, uv: &Vec2R, _point: &Point3R) -> Vec3R {
+        // pixel coordinates with the centers at integer + 0.5
+        let x = uv.x * self.width as Real;
+        let y = (1.0 - uv.y) * self.height as Real;
+        match self.filter {
+            Filter::Nearest => self.pixel(x.floor() as isize, y.floor() as isize),
+            Filter::Bilinear => {
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let (tx, ty) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as isize, y0 as isize);
+                let top = self.pixel(x0, y0).lerp(&self.pixel(x0 + 1, y0), tx);
+                let bottom = self.pixel(x0, y0 + 1).lerp(&self.pixel(x0 + 1, y0 + 1), tx);
+                top.lerp(&bottom, ty)
+            }
+        }
+    }
+}
+
+fn srgb_to_linear(value: u8) -> Real {
+    let value = value as Real / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// ------- CHECKER -------
+
+pub struct Checker {
+    pub even: Vec3R,
+    pub odd: Vec3R,
+    pub scale: Real,
+}
+
+impl Texture for Checker {
+    fn value(&self, _uv: &Vec2R, point: &Point3R) -> Vec3R {
+        let p = point * self.scale;
+        let sum = p.x.floor() as i64 + p.y.floor() as i64 + p.z.floor() as i64;
+        if sum.rem_euclid(2) == 0 {
+            self.even
+        } else {
+            self.odd
+        }
+    }
+}
+
+// ------- NOISE -------
+
+const PERLIN_POINTS: usize = 256;
+
+/// Perlin gradient noise, the same seed gives the same noise.
+pub struct Perlin {
+    gradients: Vec<Vec3R>,
+    permutations: [Vec<usize>; 3],
+}
+
+impl Perlin {
+    pub fn new(seed: u64) -> Perlin {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let gradients = (0..PERLIN_POINTS)
+            .map(|_| *Unit3R::random_with(&mut rng).vec())
+            .collect();
+        let mut permutation = || {
+            let mut indices: Vec<usize> = (0..PERLIN_POINTS).collect();
+            indices.shuffle(&mut rng);
+            indices
+        };
+        let permutations = [permutation(), permutation(), permutation()];
+        Perlin {
+            gradients,
+            permutations,
+        }
+    }
+
+    /// Noise in [-1, 1], zero at integer coordinates.
+    pub fn noise(&self, point: &Point3R) -> Real {
+        let floor = Vec3R::new(point.x.floor(), point.y.floor(), point.z.floor());
+        let t = point - floor;
+        // hermite smoothing avoids the grid artifacts
+        let smooth = |t: Real| t * t * (3.0 - 2.0 * t);
+        let (u, v, w) = (smooth(t.x), smooth(t.y), smooth(t.z));
+        let (i, j, k) = (floor.x as i64, floor.y as i64, floor.z as i64);
+        let mask = PERLIN_POINTS as i64 - 1;
+
+        let mut sum = 0.0;
+        for di in 0..2 {
+            for dj in 0..2 {
+                for dk in 0..2 {
+                    let index = self.permutations[0][((i + di) & mask) as usize]
+                        ^ self.permutations[1][((j + dj) & mask) as usize]
+                        ^ self.permutations[2][((k + dk) & mask) as usize];
+                    let offset = Vec3R::new(t.x - di as Real, t.y - dj as Real, t.z - dk as Real);
+                    let (fi, fj, fk) = (di as Real, dj as Real, dk as Real);
+                    sum += (fi * u + (1.0 - fi) * (1.0 - u))
+                        * (fj * v + (1.0 - fj) * (1.0 - v))
+                        * (fk * w + (1.0 - fk) * (1.0 - w))
+                        * self.gradients[index].dot(&offset);
+                }
+            }
+        }
+        sum
+    }
+
+    /// Sum of `depth` octaves of noise, each with double frequency and half weight.
+    pub fn turbulence(&self, point: &Point3R, depth: usize) -> Real {
+        let mut sum = 0.0;
+        let mut point = *point;
+        let mut weight = 1.0;
+        for _ in 0..depth {
+            sum += weight * self.noise(&point);
+            weight *= 0.5;
+            point *= 2.0;
+        }
+        sum.abs()
+    }
+}
+
+enum NoiseKind {
+    Perlin,
+    /// octaves
+    Turbulence(usize),
+    /// octaves of the turbulence
+    Marble(usize),
+}
+
+/// Gray levels of `color` from Perlin noise.
+struct Noise {
+    kind: NoiseKind,
+    perlin: Perlin,
+    color: Vec3R,
+    scale: Real,
+}
+
+impl Texture for Noise {
+    fn value(&self, _uv: &Vec2R, point: &Point3R) -> Vec3R {
+        let p = point * self.scale;
+        let level = match self.kind {
+            NoiseKind::Perlin => 0.5 * (1.0 + self.perlin.noise(&p)),
+            NoiseKind::Turbulence(depth) => self.perlin.turbulence(&p, depth),
+            NoiseKind::Marble(depth) => {
+                0.5 * (1.0 + (p.z + 10.0 * self.perlin.turbulence(point, depth)).sin())
+            }
+        };
+        self.color * level.clamp(0.0, 1.0)
+    }
+}
+
+#[test]
+fn test_checker() {
+    let checker = Checker {
+        even: Vec3R::new(1.0, 1.0, 1.0),
+        odd: Vec3R::new(0.0, 0.0, 0.0),
+        scale: 2.0,
+    };
+    let uv = Vec2R::default();
+    let at = |x: Real, y: Real, z: Real| checker.value(&uv, &Point3R::new(x, y, z)).x;
+    assert_eq!(at(0.1, 0.1, 0.1), 1.0);
+    assert_eq!(at(0.6, 0.1, 0.1), 0.0);
+    assert_eq!(at(0.6, 0.6, 0.1), 1.0);
+    // squares continue across zero
+    assert_eq!(at(-0.1, 0.1, 0.1), 0.0);
+    assert_eq!(at(-0.1, -0.1, -0.1), 0.0);
+}
+
+#[test]
+fn test_noise() {
+    let perlin = Perlin::new(7);
+    let mut rng = StdRng::seed_from_u64(1);
+    let mut min: Real = 1.0;
+    let mut max: Real = -1.0;
+    for _ in 0..10000 {
+        let point = Unit3R::random_with(&mut rng).vec() * 20.0;
+        let noise = perlin.noise(&point);
+        assert!((-1.0..=1.0).contains(&noise));
+        min = min.min(noise);
+        max = max.max(noise);
+        // continuous
+        let near = perlin.noise(&(point + Vec3R::new(1e-6, 0.0, 0.0)));
+        assert!((near - noise).abs() < 1e-4);
+    }
+    assert!(min < -0.3 && max > 0.3);
+    assert_eq!(perlin.noise(&Point3R::new(3.0, -2.0, 5.0)), 0.0);
+    // the seed decides the noise
+    let point = Point3R::new(0.3, 0.7, 0.2);
+    assert_eq!(perlin.noise(&point), Perlin::new(7).noise(&point));
+    assert_ne!(perlin.noise(&point), Perlin::new(8).noise(&point));
+}
+
+#[test]
+fn test_image_texture() {
+    // 2x2 image: red green on top, blue white at the bottom
+    let pixels = vec![
+        Vec3R::new(1.0, 0.0, 0.0),
+        Vec3R::new(0.0, 1.0, 0.0),
+        Vec3R::new(0.0, 0.0, 1.0),
+        Vec3R::new(1.0, 1.0, 1.0),
+    ];
+    let image = ImageTexture::new(pixels.clone(), 2, 2, Filter::Nearest);
+    let point = Point3R::default();
+    assert_eq!(image.value(&Vec2R::new(0.1, 0.9), &point), pixels[0]);
+    assert_eq!(image.value(&Vec2R::new(0.9, 0.1), &point), pixels[3]);
+    // repeated outside [0, 1]
+    assert_eq!(image.value(&Vec2R::new(1.1, -0.1), &point), pixels[0]);
+
+    let image = ImageTexture::new(pixels, 2, 2, Filter::Bilinear);
+    assert_eq!(
+        image.value(&Vec2R::new(0.25, 0.75), &point),
+        Vec3R::new(1.0, 0.0, 0.0)
+    );
+    assert_eq!(
+        image.value(&Vec2R::new(0.5, 0.75), &point),
+        Vec3R::new(0.5, 0.5, 0.0)
+    );
+}
+
+#[test]
+fn test_albedo() {
+    let mut textures: HashMap<String, Arc<dyn Texture + Send + Sync>> = HashMap::new();
+    textures.insert(
+        "checker".to_string(),
+        DesTexture::Checker {
+            even: Vec3R::new(1.0, 0.0, 0.0),
+            odd: Vec3R::new(0.0, 1.0, 0.0),
+            scale: 1.0,
+        }
+        .load(Path::new(""))
+        .unwrap(),
+    );
+    let mut color: Albedo = serde_json::from_str(r#"{"x": 0.5, "y": 0.5, "z": 0.5}"#).unwrap();
+    let mut texture: Albedo = serde_json::from_str(r#""checker""#).unwrap();
+    let mut missing: Albedo = serde_json::from_str(r#""marble""#).unwrap();
+    assert!(color.resolve(&textures).is_ok());
+    assert!(texture.resolve(&textures).is_ok());
+    assert!(missing.resolve(&textures).is_err());
+
+    let hit = test_hit(Point3R::new(0.5, 0.5, 1.5), true);
+    assert_eq!(color.value(&hit), Vec3R::new(0.5, 0.5, 0.5));
+    assert_eq!(texture.value(&hit), Vec3R::new(0.0, 1.0, 0.0));
+}
@@ -0,0 +1,574 @@
+use super::defs::Real;
+use super::geometry::Geometry;
+use super::primitives::*;
+use overload::overload;
+use rand::RngCore;
+use serde::Deserialize;
+use std::ops;
+use std::sync::Arc;
+
+/// Row major 4x4 matrix, points and vectors are columns multiplied on the right.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Matrix4 {
+    pub m: [[Real; 4]; 4],
+}
+
+impl Matrix4 {
+    pub const IDENTITY: Matrix4 = Matrix4 {
+        m: [
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ],
+    };
+
+    pub fn new(m: [[Real; 4]; 4]) -> Matrix4 {
+        Matrix4 { m }
+    }
+
+    pub fn transpose(&self) -> Matrix4 {
+        let mut t = Matrix4::IDENTITY;
+        for i in 0..4 {
+            for j in 0..4 {
+                t.m[i][j] = self.m[j][i];
+            }
+        }
+        t
+    }
+
+    /// Gauss-Jordan elimination with partial pivoting, None when the matrix is singular.
+    pub fn inverse(&self) -> Option<Matrix4> {
+        let mut a = self.m;
+        let mut inv = Matrix4::IDENTITY.m;
+        for column in 0..4 {
+            let pivot = (column..4)
+                .max_by(|&i, &j| a[i][column].abs().partial_cmp(&a[j][column].abs()).unwrap())
+                .unwrap();
+            if a[pivot][column].abs() <= 1e-12 {
+                return None;
+            }
+            a.swap(column, pivot);
+            inv.swap(column, pivot);
+            let k = 1.0 / a[column][column];
+            for j in 0..4 {
+                a[column][j] *= k;
+                inv[column][j] *= k;
+            }
+            for row in 0..4 {
+                if row != column {
+                    let factor = a[row][column];
+                    for j in 0..4 {
+                        a[row][j] -= factor * a[column][j];
+                        inv[row][j] -= factor * inv[column][j];
+                    }
+                }
+            }
+        }
+        Some(Matrix4::new(inv))
+    }
+}
+
+overload!((a: ?Matrix4) * (b: ?Matrix4) -> Matrix4 {
+    let mut m = [[0.0; 4]; 4];
+    for (i, row) in m.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..4).map(|k| a.m[i][k] * b.m[k][j]).sum();
+        }
+    }
+    Matrix4::new(m)
+});
+
+/// Affine transformation, keeps the inverse matrix to transform normals and
+/// to go back from world space.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Transform {
+    matrix: Matrix4,
+    inverse: Matrix4,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform::IDENTITY
+    }
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform {
+        matrix: Matrix4::IDENTITY,
+        inverse: Matrix4::IDENTITY,
+    };
+
+    /// None when the matrix can't be inverted.
+    pub fn new(matrix: Matrix4) -> Option<Transform> {
+        matrix
+            .inverse()
+            .map(|inverse| Transform { matrix, inverse })
+    }
+
+    pub fn matrix(&self) -> &Matrix4 {
+        &self.matrix
+    }
+
+    pub fn inverse(&self) -> Transform {
+        Transform {
+            matrix: self.inverse,
+            inverse: self.matrix,
+        }
+    }
+
+    pub fn translate(offset: Vec3R) -> Transform {
+        Transform {
+            matrix: Matrix4::new([
+                [1.0, 0.0, 0.0, offset.x],
+                [0.0, 1.0, 0.0, offset.y],
+                [0.0, 0.0, 1.0, offset.z],
+                [0.0, 0.0, 0.0, 1.0],
+            ]),
+            inverse: Matrix4::new([
+                [1.0, 0.0, 0.0, -offset.x],
+                [0.0, 1.0, 0.0, -offset.y],
+                [0.0, 0.0, 1.0, -offset.z],
+                [0.0, 0.0, 0.0, 1.0],
+            ]),
+        }
+    }
+
+    /// None when a factor is 0.
+    pub fn scale(factor: Vec3R) -> Option<Transform> {
+        if factor.x == 0.0 || factor.y == 0.0 || factor.z == 0.0 {
+            return None;
+        }
+        Some(Transform {
+            matrix: Matrix4::new([
+                [factor.x, 0.0, 0.0, 0.0],
+                [0.0, factor.y, 0.0, 0.0],
+                [0.0, 0.0, factor.z, 0.0],
+                [0.0, 0.0, 0.0, 1.0],
+            ]),
+            inverse: Matrix4::new([
+                [1.0 / factor.x, 0.0, 0.0, 0.0],
+                [0.0, 1.0 / factor.y, 0.0, 0.0],
+                [0.0, 0.0, 1.0 / factor.z, 0.0],
+                [0.0, 0.0, 0.0, 1.0],
+            ]),
+        })
+    }
+
+    /// Counterclockwise rotation around `axis`, looking from its tip.
+    pub fn rotate(axis: &Unit3R, angle_radians: Real) -> Transform {
+        let a = axis.vec();
+        let (sin, cos) = angle_radians.sin_cos();
+        let matrix = Matrix4::new([
+            [
+                a.x * a.x + (1.0 - a.x * a.x) * cos,
+                a.x * a.y * (1.0 - cos) - a.z * sin,
+                a.x * a.z * (1.0 - cos) + a.y * sin,
+                0.0,
+            ],
+            [
+                a.x * a.y * (1.0 - cos) + a.z * sin,
+                a.y * a.y + (1.0 - a.y * a.y) * cos,
+                a.y * a.z * (1.0 - cos) - a.x * sin,
+                0.0,
+            ],
+            [
+                a.x * a.z * (1.0 - cos) - a.y * sin,
+                a.y * a.z * (1.0 - cos) + a.x * sin,
+                a.z * a.z + (1.0 - a.z * a.z) * cos,
+                0.0,
+            ],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        // rotations are orthogonal
+        Transform {
+            matrix,
+            inverse: matrix.transpose(),
+        }
+    }
+
+    pub fn rotate_x(angle_radians: Real) -> Transform {
+        Transform::rotate(&Vec3R::new(1.0, 0.0, 0.0).unit(), angle_radians)
+    }
+
+    pub fn rotate_y(angle_radians: Real) -> Transform {
+        Transform::rotate(&Vec3R::new(0.0, 1.0, 0.0).unit(), angle_radians)
+    }
+
+    pub fn rotate_z(angle_radians: Real) -> Transform {
+        Transform::rotate(&Vec3R::new(0.0, 0.0, 1.0).unit(), angle_radians)
+    }
+
+    /// Places the origin in `eye` with -z pointing to `target` and +y towards `up`,
+    /// like the camera. None when the directions are degenerate.
+    pub fn look_at(eye: Point3R, target: Point3R, up: Vec3R) -> Option<Transform> {
+        let forward = target - eye;
+        let right = forward.cross(&up);
+        if forward.length_squared() == 0.0 || right.length_squared() == 0.0 {
+            return None;
+        }
+        let forward = forward.normalize();
+        let right = right.normalize();
+        let up = right.cross(&forward);
+        let rotation = Matrix4::new([
+            [right.x, up.x, -forward.x, 0.0],
+            [right.y, up.y, -forward.y, 0.0],
+            [right.z, up.z, -forward.z, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        let rotation = Transform {
+            matrix: rotation,
+            inverse: rotation.transpose(),
+        };
+        Some(Transform::translate(eye) * rotation)
+    }
+
+    pub fn point(&self, p: &Point3R) -> Point3R {
+        let m = &self.matrix.m;
+        let x = m[0][0] * p.x + m[0][1] * p.y + m[0][2] * p.z + m[0][3];
+        let y = m[1][0] * p.x + m[1][1] * p.y + m[1][2] * p.z + m[1][3];
+        let z = m[2][0] * p.x + m[2][1] * p.y + m[2][2] * p.z + m[2][3];
+        let w = m[3][0] * p.x + m[3][1] * p.y + m[3][2] * p.z + m[3][3];
+        if w == 1.0 {
+            Point3R::new(x, y, z)
+        } else {
+            Point3R::new(x, y, z) / w
+        }
+    }
+
+    pub fn vector(&self, v: &Vec3R) -> Vec3R {
+        let m = &self.matrix.m;
+        Vec3R::new(
+            m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+            m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+            m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+        )
+    }
+
+    /// Normals are transformed by the inverse transpose to stay perpendicular
+    /// to the surface, the result isn't normalized.
+    pub fn normal(&self, n: &Vec3R) -> Vec3R {
+        let m = &self.inverse.m;
+        Vec3R::new(
+            m[0][0] * n.x + m[1][0] * n.y + m[2][0] * n.z,
+            m[0][1] * n.x + m[1][1] * n.y + m[2][1] * n.z,
+            m[0][2] * n.x + m[1][2] * n.y + m[2][2] * n.z,
+        )
+    }
+
+    pub fn bounding_box(&self, aabb: &Aabb) -> Aabb {
+        if aabb.is_empty() {
+            return *aabb;
+        }
+        (0..8).fold(Aabb::empty(), |acc, corner| {
+            let p = Point3R::new(
+                if corner & 1 == 0 {
+                    aabb.min.x
+                } else {
+                    aabb.max.x
+                },
+                if corner & 2 == 0 {
+                    aabb.min.y
+                } else {
+                    aabb.max.y
+                },
+                if corner & 4 == 0 {
+                    aabb.min.z
+                } else {
+                    aabb.max.z
+                },
+            );
+            acc.grow(&self.point(&p))
+        })
+    }
+
+    /// The scale factor when the transform is a rigid motion with a uniform scale,
+    /// so that it preserves the angles and scales the areas by its square.
+    pub fn uniform_scale(&self) -> Option<Real> {
+        let m = &self.matrix.m;
+        if m[3][0] != 0.0 || m[3][1] != 0.0 || m[3][2] != 0.0 || m[3][3] != 1.0 {
+            return None;
+        }
+        let column = |j: usize| Vec3R::new(m[0][j], m[1][j], m[2][j]);
+        let (x, y, z) = (column(0), column(1), column(2));
+        let scale2 = x.length_squared();
+        let tolerance = 1e-9 * scale2;
+        let similar = (y.length_squared() - scale2).abs() <= tolerance
+            && (z.length_squared() - scale2).abs() <= tolerance
+            && x.dot(&y).abs() <= tolerance
+            && x.dot(&z).abs() <= tolerance
+            && y.dot(&z).abs() <= tolerance;
+        if similar {
+            Some(scale2.sqrt())
+        } else {
+            None
+        }
+    }
+}
+
+// `a * b` applies `b` first.
+overload!((a: ?Transform) * (b: ?Transform) -> Transform {
+    Transform {
+        matrix: a.matrix * b.matrix,
+        inverse: b.inverse * a.inverse,
+    }
+});
+
+/// One step of an object placement, the steps are applied in order.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum DesTransform {
+    Translate {
+        offset: Vec3R,
+    },
+    Scale {
+        factor: Vec3R,
+    },
+    Rotate {
+        axis: Vec3R,
+        /// degrees, counterclockwise looking from the tip of the axis
+        angle: Real,
+    },
+    LookAt {
+        eye: Point3R,
+        target: Point3R,
+        #[serde(default = "DesTransform::default_up")]
+        up: Vec3R,
+    },
+    /// row major
+    Matrix {
+        rows: [[Real; 4]; 4],
+    },
+}
+
+impl DesTransform {
+    fn default_up() -> Vec3R {
+        *Unit3R::UP.vec()
+    }
+
+    fn load(self) -> Result<Transform, String> {
+        match self {
+            DesTransform::Translate { offset } => Ok(Transform::translate(offset)),
+            DesTransform::Scale { factor } => {
+                Transform::scale(factor).ok_or_else(|| "scale factors can't be 0".to_string())
+            }
+            DesTransform::Rotate { axis, angle } => {
+                if axis.length_squared() == 0.0 {
+                    return Err("rotation axis can't be zero".to_string());
+                }
+                Ok(Transform::rotate(&axis.unit(), angle.to_radians()))
+            }
+            DesTransform::LookAt { eye, target, up } => Transform::look_at(eye, target, up)
+                .ok_or_else(|| "look-at target can't be on the up axis".to_string()),
+            DesTransform::Matrix { rows } => Transform::new(Matrix4::new(rows))
+                .ok_or_else(|| "transform matrix can't be inverted".to_string()),
+        }
+    }
+
+    /// Combines the steps, the first one is applied first.
+    pub fn load_all(steps: Vec<DesTransform>) -> Result<Transform, String> {
+        steps
+            .into_iter()
+            .try_fold(Transform::IDENTITY, |acc, step| Ok(step.load()? * acc))
+    }
+}
+
+/// A geometry placed in the scene by a transform, the same geometry can be shared
+/// by many instances.
+pub struct Instance {
+    geometry: Arc<dyn Geometry + Send + Sync>,
+    /// object space to world space
+    transform: Transform,
+    to_object: Transform,
+    bounds: Aabb,
+    /// areas are only known when the transform doesn't stretch the geometry
+    scale: Option<Real>,
+}
+
+impl Instance {
+    pub fn new(geometry: Arc<dyn Geometry + Send + Sync>, transform: Transform) -> Instance {
+        Instance {
+            bounds: transform.bounding_box(&geometry.bounding_box()),
+            scale: transform.uniform_scale(),
+            to_object: transform.inverse(),
+            geometry,
+            transform,
+        }
+    }
+
+    /// The ray in object space, its direction isn't unit in general so the times
+    /// along it are scaled by the returned factor.
+    fn object_ray(&self, ray: &Ray) -> (Ray, Real) {
+        let direction = self.to_object.vector(ray.direction.vec());
+        let length = direction.length();
+        (
+            Ray::with_color(
+                self.to_object.point(&ray.origin),
+                Unit3R::normalized(direction / length),
+                ray.color,
+            ),
+            length,
+        )
+    }
+}
+
+impl Geometry for Instance {
+    fn intersect(&self, ray: &Ray, t_min: Real, t_max: Real) -> Real {
+        let (object_ray, k) = self.object_ray(ray);
+        self.geometry.intersect(&object_ray, t_min * k, t_max * k) / k
+    }
+
+    fn hit(&self, ray: &Ray, time: Real) -> Hit {
+        let (object_ray, k) = self.object_ray(ray);
+        let hit = self.geometry.hit(&object_ray, time * k);
+        Hit {
+            point: self.transform.point(&hit.point),
+            local_point: hit.local_point,
+            // the transposed inverse keeps the side the normal faces
+            normal: self.transform.normal(hit.normal.vec()).unit(),
+            geometric_normal: self.transform.normal(hit.geometric_normal.vec()).unit(),
+            is_front_face: hit.is_front_face,
+            barycentric: hit.barycentric,
+            uv: hit.uv,
+        }
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.bounds
+    }
+
+    fn area(&self) -> Real {
+        self.scale
+            .map_or(0.0, |scale| self.geometry.area() * scale * scale)
+    }
+
+    fn sample_point(&self, rng: &mut dyn RngCore) -> Option<Point3R> {
+        self.scale?;
+        self.geometry
+            .sample_point(rng)
+            .map(|point| self.transform.point(&point))
+    }
+}
+
+#[test]
+fn test_transform() {
+    let close = |a: &Vec3R, b: &Vec3R| a.distance(b) < 1e-9;
+    let t = Transform::translate(Vec3R::new(1.0, 2.0, 3.0))
+        * Transform::rotate_z(crate::core::defs::PI * 0.5)
+        * Transform::scale(Vec3R::new(2.0, 2.0, 2.0)).unwrap();
+    assert_eq!(
+        t.uniform_scale().map(|s| (s * 1e9).round() / 1e9),
+        Some(2.0)
+    );
+    // scaled, then rotated, then moved
+    let p = t.point(&Point3R::new(1.0, 0.0, 0.0));
+    assert!(close(&p, &Point3R::new(1.0, 4.0, 3.0)));
+    assert!(close(&t.inverse().point(&p), &Point3R::new(1.0, 0.0, 0.0)));
+    // vectors ignore the translation
+    assert!(close(
+        &t.vector(&Vec3R::new(0.0, 1.0, 0.0)),
+        &Vec3R::new(-2.0, 0.0, 0.0)
+    ));
+    let product = t.matrix() * t.inverse().matrix();
+    for i in 0..4 {
+        for j in 0..4 {
+            let expected = if i == j { 1.0 } else { 0.0 };
+            assert!((product.m[i][j] - expected).abs() < 1e-9);
+        }
+    }
+    assert_eq!(
+        Transform::new(*t.matrix()).unwrap().inverse().matrix().m[0][3],
+        t.inverse().matrix().m[0][3]
+    );
+    assert!(Transform::new(Matrix4::new([[0.0; 4]; 4])).is_none());
+
+    // a non uniform scale keeps the normals perpendicular to the surface
+    let stretch = Transform::scale(Vec3R::new(4.0, 1.0, 1.0)).unwrap();
+    assert_eq!(stretch.uniform_scale(), None);
+    let tangent = stretch.vector(&Vec3R::new(1.0, -1.0, 0.0));
+    let normal = stretch.normal(&Vec3R::new(1.0, 1.0, 0.0));
+    assert!(tangent.dot(&normal).abs() < 1e-9);
+
+    // looking towards -x from +x, the right side is -z
+    let look = Transform::look_at(
+        Point3R::new(5.0, 0.0, 0.0),
+        Point3R::new(0.0, 0.0, 0.0),
+        Vec3R::new(0.0, 1.0, 0.0),
+    )
+    .unwrap();
+    assert!(close(
+        &look.point(&Point3R::new(0.0, 0.0, -5.0)),
+        &Point3R::new(0.0, 0.0, 0.0)
+    ));
+    assert!(close(
+        &look.vector(&Vec3R::new(1.0, 0.0, 0.0)),
+        &Vec3R::new(0.0, 0.0, -1.0)
+    ));
+    assert!(Transform::look_at(
+        Point3R::new(0.0, 0.0, 0.0),
+        Point3R::new(0.0, 3.0, 0.0),
+        Vec3R::new(0.0, 1.0, 0.0)
+    )
+    .is_none());
+
+    let steps: Vec<DesTransform> = serde_json::from_str(
+        r#"[
+            {"type": "scale", "factor": {"x": 2, "y": 2, "z": 2}},
+            {"type": "rotate", "axis": {"x": 0, "y": 0, "z": 1}, "angle": 90},
+            {"type": "translate", "offset": {"x": 1, "y": 2, "z": 3}}
+        ]"#,
+    )
+    .unwrap();
+    let loaded = DesTransform::load_all(steps).unwrap();
+    assert!(close(&loaded.point(&Point3R::new(1.0, 0.0, 0.0)), &p));
+}
+
+#[test]
+fn test_instance() {
+    use super::geometry::Sphere;
+
+    let sphere: Arc<dyn Geometry + Send + Sync> =
+        Arc::new(Sphere::new(Point3R::new(0.0, 0.0, 0.0), 1.0));
+    let transform = Transform::translate(Vec3R::new(0.0, 0.0, -10.0))
+        * Transform::scale(Vec3R::new(2.0, 1.0, 1.0)).unwrap();
+    let instance = Instance::new(sphere.clone(), transform);
+    assert_eq!(
+        instance.bounding_box(),
+        Aabb::new(
+            Point3R::new(-2.0, -1.0, -11.0),
+            Point3R::new(2.0, 1.0, -9.0)
+        )
+    );
+    // stretched geometries can't be sampled
+    assert_eq!(instance.area(), 0.0);
+    assert!(instance.sample_point(&mut rand::thread_rng()).is_none());
+
+    // the ellipsoid is 2 wide along x
+    let ray = Ray::new(
+        Point3R::new(-5.0, 0.0, -10.0),
+        Vec3R::new(1.0, 0.0, 0.0).unit(),
+    );
+    let time = instance.intersect(&ray, 0.0, Real::INFINITY);
+    assert!((time - 3.0).abs() < 1e-9);
+    let hit = instance.hit(&ray, time);
+    assert!(hit.point.distance(&Point3R::new(-2.0, 0.0, -10.0)) < 1e-9);
+    // solid textures stay attached to the object
+    assert!(hit.local_point.distance(&Point3R::new(-1.0, 0.0, 0.0)) < 1e-9);
+    assert!(hit.normal.vec().distance(&Vec3R::new(-1.0, 0.0, 0.0)) < 1e-9);
+    assert!(hit.is_front_face);
+    assert_eq!(instance.intersect(&ray, 0.0, 2.9), Real::INFINITY);
+
+    // the normal of the ellipsoid (x/2)^2 + y^2 + (z+10)^2 = 1 is its gradient
+    let x = (2.0 as Real).sqrt();
+    let ray = Ray::new(Point3R::new(x, 0.5, 0.0), Vec3R::new(0.0, 0.0, -1.0).unit());
+    let time = instance.intersect(&ray, 0.0, Real::INFINITY);
+    assert!((time - 9.5).abs() < 1e-9);
+    let hit = instance.hit(&ray, time);
+    let gradient = Vec3R::new(x / 4.0, 0.5, 0.5).normalize();
+    assert!(hit.normal.vec().distance(&gradient) < 1e-9);
+
+    let moved = Instance::new(sphere, Transform::translate(Vec3R::new(5.0, 0.0, 0.0)));
+    assert_eq!(moved.area(), 4.0 * crate::core::defs::PI);
+    let point = moved.sample_point(&mut rand::thread_rng()).unwrap();
+    assert!((point.distance(&Point3R::new(5.0, 0.0, 0.0)) - 1.0).abs() < 1e-9);
+}
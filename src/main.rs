@@ -0,0 +1,570 @@
+mod core;
+mod viewer;
+
+use crate::core::renderer::renderer_buffer::RendererBuffer;
+use crate::core::renderer::{
+    save_exr, save_pfm, AdaptiveBuffer, AdaptiveSampling, Aov, AovBuffer, DisplaySettings,
+    ExrChannel, ExrPrecision, ToneMapping,
+};
+use crate::core::scene::Scene;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "usage:
+    pbr render <scene.json> [options]
+    pbr view <scene.json>
+
+options:
+    -o, --output <file>     image to write, png or bmp, or exr or pfm to keep the
+                            linear colors above 1 (default: render.png)
+    --half                  write 16 bit floats to exr files instead of 32 bit
+    --width <pixels>        override the scene width
+    --height <pixels>       override the scene height
+    --spp <samples>         samples per pixel, the maximum with --adaptive
+                            (default: 64, 1024 with --adaptive, unless --time is given)
+    --max-bounces <n>       override the scene max bounces
+    --time <seconds>        stop sampling after this time
+    --spectral              trace wavelengths instead of RGB colors
+    --seed <n>              override the scene seed of the random numbers
+    --adaptive <threshold>  stop sampling the pixels whose error, relative to their
+                            brightness, is below the threshold, like 0.02
+    --min-spp <samples>     samples per pixel before checking the error (default: 16)
+    --heatmap <file>        image of the samples taken by each pixel, with --adaptive
+    --exposure <ev>         override the scene exposure, each unit doubles the brightness
+    --tone-mapping <name>   override the scene tone mapping: clamp, reinhard,
+                            extended-reinhard, aces or agx
+    --white <luminance>     luminance mapped to white by extended-reinhard (default: 4)
+    --dither                add noise to the 8 bit images against color banding
+    --aovs <names>          also write these output variables, separated by commas, or all:
+                            albedo, normal, depth, position, object, material, bounces.
+                            Layers of exr outputs, otherwise images next to the output,
+                            like render.depth.png. The materials are indexed by name";
+
+const DEFAULT_SPP: usize = 64;
+
+#[derive(Debug, PartialEq)]
+struct RenderOptions {
+    scene: PathBuf,
+    output: PathBuf,
+    width: Option<usize>,
+    height: Option<usize>,
+    /// None means unlimited, the rendering stops at the time budget
+    spp: Option<usize>,
+    max_bounces: Option<usize>,
+    time_budget: Option<Duration>,
+    spectral: bool,
+    seed: Option<u64>,
+    /// relative error threshold of the adaptive sampling
+    adaptive: Option<f64>,
+    min_spp: Option<usize>,
+    heatmap: Option<PathBuf>,
+    /// precision of the exr output
+    half: bool,
+    exposure: Option<f64>,
+    tone_mapping: Option<ToneMapping>,
+    dither: bool,
+    aovs: Vec<Aov>,
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<&String>) -> Result<T, String> {
+    match value {
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("invalid value '{}' for {}", value, flag)),
+        None => Err(format!("missing value for {}", flag)),
+    }
+}
+
+fn parse_render_options(args: &[String]) -> Result<RenderOptions, String> {
+    let mut scene = None;
+    let mut output = PathBuf::from("render.png");
+    let mut width = None;
+    let mut height = None;
+    let mut spp = None;
+    let mut max_bounces = None;
+    let mut time_budget = None;
+    let mut spectral = false;
+    let mut seed = None;
+    let mut adaptive = None;
+    let mut min_spp = None;
+    let mut heatmap = None;
+    let mut half = false;
+    let mut exposure = None;
+    let mut tone_mapping = None;
+    let mut white = None;
+    let mut dither = false;
+    let mut aovs = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = parse_value(arg, args.next())?,
+            "--width" => width = Some(parse_value(arg, args.next())?),
+            "--height" => height = Some(parse_value(arg, args.next())?),
+            "--spp" => spp = Some(parse_value(arg, args.next())?),
+            "--max-bounces" => max_bounces = Some(parse_value(arg, args.next())?),
+            "--time" => {
+                let seconds: f64 = parse_value(arg, args.next())?;
+                if seconds.is_nan() || seconds <= 0.0 {
+                    return Err("--time must be positive".to_string());
+                }
+                time_budget = Some(Duration::from_secs_f64(seconds));
+            }
+            "--spectral" => spectral = true,
+            "--seed" => seed = Some(parse_value(arg, args.next())?),
+            "--adaptive" => {
+                let threshold: f64 = parse_value(arg, args.next())?;
+                if threshold.is_nan() || threshold <= 0.0 {
+                    return Err("--adaptive must be positive".to_string());
+                }
+                adaptive = Some(threshold);
+            }
+            "--min-spp" => min_spp = Some(parse_value(arg, args.next())?),
+            "--heatmap" => heatmap = Some(parse_value(arg, args.next())?),
+            "--half" => half = true,
+            "--exposure" => {
+                let ev: f64 = parse_value(arg, args.next())?;
+                if !ev.is_finite() {
+                    return Err("--exposure must be a number".to_string());
+                }
+                exposure = Some(ev);
+            }
+            "--tone-mapping" => tone_mapping = Some(parse_value(arg, args.next())?),
+            "--white" => {
+                let luminance: f64 = parse_value(arg, args.next())?;
+                if luminance.is_nan() || luminance <= 0.0 {
+                    return Err("--white must be positive".to_string());
+                }
+                white = Some(luminance);
+            }
+            "--dither" => dither = true,
+            "--aovs" => {
+                let names: String = parse_value(arg, args.next())?;
+                aovs = if names == "all" {
+                    Aov::ALL.to_vec()
+                } else {
+                    names
+                        .split(',')
+                        .map(str::parse)
+                        .collect::<Result<Vec<Aov>, String>>()?
+                };
+                for (i, aov) in aovs.iter().enumerate() {
+                    if aovs[..i].contains(aov) {
+                        return Err(format!("--aovs lists '{}' more than once", aov.name()));
+                    }
+                }
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option '{}'", arg)),
+            _ if scene.is_none() => scene = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument '{}'", arg)),
+        }
+    }
+    if width == Some(0) || height == Some(0) || spp == Some(0) {
+        return Err("width, height and spp must be positive".to_string());
+    }
+    if adaptive.is_none() && (min_spp.is_some() || heatmap.is_some()) {
+        return Err("--min-spp and --heatmap need --adaptive".to_string());
+    }
+    if half && image_format(&output) != "exr" {
+        return Err("--half needs an exr output".to_string());
+    }
+    if adaptive.is_some() && !aovs.is_empty() {
+        return Err("--aovs can't be used with --adaptive".to_string());
+    }
+    if let Some(luminance) = white {
+        match &mut tone_mapping {
+            Some(ToneMapping::ExtendedReinhard { white }) => *white = luminance,
+            _ => return Err("--white needs --tone-mapping extended-reinhard".to_string()),
+        }
+    }
+    if spp.is_none() && time_budget.is_none() {
+        spp = Some(if adaptive.is_some() {
+            AdaptiveSampling::default().max_spp
+        } else {
+            DEFAULT_SPP
+        });
+    }
+    if let (Some(min_spp), Some(spp)) = (min_spp, spp) {
+        if min_spp > spp {
+            return Err("--min-spp can't be greater than --spp".to_string());
+        }
+    }
+    Ok(RenderOptions {
+        scene: scene.ok_or_else(|| "missing scene file".to_string())?,
+        output,
+        width,
+        height,
+        spp,
+        max_bounces,
+        time_budget,
+        spectral,
+        seed,
+        adaptive,
+        min_spp,
+        heatmap,
+        half,
+        exposure,
+        tone_mapping,
+        dither,
+        aovs,
+    })
+}
+
+fn save_rgba(data: &[u8], width: usize, height: usize, path: &Path) -> Result<(), String> {
+    image::save_buffer(
+        path,
+        data,
+        width as u32,
+        height as u32,
+        image::ColorType::Rgba8,
+    )
+    .map_err(|err| format!("cannot write '{}': {}", path.display(), err))
+}
+
+/// Lowercase extension of the file.
+fn image_format(path: &Path) -> String {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+fn exr_precision(options: &RenderOptions) -> ExrPrecision {
+    if options.half {
+        ExrPrecision::Half
+    } else {
+        ExrPrecision::Float
+    }
+}
+
+/// Image of an output variable next to the output, render.png gives render.depth.png.
+fn aov_path(output: &Path, aov: Aov) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    output.with_file_name(match output.extension() {
+        Some(extension) => format!("{}.{}.{}", stem, aov.name(), extension.to_string_lossy()),
+        None => format!("{}.{}", stem, aov.name()),
+    })
+}
+
+/// Saves the image with its output variables, as layers of exr outputs
+/// or as images of the same format next to the others.
+fn save_image_with_aovs(
+    buffer: &AovBuffer,
+    display: &DisplaySettings,
+    options: &RenderOptions,
+) -> Result<(), String> {
+    let (width, height) = (buffer.width(), buffer.height());
+    let format = image_format(&options.output);
+    if format == "exr" {
+        let mut channels = ExrChannel::rgb(None, &buffer.to_linear());
+        for &aov in &options.aovs {
+            channels.extend(buffer.exr_channels(aov));
+        }
+        return save_exr(
+            &options.output,
+            width,
+            height,
+            &channels,
+            exr_precision(options),
+        );
+    }
+    save_image(buffer, display, options)?;
+    for &aov in &options.aovs {
+        let path = aov_path(&options.output, aov);
+        if format == "pfm" {
+            save_pfm(&path, width, height, &buffer.aov(aov))?;
+        } else {
+            save_rgba(&buffer.aov_img(aov), width, height, &path)?;
+        }
+    }
+    Ok(())
+}
+
+fn save_image(
+    buffer: &impl RendererBuffer,
+    display: &DisplaySettings,
+    options: &RenderOptions,
+) -> Result<(), String> {
+    let (width, height) = (buffer.width(), buffer.height());
+    let path = &options.output;
+    match image_format(path).as_str() {
+        "exr" => save_exr(
+            path,
+            width,
+            height,
+            &ExrChannel::rgb(None, &buffer.to_linear()),
+            exr_precision(options),
+        ),
+        "pfm" => save_pfm(path, width, height, &buffer.to_linear()),
+        _ => save_rgba(&buffer.to_img(display), width, height, path),
+    }
+}
+
+/// Renders passes over the image until the options or `is_done` stop it,
+/// returns the number of passes.
+fn render_passes<B: RendererBuffer>(
+    scene: &Scene,
+    buffer: &mut B,
+    options: &RenderOptions,
+    interrupted: &AtomicBool,
+    is_done: impl Fn(&B) -> bool,
+) -> usize {
+    let start = Instant::now();
+    let mut passes = 0;
+    loop {
+        scene.render(buffer);
+        passes += 1;
+        if options.spp.is_some_and(|spp| passes >= spp)
+            || options
+                .time_budget
+                .is_some_and(|budget| start.elapsed() >= budget)
+            || interrupted.load(Ordering::SeqCst)
+            || is_done(buffer)
+        {
+            break passes;
+        }
+    }
+}
+
+fn render_command(options: RenderOptions) -> Result<(), String> {
+    let mut scene = Scene::from_file(&options.scene)?;
+    if options.width.is_some() || options.height.is_some() {
+        scene.set_size(
+            options.width.unwrap_or_else(|| scene.width()),
+            options.height.unwrap_or_else(|| scene.height()),
+        );
+    }
+    if let Some(max_bounces) = options.max_bounces {
+        scene.max_bounces = max_bounces;
+    }
+    scene.spectral |= options.spectral;
+    if let Some(seed) = options.seed {
+        scene.seed = seed;
+    }
+    if let Some(exposure) = options.exposure {
+        scene.display.exposure = exposure;
+    }
+    if let Some(tone_mapping) = options.tone_mapping {
+        scene.display.tone_mapping = tone_mapping;
+    }
+    scene.display.dither |= options.dither;
+
+    // ctrl-c stops sampling, the image rendered so far is saved anyway
+    let interrupted = Arc::new(AtomicBool::new(false));
+    {
+        let interrupted = interrupted.clone();
+        ctrlc::set_handler(move || interrupted.store(true, Ordering::SeqCst))
+            .map_err(|err| format!("cannot set the ctrl-c handler: {}", err))?;
+    }
+
+    let start = Instant::now();
+    match options.adaptive {
+        Some(threshold) => {
+            let settings = AdaptiveSampling {
+                threshold,
+                min_spp: options
+                    .min_spp
+                    .unwrap_or_else(|| AdaptiveSampling::default().min_spp),
+                max_spp: options.spp.unwrap_or(usize::MAX),
+            };
+            let mut buffer = AdaptiveBuffer::new(scene.width(), scene.height(), settings);
+            render_passes(&scene, &mut buffer, &options, &interrupted, |buffer| {
+                buffer.is_converged()
+            });
+            println!(
+                "rendered {:.1} samples per pixel on average in {:.3?}",
+                buffer.average_spp(),
+                start.elapsed()
+            );
+            save_image(&buffer, &scene.display, &options)?;
+            match &options.heatmap {
+                Some(path) => save_rgba(&buffer.to_heatmap(), scene.width(), scene.height(), path),
+                None => Ok(()),
+            }
+        }
+        None if !options.aovs.is_empty() => {
+            let mut buffer = AovBuffer::new(scene.width(), scene.height());
+            let samples = render_passes(&scene, &mut buffer, &options, &interrupted, |_| false);
+            println!(
+                "rendered {} samples per pixel in {:.3?}",
+                samples,
+                start.elapsed()
+            );
+            save_image_with_aovs(&buffer, &scene.display, &options)
+        }
+        None => {
+            let mut buffer = scene.new_pixel_buffer();
+            let samples = render_passes(&scene, &mut buffer, &options, &interrupted, |_| false);
+            println!(
+                "rendered {} samples per pixel in {:.3?}",
+                samples,
+                start.elapsed()
+            );
+            save_image(&buffer, &scene.display, &options)
+        }
+    }
+}
+
+fn exit_with_error(err: &str, show_usage: bool) -> ! {
+    if show_usage {
+        eprintln!("error: {}\n\n{}", err, USAGE);
+    } else {
+        eprintln!("error: {}", err);
+    }
+    std::process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("render") => {
+            let options =
+                parse_render_options(&args[1..]).unwrap_or_else(|err| exit_with_error(&err, true));
+            if let Err(err) = render_command(options) {
+                exit_with_error(&err, false);
+            }
+        }
+        Some("view") => match &args[1..] {
+            [path] => {
+                if let Err(err) = viewer::view_command(Path::new(path)) {
+                    exit_with_error(&err, false);
+                }
+            }
+            _ => exit_with_error("view needs just the scene file", true),
+        },
+        Some("-h") | Some("--help") => println!("{}", USAGE),
+        Some(command) => exit_with_error(&format!("unknown command '{}'", command), true),
+        None => exit_with_error("missing command", true),
+    }
+}
+
+#[test]
+fn test_parse_render_options() {
+    let args = |line: &str| -> Vec<String> { line.split_whitespace().map(String::from).collect() };
+
+    assert_eq!(
+        parse_render_options(&args("scene.json")),
+        Ok(RenderOptions {
+            scene: PathBuf::from("scene.json"),
+            output: PathBuf::from("render.png"),
+            width: None,
+            height: None,
+            spp: Some(DEFAULT_SPP),
+            max_bounces: None,
+            time_budget: None,
+            spectral: false,
+            seed: None,
+            adaptive: None,
+            min_spp: None,
+            heatmap: None,
+            half: false,
+            exposure: None,
+            tone_mapping: None,
+            dither: false,
+            aovs: Vec::new(),
+        })
+    );
+    assert_eq!(
+        parse_render_options(&args(
+            "--width 320 scene.json -o out.bmp --height 200 --max-bounces 3 --time 1.5 --spectral \
+             --seed 42"
+        )),
+        Ok(RenderOptions {
+            scene: PathBuf::from("scene.json"),
+            output: PathBuf::from("out.bmp"),
+            width: Some(320),
+            height: Some(200),
+            spp: None,
+            max_bounces: Some(3),
+            time_budget: Some(Duration::from_millis(1500)),
+            spectral: true,
+            seed: Some(42),
+            adaptive: None,
+            min_spp: None,
+            heatmap: None,
+            half: false,
+            exposure: None,
+            tone_mapping: None,
+            dither: false,
+            aovs: Vec::new(),
+        })
+    );
+    assert_eq!(
+        parse_render_options(&args(
+            "scene.json --adaptive 0.05 --min-spp 8 --heatmap spp.png"
+        )),
+        Ok(RenderOptions {
+            scene: PathBuf::from("scene.json"),
+            output: PathBuf::from("render.png"),
+            width: None,
+            height: None,
+            spp: Some(AdaptiveSampling::default().max_spp),
+            max_bounces: None,
+            time_budget: None,
+            spectral: false,
+            seed: None,
+            adaptive: Some(0.05),
+            min_spp: Some(8),
+            heatmap: Some(PathBuf::from("spp.png")),
+            half: false,
+            exposure: None,
+            tone_mapping: None,
+            dither: false,
+            aovs: Vec::new(),
+        })
+    );
+    assert!(parse_render_options(&args("scene.json --adaptive 0")).is_err());
+    assert!(parse_render_options(&args("scene.json --heatmap spp.png")).is_err());
+    assert!(parse_render_options(&args("scene.json --adaptive 0.1 --spp 8 --min-spp 16")).is_err());
+    assert!(parse_render_options(&args("scene.json --adaptive 0.1 --min-spp 2048")).is_err());
+    assert_eq!(
+        parse_render_options(&args("scene.json -o out.EXR --half")).map(|options| options.half),
+        Ok(true)
+    );
+    assert!(parse_render_options(&args("scene.json -o out.pfm --half")).is_err());
+    let options = parse_render_options(&args(
+        "scene.json --exposure -1.5 --tone-mapping aces --dither",
+    ))
+    .unwrap();
+    assert_eq!(
+        (options.exposure, options.tone_mapping, options.dither),
+        (Some(-1.5), Some(ToneMapping::Aces), true)
+    );
+    assert_eq!(
+        parse_render_options(&args(
+            "scene.json --white 8 --tone-mapping extended-reinhard"
+        ))
+        .map(|options| options.tone_mapping),
+        Ok(Some(ToneMapping::ExtendedReinhard { white: 8.0 }))
+    );
+    assert!(parse_render_options(&args("scene.json --white 8")).is_err());
+    assert!(parse_render_options(&args("scene.json --tone-mapping filmic")).is_err());
+    assert!(parse_render_options(&args("scene.json --exposure inf")).is_err());
+    assert_eq!(
+        parse_render_options(&args("scene.json --aovs depth,object")).map(|options| options.aovs),
+        Ok(vec![Aov::Depth, Aov::Object])
+    );
+    assert_eq!(
+        parse_render_options(&args("scene.json --aovs all")).map(|options| options.aovs.len()),
+        Ok(Aov::ALL.len())
+    );
+    assert!(parse_render_options(&args("scene.json --aovs depth,beauty")).is_err());
+    assert!(parse_render_options(&args("scene.json --aovs depth,object,depth")).is_err());
+    assert!(parse_render_options(&args("scene.json --aovs depth --adaptive 0.1")).is_err());
+    assert_eq!(
+        aov_path(Path::new("out/render.png"), Aov::Depth),
+        PathBuf::from("out/render.depth.png")
+    );
+    assert_eq!(
+        aov_path(Path::new("render"), Aov::Normal),
+        PathBuf::from("render.normal")
+    );
+    assert!(parse_render_options(&args("")).is_err());
+    assert!(parse_render_options(&args("scene.json --spp")).is_err());
+    assert!(parse_render_options(&args("scene.json --spp ten")).is_err());
+    assert!(parse_render_options(&args("scene.json --seed -1")).is_err());
+    assert!(parse_render_options(&args("scene.json --width 0")).is_err());
+    assert!(parse_render_options(&args("scene.json --fast")).is_err());
+    assert!(parse_render_options(&args("a.json b.json")).is_err());
+}
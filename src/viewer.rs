@@ -0,0 +1,269 @@
+use crate::core::defs::Real;
+use crate::core::primitives::Vec2R;
+use crate::core::renderer::renderer_buffer::RendererBuffer;
+use crate::core::renderer::PartitionedBuffer;
+use crate::core::scene::Scene;
+use piston_window::*;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+/// world units per second
+const MOVE_SPEED: Real = 1.5;
+/// radians per pixel of mouse movement
+const MOUSE_SENSITIVITY: Real = 0.003;
+const RELOAD_INTERVAL: Duration = Duration::from_millis(250);
+/// time a refinement pass should take to keep the window responsive
+const PASS_TIME: Duration = Duration::from_millis(12);
+/// fonts tried for the error overlay, without one the error is only in the title and on stderr
+const FONT_PATHS: [&str; 3] = [
+    "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf",
+    "/Library/Fonts/Arial.ttf",
+    "C:\\Windows\\Fonts\\arial.ttf",
+];
+
+/// Scene file watched for changes, reloaded when its modification time changes.
+struct SceneFile {
+    path: PathBuf,
+    /// None before the first load
+    modified: Option<Option<SystemTime>>,
+    last_check: Instant,
+}
+
+impl SceneFile {
+    fn new(path: &Path) -> SceneFile {
+        SceneFile {
+            path: path.to_path_buf(),
+            modified: None,
+            last_check: Instant::now(),
+        }
+    }
+
+    fn modified_time(&self) -> Option<SystemTime> {
+        std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    /// Loads the scene if it changed since the last load.
+    fn reload(&mut self) -> Option<Result<Scene, String>> {
+        let modified = Some(self.modified_time());
+        if modified == self.modified {
+            return None;
+        }
+        self.modified = modified;
+        Some(Scene::from_file(&self.path))
+    }
+
+    fn should_check(&mut self) -> bool {
+        if self.last_check.elapsed() >= RELOAD_INTERVAL {
+            self.last_check = Instant::now();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+struct View {
+    scene: Scene,
+    buffer: PartitionedBuffer,
+    texture: G2dTexture,
+}
+
+impl View {
+    fn new(scene: Scene, texture_context: &mut G2dTextureContext) -> Result<View, String> {
+        let mut buffer = PartitionedBuffer::new(scene.width(), scene.height());
+        buffer.debug_error(scene.debug_error);
+        buffer.set_target_time(PASS_TIME);
+        let texture = Texture::from_image(
+            texture_context,
+            &View::canvas(&buffer, &scene),
+            &TextureSettings::new(),
+        )
+        .map_err(|err| format!("cannot create the texture: {:?}", err))?;
+        Ok(View {
+            scene,
+            buffer,
+            texture,
+        })
+    }
+
+    fn canvas(buffer: &PartitionedBuffer, scene: &Scene) -> ::image::RgbaImage {
+        ::image::RgbaImage::from_raw(
+            buffer.width() as u32,
+            buffer.height() as u32,
+            buffer.to_img(&scene.display),
+        )
+        .expect("the buffer has 4 bytes per pixel")
+    }
+
+    fn render(&mut self, texture_context: &mut G2dTextureContext) {
+        self.scene.render(&mut self.buffer);
+        if let Err(err) = self
+            .texture
+            .update(texture_context, &View::canvas(&self.buffer, &self.scene))
+        {
+            eprintln!("cannot update the texture: {:?}", err);
+        }
+    }
+}
+
+fn move_camera(view: &mut View, keys: &HashSet<Key>, dt: Real) -> bool {
+    let distance = MOVE_SPEED * dt;
+    let camera = &mut view.scene.camera;
+    let mut moved = false;
+    for key in keys {
+        match key {
+            Key::W | Key::Up => camera.move_forward(distance),
+            Key::S | Key::Down => camera.move_backward(distance),
+            Key::D | Key::Right => camera.move_right(distance),
+            Key::A | Key::Left => camera.move_left(distance),
+            Key::Space => camera.move_up(distance),
+            Key::LShift | Key::RShift => camera.move_down(distance),
+            _ => continue,
+        }
+        moved = true;
+    }
+    moved
+}
+
+/// Errors are always printed too, the title can be cut and the overlay needs a font.
+fn report(err: String) -> Option<String> {
+    eprintln!("error: {}", err);
+    Some(err)
+}
+
+/// Shows the scene in a window, refining the image while the camera is still.
+/// WASD or the arrows move the camera, space and shift move it up and down,
+/// dragging with the left mouse button looks around.
+pub fn view_command(path: &Path) -> Result<(), String> {
+    let mut scene_file = SceneFile::new(path);
+    let (scene, mut error) = match scene_file.reload() {
+        Some(Ok(scene)) => (Some(scene), None),
+        Some(Err(err)) => (None, report(err)),
+        None => (None, None),
+    };
+    let (width, height) = scene.as_ref().map_or((640, 480), |scene| {
+        (scene.width() as u32, scene.height() as u32)
+    });
+
+    let mut window: PistonWindow = WindowSettings::new("pbr", [width, height])
+        .exit_on_esc(true)
+        .resizable(false)
+        .build()
+        .map_err(|err| format!("cannot open the window: {}", err))?;
+    let mut texture_context = window.create_texture_context();
+    let mut glyphs = FONT_PATHS
+        .iter()
+        .find_map(|font| window.load_font(font).ok());
+
+    let mut view = match scene {
+        Some(scene) => match View::new(scene, &mut texture_context) {
+            Ok(view) => Some(view),
+            Err(err) => {
+                error = report(err);
+                None
+            }
+        },
+        None => None,
+    };
+    let mut keys = HashSet::new();
+    let mut looking = false;
+
+    while let Some(event) = window.next() {
+        if scene_file.should_check() {
+            match scene_file.reload() {
+                Some(Ok(scene)) => {
+                    let size = (scene.width() as u32, scene.height() as u32);
+                    match View::new(scene, &mut texture_context) {
+                        Ok(new_view) => {
+                            window.set_size(size);
+                            view = Some(new_view);
+                            error = None;
+                        }
+                        Err(err) => error = report(err),
+                    }
+                }
+                // the last working scene stays on screen
+                Some(Err(err)) => error = report(err),
+                None => {}
+            }
+            window.set_title(match &error {
+                Some(err) => format!("pbr - {}", err),
+                None => "pbr".to_string(),
+            });
+        }
+
+        if let Some(Button::Keyboard(key)) = event.press_args() {
+            keys.insert(key);
+        }
+        if let Some(Button::Keyboard(key)) = event.release_args() {
+            keys.remove(&key);
+        }
+        if let Some(Button::Mouse(MouseButton::Left)) = event.press_args() {
+            looking = true;
+        }
+        if let Some(Button::Mouse(MouseButton::Left)) = event.release_args() {
+            looking = false;
+        }
+
+        if let Some(view) = view.as_mut() {
+            let mut camera_changed = false;
+            if let Some([dx, dy]) = event.mouse_relative_args() {
+                if looking {
+                    view.scene.camera.rotate(Vec2R::new(
+                        -dy as Real * MOUSE_SENSITIVITY,
+                        dx as Real * MOUSE_SENSITIVITY,
+                    ));
+                    camera_changed = true;
+                }
+            }
+            if let Some(args) = event.update_args() {
+                camera_changed |= move_camera(view, &keys, args.dt as Real);
+            }
+            if camera_changed {
+                if view.scene.autofocus {
+                    view.scene.autofocus();
+                }
+                view.buffer.reset();
+            }
+            if event.render_args().is_some() {
+                view.render(&mut texture_context);
+            }
+        }
+
+        window.draw_2d(&event, |context, graphics, device| {
+            texture_context.encoder.flush(device);
+            clear([0.0, 0.0, 0.0, 1.0], graphics);
+            if let Some(view) = view.as_ref() {
+                image(&view.texture, context.transform, graphics);
+            }
+            if let Some(err) = error.as_ref() {
+                let [view_width, _] = context.get_view_size();
+                rectangle(
+                    [0.6, 0.0, 0.0, 0.85],
+                    [0.0, 0.0, view_width, 48.0],
+                    context.transform,
+                    graphics,
+                );
+                if let Some(glyphs) = glyphs.as_mut() {
+                    let transform = context.transform.trans(8.0, 20.0);
+                    for (line, text) in err.lines().take(2).enumerate() {
+                        text::Text::new_color([1.0, 1.0, 1.0, 1.0], 14)
+                            .draw(
+                                text,
+                                glyphs,
+                                &context.draw_state,
+                                transform.trans(0.0, line as f64 * 18.0),
+                                graphics,
+                            )
+                            .ok();
+                    }
+                    glyphs.factory.encoder.flush(device);
+                }
+            }
+        });
+    }
+    Ok(())
+}